@@ -0,0 +1,205 @@
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use cid::Cid;
+
+use crate::git;
+
+/// Directory for the cache, which can be shared by all clones on a machine
+const CACHE_DIR_KEY: &str = "lfs.ipfs.cachedir";
+/// Size cap of the cache in bytes, with git's `k`/`m`/`g` suffixes
+const CACHE_SIZE_KEY: &str = "lfs.ipfs.cachesize";
+
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Suffix of entries that are still being written
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Local copies of file contents keyed by CID, so smudge can skip the network
+///
+/// Entries are evicted least recently used first once the cache grows past its size cap.
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+        }
+    }
+
+    /// Use `lfs.ipfs.cachedir`, or a directory in the current repository if it is not set
+    pub fn from_git_config() -> Result<Option<Self>> {
+        let dir = match git::config(CACHE_DIR_KEY, Some("path"))? {
+            Some(dir) => PathBuf::from(dir),
+            None => match git::common_dir() {
+                Some(git_dir) => git_dir.join("lfs").join("ipfs").join("cache"),
+                None => return Ok(None),
+            },
+        };
+        let max_size = match git::config(CACHE_SIZE_KEY, Some("int"))? {
+            Some(size) => size
+                .parse()
+                .with_context(|| format!("invalid {} {}", CACHE_SIZE_KEY, size))?,
+            None => DEFAULT_MAX_SIZE,
+        };
+        Ok(Some(Self::new(dir, max_size)))
+    }
+
+    /// Path of the cached contents, marking them as recently used
+    pub fn get(&self, cid: &Cid) -> Option<PathBuf> {
+        let path = self.path(cid);
+        let file = File::options().write(true).open(&path).ok()?;
+        // Failing to bump the entry only makes it more likely to be evicted
+        let _ = file.set_modified(SystemTime::now());
+        Some(path)
+    }
+
+    /// Start writing an entry, which only becomes visible once [Entry::commit]ted
+    pub fn entry(&self, cid: &Cid) -> Result<Entry<'_>> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("could not create cache {}", self.dir.display()))?;
+        let path = self.path(cid);
+        let partial_path =
+            self.dir
+                .join(format!("{}.{}{}", cid, std::process::id(), PARTIAL_SUFFIX));
+        let file = File::create(&partial_path)
+            .with_context(|| format!("could not create {}", partial_path.display()))?;
+        Ok(Entry {
+            cache: self,
+            file,
+            partial_path,
+            path,
+        })
+    }
+
+    fn path(&self, cid: &Cid) -> PathBuf {
+        self.dir.join(cid.to_string())
+    }
+
+    /// Remove least recently used entries until the cache fits in its cap
+    fn evict(&self) -> Result<()> {
+        let mut entries = vec![];
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let dir_entry = dir_entry?;
+            if dir_entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(PARTIAL_SUFFIX)
+            {
+                continue;
+            }
+            let metadata = match dir_entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            entries.push((metadata.modified()?, metadata.len(), dir_entry.path()));
+        }
+        entries.sort();
+        let mut size = entries.iter().map(|(_, len, _)| len).sum::<u64>();
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => size -= len,
+                // Another process evicted it first
+                Err(err) if err.kind() == ErrorKind::NotFound => size -= len,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A cache entry being written
+pub struct Entry<'a> {
+    cache: &'a Cache,
+    file: File,
+    partial_path: PathBuf,
+    path: PathBuf,
+}
+
+impl Entry<'_> {
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Make the entry visible, then evict others if the cache is over its cap
+    pub fn commit(self) -> Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.partial_path, &self.path)
+            .with_context(|| format!("could not write {}", self.path.display()))?;
+        self.cache.evict()
+    }
+}
+
+impl Drop for Entry<'_> {
+    fn drop(&mut self) {
+        // Still there unless committed
+        let _ = fs::remove_file(&self.partial_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, str::FromStr, time::Duration};
+    use tempfile::tempdir;
+
+    const CID: &str = "Qmf412jQZiuVUtdgnB36FXFX7xg5V6KEbSJ4dpQuhkLyfD";
+    const OTHER_CID: &str = "QmWATWQ7fVPP2EFGu71UkfnqhYXDYH566qy47CnJDgvs8u";
+
+    fn insert(cache: &Cache, cid: &Cid, contents: &[u8]) {
+        let mut entry = cache.entry(cid).unwrap();
+        entry.file().write_all(contents).unwrap();
+        entry.commit().unwrap();
+    }
+
+    #[test]
+    fn get_returns_committed_entries_only() {
+        let dir = tempdir().unwrap();
+        let cache = Cache::new(dir.path(), DEFAULT_MAX_SIZE);
+        let cid = Cid::from_str(CID).unwrap();
+        let mut entry = cache.entry(&cid).unwrap();
+        entry.file().write_all(b"hello world").unwrap();
+        assert_eq!(cache.get(&cid), None);
+        entry.commit().unwrap();
+        assert_eq!(fs::read(cache.get(&cid).unwrap()).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn abandoned_entry_is_removed() {
+        let dir = tempdir().unwrap();
+        let cache = Cache::new(dir.path(), DEFAULT_MAX_SIZE);
+        drop(cache.entry(&Cid::from_str(CID).unwrap()).unwrap());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn commit_evicts_least_recently_used_entries() {
+        let dir = tempdir().unwrap();
+        let cache = Cache::new(dir.path(), 16);
+        let cid = Cid::from_str(CID).unwrap();
+        let other_cid = Cid::from_str(OTHER_CID).unwrap();
+        insert(&cache, &cid, b"hello world");
+        let old = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(cache.path(&cid))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        insert(&cache, &other_cid, b"goodbye world");
+        assert!(!cache.path(&cid).is_file());
+        assert!(cache.path(&other_cid).is_file());
+    }
+}
@@ -1,10 +1,12 @@
-use std::io::Read;
+use std::io::{Cursor, Read};
 
 use anyhow::Result;
-use futures::StreamExt;
-use ipfs_api_backend_hyper::{response::AddResponse, IpfsApi};
+use futures::{io::AllowStdIo, StreamExt};
+use ipfs_api_backend_hyper::{request, response::AddResponse, IpfsApi};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+use crate::{compression, encryption::Key, pins::Pins, policy::Policy, rate_limit::Limits};
+
 /// Replace file contents with the raw IPFS block contents.
 ///
 /// This means two things:
@@ -13,13 +15,58 @@ use tokio::io::{AsyncWrite, AsyncWriteExt};
 ///    identical to the Qmhash, allowing retrieval of the
 ///    file's contents via IPFS.
 ///
+/// Files that the [Policy] keeps out of IPFS are passed through unchanged.
+/// Contents are compressed if the [Policy] asks for it, and then encrypted if there
+/// is a [Key], before they are added at the upload rate of the [Limits]. Unless the
+/// [Policy] says otherwise, the file is then pinned in the repository's [Pins].
+///
 /// <https://github.com/git-lfs/git-lfs/blob/main/docs/extensions.md#clean>
 pub async fn clean<E: 'static + Send + Sync + std::error::Error>(
-    client: impl IpfsApi<Error = E>,
+    client: impl IpfsApi<Error = E> + Send + Sync,
+    policy: &Policy,
+    pins: &Pins,
+    key: Option<&Key>,
+    limits: &Limits,
     input: impl Read + Send + Sync + Unpin + 'static,
-    mut output: impl AsyncWrite + AsyncWriteExt + Unpin,
+    mut output: impl AsyncWrite + Unpin,
 ) -> Result<()> {
-    let AddResponse { hash, .. } = client.add(input).await?;
+    let mut input: Box<dyn Read + Send + Sync> = Box::new(input);
+    if !policy.ipfs {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = input.read(&mut buf)?;
+            if read == 0 {
+                return Ok(());
+            }
+            output.write_all(&buf[..read]).await?;
+        }
+    }
+    let options = request::Add {
+        chunker: policy.chunker.as_deref(),
+        cid_version: policy.cid_version,
+        pin: Some(false),
+        // Raw leaves would make single block files a raw block rather than dag-pb,
+        // which smudge could not tell apart
+        raw_leaves: Some(false),
+        ..Default::default()
+    };
+    if let Some(level) = policy.zstd_level {
+        input = Box::new(compression::compress(
+            input,
+            level,
+            policy.zstd_dictionary.as_deref(),
+        )?);
+    }
+    if let Some(key) = key {
+        let mut contents = vec![];
+        input.read_to_end(&mut contents)?;
+        input = Box::new(Cursor::new(key.encrypt(&contents)?));
+    }
+    let input = limits.upload_reader(AllowStdIo::new(input));
+    let AddResponse { hash, .. } = client.add_async_with_options(input, options).await?;
+    if policy.pin {
+        pins.pin(&client, &hash).await?;
+    }
     let mut stream = client.block_get(&hash);
     while let Some(bytes) = stream.next().await.transpose()? {
         output.write_all(&bytes).await?;
@@ -32,17 +79,72 @@ pub async fn clean<E: 'static + Send + Sync + std::error::Error>(
 mod tests {
     use super::*;
     use crate::ipfs::client;
-    use std::io::Cursor;
 
     const FILE: &[u8] = b"hello world";
     const RAW_BLOCK: &[u8] = include_bytes!("../test/hello_world_raw_block");
 
+    fn pins() -> Pins {
+        Pins::for_git_dir(&std::env::temp_dir().join("git-lfs-ipfs-clean-test"))
+    }
+
     #[tokio::test]
     #[ignore]
     async fn clean_converts_file_into_raw_root_block() {
         let client = client();
         let mut cursor = Cursor::new(vec![]);
-        clean(client, FILE, &mut cursor).await.unwrap();
+        clean(
+            client,
+            &Policy::default(),
+            &pins(),
+            None,
+            &Limits::default(),
+            FILE,
+            &mut cursor,
+        )
+        .await
+        .unwrap();
         assert_eq!(&cursor.into_inner(), RAW_BLOCK);
     }
+
+    #[tokio::test]
+    #[ignore]
+    async fn clean_encrypts_file_before_adding() {
+        let key = Key::new([1; 32]);
+        let mut cursor = Cursor::new(vec![]);
+        clean(
+            client(),
+            &Policy::default(),
+            &pins(),
+            Some(&key),
+            &Limits::default(),
+            FILE,
+            &mut cursor,
+        )
+        .await
+        .unwrap();
+        let raw_block = cursor.into_inner();
+        assert_ne!(raw_block, RAW_BLOCK);
+        assert!(!raw_block.windows(FILE.len()).any(|window| window == FILE));
+    }
+
+    #[tokio::test]
+    async fn clean_passes_through_files_kept_out_of_ipfs() {
+        let policy = Policy {
+            ipfs: false,
+            ..Default::default()
+        };
+        let mut cursor = Cursor::new(vec![]);
+        clean(
+            client(),
+            &policy,
+            &pins(),
+            None,
+            &Limits::default(),
+            FILE,
+            &mut cursor,
+        )
+        .await
+        .unwrap();
+        assert_eq!(&cursor.into_inner(), FILE);
+    }
 }
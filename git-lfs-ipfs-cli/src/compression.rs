@@ -0,0 +1,184 @@
+use std::{
+    borrow::Cow,
+    io::{BufReader, Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Start of every compressed object, which can't be mistaken for the start of a small
+/// text file or a `.zst` file that was stored as is
+const MAGIC: &[u8] = b"\0git-lfs-ipfs-compressed\0";
+/// Algorithm byte for zstd without a dictionary
+const ZSTD: u8 = 1;
+/// Algorithm byte for zstd with a dictionary, whose id follows
+const ZSTD_WITH_DICTIONARY: u8 = 2;
+const DICTIONARY_ID_LEN: usize = 8;
+/// Enough of an object to tell whether it is compressed
+pub const HEADER_LEN: usize = MAGIC.len() + 1;
+
+/// Level used by the `zstd` attribute when none is given
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Compress file contents with zstd before they are added to IPFS
+///
+/// Compressed objects start with a header recording the algorithm and the id of the
+/// dictionary, if there is one, so they can sit next to uncompressed ones and be
+/// decompressed whatever the policy of their path is by then. Like with encryption, the
+/// header lives in the object because pointer extensions can only hold an oid.
+///
+/// Compression is deterministic, so the same file compressed with the same level and
+/// dictionary always gets the same CID and is deduplicated as before. Changing the
+/// level or dictionary gives a new CID though, and a small edit changes most of the
+/// compressed bytes, so IPFS can no longer share chunks between versions of a file.
+pub fn compress(
+    input: impl Read + Send + Sync + 'static,
+    level: i32,
+    dictionary: Option<&Path>,
+) -> Result<impl Read + Send + Sync + 'static> {
+    let dictionary = dictionary.map(read_dictionary).transpose()?;
+    let mut header = MAGIC.to_vec();
+    match &dictionary {
+        Some(dictionary) => {
+            header.push(ZSTD_WITH_DICTIONARY);
+            header.extend_from_slice(&dictionary_id(dictionary));
+        }
+        None => header.push(ZSTD),
+    }
+    let encoder = zstd::stream::read::Encoder::with_dictionary(
+        BufReader::new(input),
+        level,
+        dictionary.as_deref().unwrap_or_default(),
+    )?;
+    Ok(Cursor::new(header).chain(encoder))
+}
+
+/// Whether `data` starts like a compressed object
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Decompress an object, passing uncompressed ones through as they are
+///
+/// An object compressed with a dictionary is decompressed with whichever of
+/// `dictionaries` it was, going by the id in its header.
+pub fn decompress<'a>(data: &'a [u8], dictionaries: &[PathBuf]) -> Result<Cow<'a, [u8]>> {
+    if !is_compressed(data) {
+        return Ok(Cow::Borrowed(data));
+    }
+    if data.len() < HEADER_LEN {
+        return Err(anyhow::anyhow!("compressed object is truncated"));
+    }
+    let (header, frame) = data.split_at(HEADER_LEN);
+    let (dictionary, frame) = match header[MAGIC.len()] {
+        ZSTD => (None, frame),
+        ZSTD_WITH_DICTIONARY if frame.len() >= DICTIONARY_ID_LEN => {
+            let (id, frame) = frame.split_at(DICTIONARY_ID_LEN);
+            (Some(find_dictionary(id, dictionaries)?), frame)
+        }
+        ZSTD_WITH_DICTIONARY => return Err(anyhow::anyhow!("compressed object is truncated")),
+        algorithm => {
+            return Err(anyhow::anyhow!(
+                "unknown compression algorithm {}",
+                algorithm
+            ))
+        }
+    };
+    let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+        BufReader::new(frame),
+        dictionary.as_deref().unwrap_or_default(),
+    )?;
+    let mut decompressed = vec![];
+    decoder
+        .read_to_end(&mut decompressed)
+        .context("could not decompress object")?;
+    Ok(Cow::Owned(decompressed))
+}
+
+fn read_dictionary(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("could not read dictionary {}", path.display()))
+}
+
+fn dictionary_id(dictionary: &[u8]) -> [u8; DICTIONARY_ID_LEN] {
+    let mut id = [0; DICTIONARY_ID_LEN];
+    id.copy_from_slice(&Sha256::digest(dictionary)[..DICTIONARY_ID_LEN]);
+    id
+}
+
+fn find_dictionary(id: &[u8], dictionaries: &[PathBuf]) -> Result<Vec<u8>> {
+    for path in dictionaries {
+        let dictionary = read_dictionary(path)?;
+        if dictionary_id(&dictionary) == id {
+            return Ok(dictionary);
+        }
+    }
+    Err(anyhow::anyhow!(
+        "object is compressed with dictionary {}, which no zstd-dictionary names",
+        hex::encode(id)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    const FILE: &[u8] = b"timestamp,value\n0,0\n1,0\n2,0\n3,0\n4,0\n5,0\n6,0\n7,0\n8,0\n9,0\n";
+
+    fn compressed(input: &[u8], level: i32, dictionary: Option<&Path>) -> Vec<u8> {
+        let mut output = vec![];
+        compress(Cursor::new(input.to_vec()), level, dictionary)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let file = FILE.repeat(100);
+        let output = compressed(&file, DEFAULT_LEVEL, None);
+        assert!(is_compressed(&output));
+        assert!(output.len() < file.len());
+        assert_eq!(decompress(&output, &[]).unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn compression_is_deterministic_for_dedup() {
+        let file = FILE.repeat(100);
+        let output = compressed(&file, DEFAULT_LEVEL, None);
+        assert_eq!(output, compressed(&file, DEFAULT_LEVEL, None));
+        // Other settings give other objects, so the same file is stored twice
+        assert_ne!(output, compressed(&file, 19, None));
+        assert_ne!(output, file);
+    }
+
+    #[test]
+    fn compression_uses_dictionary() {
+        let dir = tempdir().unwrap();
+        let dictionary = dir.path().join("csv.dict");
+        std::fs::write(&dictionary, FILE).unwrap();
+        let other = dir.path().join("wav.dict");
+        std::fs::write(&other, b"RIFF").unwrap();
+
+        let output = compressed(FILE, DEFAULT_LEVEL, Some(&dictionary));
+        assert_ne!(output, compressed(FILE, DEFAULT_LEVEL, None));
+        // The header says which dictionary it was
+        assert_eq!(
+            decompress(&output, &[other.clone(), dictionary])
+                .unwrap()
+                .as_ref(),
+            FILE
+        );
+        assert!(decompress(&output, &[other]).is_err());
+        assert!(decompress(&output, &[dir.path().join("missing.dict")]).is_err());
+    }
+
+    #[test]
+    fn decompress_passes_through_uncompressed_objects() {
+        assert_eq!(decompress(FILE, &[]).unwrap().as_ref(), FILE);
+        assert!(decompress(MAGIC, &[]).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std::{borrow::Cow, path::PathBuf};
+
+use anyhow::{Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::git;
+
+/// Hex-encoded 256-bit key for objects added to IPFS
+const KEY_KEY: &str = "lfs.ipfs.encryptionkey";
+/// File holding the hex-encoded key, for keeping it out of git config
+const KEY_FILE_KEY: &str = "lfs.ipfs.encryptionkeyfile";
+
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+
+/// Start of every encrypted object, which can't be mistaken for the start of a small
+/// text file
+const MAGIC: &[u8] = b"\0git-lfs-ipfs-encrypted\0";
+/// Algorithm byte for ChaCha20-Poly1305, the only one so far
+const CHACHA20_POLY1305: u8 = 1;
+/// Enough of an object to tell whether it is encrypted
+pub const HEADER_LEN: usize = MAGIC.len() + 1 + KEY_ID_LEN + NONCE_LEN;
+
+/// Repository key that encrypts file contents before they are added to IPFS
+///
+/// Encrypted objects start with a header recording the algorithm and the id of the key,
+/// so they can sit next to unencrypted ones. Pointer extensions can only hold an oid,
+/// which is why the header lives in the object rather than in the pointer.
+///
+/// The nonce is derived from the key and the contents, like in SIV. Cleaning the same
+/// file twice then gives the same object, which git expects of a clean filter.
+/// Transfers only move opaque blocks, so decryption is left to smudge.
+pub struct Key {
+    id: [u8; KEY_ID_LEN],
+    cipher: ChaCha20Poly1305,
+    nonce_key: [u8; KEY_LEN],
+}
+
+impl Key {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        let digest = Sha256::digest(key);
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&digest[..KEY_ID_LEN]);
+        Self {
+            id,
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            nonce_key: key,
+        }
+    }
+
+    /// Use `lfs.ipfs.encryptionkey` or the file in `lfs.ipfs.encryptionkeyfile`,
+    /// if either is set
+    pub fn from_git_config() -> Result<Option<Self>> {
+        if let Some(key) = git::config(KEY_KEY, None)? {
+            return Self::parse(&key)
+                .with_context(|| format!("invalid {}", KEY_KEY))
+                .map(Some);
+        }
+        match git::config(KEY_FILE_KEY, Some("path"))? {
+            Some(path) => {
+                let path = PathBuf::from(path);
+                let key = std::fs::read_to_string(&path)
+                    .with_context(|| format!("could not read {}", path.display()))?;
+                Self::parse(&key)
+                    .with_context(|| format!("invalid key in {}", path.display()))
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn parse(hex_key: &str) -> Result<Self> {
+        let mut key = [0; KEY_LEN];
+        hex::decode_to_slice(hex_key.trim(), &mut key)
+            .context("expected 64 hexadecimal characters")?;
+        Ok(Self::new(key))
+    }
+
+    /// Hex-encoded digest of the key, which identifies it without revealing it
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.nonce_key)
+            .expect("HMAC takes keys of any size");
+        mac.update(plaintext);
+        let nonce = mac.finalize().into_bytes();
+        let nonce = Nonce::from_slice(&nonce[..NONCE_LEN]);
+
+        let mut encrypted = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+        encrypted.extend_from_slice(MAGIC);
+        encrypted.push(CHACHA20_POLY1305);
+        encrypted.extend_from_slice(&self.id);
+        encrypted.extend_from_slice(nonce);
+        // The header is authenticated so the key id and algorithm can't be swapped out
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &encrypted,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("could not encrypt"))?;
+        encrypted.extend(ciphertext);
+        Ok(encrypted)
+    }
+}
+
+/// Whether `data` starts like an encrypted object
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Decrypt an object, passing unencrypted ones through as they are
+pub fn decrypt<'a>(key: Option<&Key>, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    if !is_encrypted(data) {
+        return Ok(Cow::Borrowed(data));
+    }
+    if data.len() < HEADER_LEN {
+        return Err(anyhow::anyhow!("encrypted object is truncated"));
+    }
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let algorithm = header[MAGIC.len()];
+    let id = &header[MAGIC.len() + 1..MAGIC.len() + 1 + KEY_ID_LEN];
+    let nonce = Nonce::from_slice(&header[HEADER_LEN - NONCE_LEN..]);
+    if algorithm != CHACHA20_POLY1305 {
+        return Err(anyhow::anyhow!(
+            "unknown encryption algorithm {}",
+            algorithm
+        ));
+    }
+    let key = key.with_context(|| {
+        format!(
+            "object is encrypted with key {}, but {} is not set",
+            hex::encode(id),
+            KEY_KEY
+        )
+    })?;
+    if id != key.id {
+        return Err(anyhow::anyhow!(
+            "object is encrypted with key {}, not {}",
+            hex::encode(id),
+            key.id()
+        ));
+    }
+    key.cipher
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map(Cow::Owned)
+        .map_err(|_| anyhow::anyhow!("encrypted object is corrupt"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const FILE: &[u8] = b"hello world";
+
+    fn key(byte: u8) -> Key {
+        Key::new([byte; KEY_LEN])
+    }
+
+    #[test]
+    fn encryption_round_trips_and_is_deterministic() {
+        let key = key(1);
+        let encrypted = key.encrypt(FILE).unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.windows(FILE.len()).any(|window| window == FILE));
+        assert_eq!(encrypted, key.encrypt(FILE).unwrap());
+        assert_ne!(encrypted, key.encrypt(b"hello world!").unwrap());
+        assert_eq!(decrypt(Some(&key), &encrypted).unwrap().as_ref(), FILE);
+    }
+
+    #[test]
+    fn decrypt_passes_through_unencrypted_objects() {
+        assert_eq!(decrypt(None, FILE).unwrap().as_ref(), FILE);
+        assert_eq!(decrypt(Some(&key(1)), b"").unwrap().as_ref(), b"");
+    }
+
+    #[test]
+    fn decrypt_rejects_wrong_key_and_tampering() {
+        let encrypted = key(1).encrypt(FILE).unwrap();
+        assert!(decrypt(None, &encrypted).is_err());
+        assert!(decrypt(Some(&key(2)), &encrypted).is_err());
+
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(Some(&key(1)), &tampered).is_err());
+        assert!(decrypt(Some(&key(1)), &encrypted[..HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn key_parses_hex() {
+        let parsed = Key::parse(&format!("{}\n", "01".repeat(KEY_LEN))).unwrap();
+        assert_eq!(parsed.id(), key(1).id());
+        assert!(Key::parse("0123").is_err());
+    }
+}
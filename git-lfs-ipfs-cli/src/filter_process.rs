@@ -0,0 +1,669 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{Context, Result};
+use git_lfs_spec::{
+    batch::{Actions, Operation},
+    pkt_line::{read_pkt_line, read_text_until_separator, write_pkt_line, PktLine, MAX_DATA_LEN},
+    Object,
+};
+use ipfs_api_backend_hyper::IpfsApi;
+use multihash::{Hasher, Sha2_256};
+use tokio::io::AsyncWrite;
+use tracing::{info_span, warn, Instrument};
+
+use crate::{
+    cache::Cache,
+    clean::clean,
+    encryption::Key,
+    git,
+    ipfs::sha256_to_cid,
+    migrate::object_path,
+    pins::Pins,
+    policy::{Policies, Policy},
+    rate_limit::Limits,
+    remote::Remote,
+    smudge::smudge_cid,
+    transfer::batch,
+};
+
+const CLIENT_WELCOME: &str = "git-filter-client";
+const SERVER_WELCOME: &str = "git-filter-server";
+const VERSION: &str = "version=2";
+const CAPABILITIES: [&str; 2] = ["capability=clean", "capability=smudge"];
+
+const POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+/// git-lfs does not read anything larger as a pointer
+const MAX_POINTER_LEN: usize = 1024;
+/// Key of the pointer line with the oid of a file that went through IPFS, as git-lfs
+/// writes it for `lfs.extension.ipfs` with priority 0
+const EXTENSION: &str = "ext-0-ipfs";
+
+/// Clean and smudge every file of a checkout in one process, in place of git-lfs's own
+/// filter
+///
+/// Git starts the filter once and sends it files over pkt-lines, so the runtime and the
+/// connection pool to IPFS are shared by all of them. Files go in and out of it as
+/// git-lfs pointers with objects in `.git/lfs/objects`, so the transfer agent, locking
+/// and `git lfs fsck` work the same as with git-lfs. Files that go through IPFS are
+/// stored as their raw root block, and their pointer names the file with an
+/// `ext-0-ipfs` line, like a git-lfs extension would. Contents are streamed, except
+/// that encrypted files are held in memory whole by clean and smudge, and compressed
+/// ones by smudge.
+///
+/// <https://git-scm.com/docs/gitattributes#_long_running_filter_process>
+pub async fn filter_process<C, E>(
+    client: C,
+    cache: Option<&Cache>,
+    policies: &Policies,
+    key: Option<&Key>,
+    limits: &Limits,
+    input: impl Read + Send + 'static,
+    mut output: impl Write,
+) -> Result<()>
+where
+    C: IpfsApi<Error = E> + Clone + Send + Sync,
+    E: 'static + Send + Sync + std::error::Error,
+{
+    let input = Arc::new(Mutex::new(input));
+    handshake(&mut *input.lock().unwrap(), &mut output)?;
+    let dictionaries = policies.dictionaries();
+    let filter = Filter {
+        client,
+        cache,
+        dictionaries: &dictionaries,
+        key,
+        limits,
+    };
+    loop {
+        let (lines, separator) = read_text_until_separator(&mut *input.lock().unwrap())?;
+        if lines.is_empty() && separator.is_none() {
+            // Git closes the pipe when it is done
+            return Ok(());
+        }
+        let command = arg(&lines, "command").context("filter request without a command")?;
+        let pathname = arg(&lines, "pathname").unwrap_or_default();
+        let policy = policies.for_path(Path::new(pathname));
+        let blob = Blob::new(input.clone());
+
+        let span = info_span!("filter", command, pathname);
+        match command {
+            "clean" => {
+                let result = filter
+                    .clean(&policy, blob.clone())
+                    .instrument(span.clone())
+                    .await;
+                blob.skip()?;
+                match result {
+                    Ok(pointer) => {
+                        write_pkt_line(&mut output, &PktLine::text("status=success"))?;
+                        write_pkt_line(&mut output, &PktLine::Flush)?;
+                        DataWriter(&mut output).write_all(pointer.as_bytes())?;
+                        write_pkt_line(&mut output, &PktLine::Flush)?;
+                        // An empty list keeps the status as is
+                        write_pkt_line(&mut output, &PktLine::Flush)?;
+                    }
+                    Err(err) => {
+                        span.in_scope(|| warn!("{:#}", err));
+                        write_pkt_line(&mut output, &PktLine::text("status=error"))?;
+                        write_pkt_line(&mut output, &PktLine::Flush)?;
+                    }
+                }
+            }
+            "smudge" => {
+                // The file is written out as it arrives, so a failure can only be told
+                // to git after some of it
+                write_pkt_line(&mut output, &PktLine::text("status=success"))?;
+                write_pkt_line(&mut output, &PktLine::Flush)?;
+                let result = filter
+                    .smudge(blob.clone(), &mut DataWriter(&mut output))
+                    .instrument(span.clone())
+                    .await;
+                blob.skip()?;
+                write_pkt_line(&mut output, &PktLine::Flush)?;
+                match result {
+                    Ok(()) => write_pkt_line(&mut output, &PktLine::Flush)?,
+                    Err(err) => {
+                        span.in_scope(|| warn!("{:#}", err));
+                        write_pkt_line(&mut output, &PktLine::text("status=error"))?;
+                        write_pkt_line(&mut output, &PktLine::Flush)?;
+                    }
+                }
+            }
+            _ => {
+                blob.skip()?;
+                span.in_scope(|| warn!("unsupported command"));
+                write_pkt_line(&mut output, &PktLine::text("status=error"))?;
+                write_pkt_line(&mut output, &PktLine::Flush)?;
+            }
+        }
+        output.flush()?;
+    }
+}
+
+/// What a filter request needs besides the file
+struct Filter<'a, C> {
+    client: C,
+    cache: Option<&'a Cache>,
+    dictionaries: &'a [PathBuf],
+    key: Option<&'a Key>,
+    limits: &'a Limits,
+}
+
+impl<C, E> Filter<'_, C>
+where
+    C: IpfsApi<Error = E> + Clone + Send + Sync,
+    E: 'static + Send + Sync + std::error::Error,
+{
+    /// Store a file as an LFS object and return its pointer
+    ///
+    /// Pointers that are committed as they are pass through, like git-lfs leaves them.
+    async fn clean<R: Read + Send + 'static>(
+        &self,
+        policy: &Policy,
+        mut blob: Blob<R>,
+    ) -> Result<String> {
+        let start = read_start(&mut blob)?;
+        if Pointer::parse(&start).is_some() {
+            return Ok(String::from_utf8(start)?);
+        }
+        let tmp_dir = lfs_dir()?.join("tmp");
+        std::fs::create_dir_all(&tmp_dir)?;
+        // Requests come one at a time, so one file per process is enough
+        let tmp_path = tmp_dir.join(format!("{}.partial", std::process::id()));
+        let mut contents = Cursor::new(start).chain(blob.clone());
+        if !policy.ipfs {
+            io::copy(&mut contents, &mut File::create(&tmp_path)?)?;
+            let (oid, size) = blob.finish()?;
+            self.store(&tmp_path, &oid)?;
+            return Ok(Pointer {
+                file_oid: None,
+                oid,
+                size,
+            }
+            .to_string());
+        }
+        // The object is the raw root block, which is small
+        let mut block = vec![];
+        clean(
+            self.client.clone(),
+            policy,
+            &Pins::from_git_dir()?,
+            self.key,
+            self.limits,
+            contents,
+            &mut block,
+        )
+        .await?;
+        let (file_oid, _) = blob.finish()?;
+        let mut hasher = Sha2_256::default();
+        hasher.update(&block);
+        let oid = hex::encode(hasher.finalize());
+        std::fs::write(&tmp_path, &block)?;
+        self.store(&tmp_path, &oid)?;
+        Ok(Pointer {
+            file_oid: Some(file_oid),
+            oid,
+            size: block.len() as u64,
+        }
+        .to_string())
+    }
+
+    /// Write out the file of a pointer, fetching its object from the remote if it is
+    /// neither in IPFS nor stored locally
+    ///
+    /// Anything that is not a pointer passes through.
+    async fn smudge<R: Read + Send + 'static>(
+        &self,
+        mut blob: Blob<R>,
+        output: &mut (impl Write + AsyncWrite + Unpin),
+    ) -> Result<()> {
+        let start = read_start(&mut blob)?;
+        let pointer = match Pointer::parse(&start) {
+            Some(pointer) => pointer,
+            None => {
+                output.write_all(&start)?;
+                io::copy(&mut blob, output)?;
+                return Ok(());
+            }
+        };
+        // The pointer tells how the object was stored, whatever the path's policy is now
+        if pointer.file_oid.is_some() {
+            let cid = sha256_to_cid(&pointer.oid)?;
+            return smudge_cid(
+                self.client.clone(),
+                self.cache,
+                self.dictionaries,
+                self.key,
+                self.limits,
+                &cid,
+                output,
+            )
+            .await;
+        }
+        let path = object_path(&lfs_dir()?.join("objects"), &pointer.oid);
+        if !path.exists() {
+            let object = Object {
+                oid: pointer.oid.clone(),
+                size: pointer.size,
+            };
+            let remote = Remote::from_git_config(&Remote::default_name()?)?;
+            match batch(&remote, Operation::Download, &object).await? {
+                Actions::Download { download } => {
+                    remote.download(&download, &object, &path).await?
+                }
+                _ => return Err(anyhow::anyhow!("no download action for {}", object.oid)),
+            }
+        }
+        io::copy(&mut File::open(&path)?, output)?;
+        Ok(())
+    }
+
+    /// Move a finished object into `.git/lfs/objects`
+    fn store(&self, tmp_path: &Path, oid: &str) -> Result<()> {
+        let path = object_path(&lfs_dir()?.join("objects"), oid);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(tmp_path, &path)
+            .with_context(|| format!("could not store {}", path.display()))
+    }
+}
+
+/// Where git-lfs keeps its objects and temporary files
+fn lfs_dir() -> Result<PathBuf> {
+    Ok(git::common_dir()
+        .context("not in a git repository")?
+        .join("lfs"))
+}
+
+/// Read as much of a file as a pointer could be, and one byte more
+fn read_start(blob: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut start = vec![];
+    blob.take(MAX_POINTER_LEN as u64 + 1)
+        .read_to_end(&mut start)?;
+    Ok(start)
+}
+
+/// The parts of a git-lfs pointer that the filter uses
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/spec.md#the-pointer>
+#[derive(PartialEq, Eq, Debug)]
+struct Pointer {
+    /// SHA-256 of the file when it went through IPFS, and the object is its raw root
+    /// block
+    file_oid: Option<String>,
+    oid: String,
+    size: u64,
+}
+
+impl Pointer {
+    fn parse(text: &[u8]) -> Option<Self> {
+        if text.len() > MAX_POINTER_LEN {
+            return None;
+        }
+        let mut lines = std::str::from_utf8(text).ok()?.lines();
+        if lines.next()? != POINTER_VERSION {
+            return None;
+        }
+        let sha256 = |value: &str| {
+            value
+                .strip_prefix("sha256:")
+                .filter(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .map(str::to_string)
+        };
+        let (mut file_oid, mut oid, mut size) = (None, None, None);
+        for line in lines {
+            match line.split_once(' ')? {
+                (EXTENSION, value) => file_oid = Some(sha256(value)?),
+                ("oid", value) => oid = Some(sha256(value)?),
+                ("size", value) => size = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+        Some(Self {
+            file_oid,
+            oid: oid?,
+            size: size?,
+        })
+    }
+}
+
+impl fmt::Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", POINTER_VERSION)?;
+        if let Some(file_oid) = &self.file_oid {
+            writeln!(f, "{} sha256:{}", EXTENSION, file_oid)?;
+        }
+        writeln!(f, "oid sha256:{}", self.oid)?;
+        writeln!(f, "size {}", self.size)
+    }
+}
+
+/// The file of a request, read from git's data packets as it is needed
+///
+/// Clones share their position, and the SHA-256 and size of what was read.
+struct Blob<R> {
+    input: Arc<Mutex<R>>,
+    state: Arc<Mutex<BlobState>>,
+}
+
+#[derive(Default)]
+struct BlobState {
+    packet: Vec<u8>,
+    read: usize,
+    finished: bool,
+    hasher: Sha2_256,
+    size: u64,
+}
+
+impl<R> Clone for Blob<R> {
+    fn clone(&self) -> Self {
+        Self {
+            input: self.input.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<R: Read> Blob<R> {
+    fn new(input: Arc<Mutex<R>>) -> Self {
+        Self {
+            input,
+            state: Default::default(),
+        }
+    }
+
+    /// Read past whatever is left of the file
+    fn skip(&self) -> io::Result<()> {
+        io::copy(&mut self.clone(), &mut io::sink())?;
+        Ok(())
+    }
+
+    /// Read past whatever is left of the file, returning the SHA-256 and size of all
+    /// of it
+    fn finish(&self) -> io::Result<(String, u64)> {
+        self.skip()?;
+        let mut state = self.state.lock().unwrap();
+        let size = state.size;
+        Ok((hex::encode(state.hasher.finalize()), size))
+    }
+}
+
+impl<R: Read> Read for Blob<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if state.read == state.packet.len() {
+            if state.finished {
+                return Ok(0);
+            }
+            match read_pkt_line(&mut *self.input.lock().unwrap())? {
+                Some(PktLine::Data(packet)) => {
+                    state.packet = packet;
+                    state.read = 0;
+                }
+                Some(PktLine::Flush) => {
+                    state.finished = true;
+                    return Ok(0);
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "expected file contents or a flush",
+                    ))
+                }
+            }
+        }
+        let chunk = &state.packet[state.read..];
+        let len = buf.len().min(chunk.len());
+        buf[..len].copy_from_slice(&chunk[..len]);
+        state.hasher.update(&chunk[..len]);
+        state.read += len;
+        state.size += len as u64;
+        Ok(len)
+    }
+}
+
+/// Writes a file back to git in data packets as it is produced
+///
+/// Writes block, like the rest of the filter's I/O with git.
+struct DataWriter<'a, W>(&'a mut W);
+
+impl<W: Write> Write for DataWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(MAX_DATA_LEN);
+        write_pkt_line(self.0, &PktLine::Data(buf[..len].to_vec()))?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> AsyncWrite for DataWriter<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn handshake(input: &mut impl Read, output: &mut impl Write) -> Result<()> {
+    let (welcome, _) = read_text_until_separator(input)?;
+    if welcome.first().map(String::as_str) != Some(CLIENT_WELCOME) {
+        return Err(anyhow::anyhow!(
+            "expected {}, got {:?}",
+            CLIENT_WELCOME,
+            welcome
+        ));
+    }
+    if !welcome.iter().any(|line| line == VERSION) {
+        return Err(anyhow::anyhow!("git does not support {}", VERSION));
+    }
+    write_pkt_line(output, &PktLine::text(SERVER_WELCOME))?;
+    write_pkt_line(output, &PktLine::text(VERSION))?;
+    write_pkt_line(output, &PktLine::Flush)?;
+    output.flush()?;
+
+    let (capabilities, _) = read_text_until_separator(input)?;
+    for capability in CAPABILITIES {
+        if capabilities.iter().any(|line| line == capability) {
+            write_pkt_line(output, &PktLine::text(capability))?;
+        }
+    }
+    write_pkt_line(output, &PktLine::Flush)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn arg<'a>(lines: &'a [String], key: &str) -> Option<&'a str> {
+    lines.iter().find_map(|line| {
+        line.split_once('=')
+            .filter(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipfs::client;
+    use git_lfs_spec::pkt_line::write_data;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    const RAW_BLOCK: &[u8] = include_bytes!("../test/hello_world_raw_block");
+    const MULTI_HASH: &str = "Qmf412jQZiuVUtdgnB36FXFX7xg5V6KEbSJ4dpQuhkLyfD";
+    /// SHA-256 of [RAW_BLOCK], whose CID is [MULTI_HASH]
+    const RAW_BLOCK_OID: &str = "f852c7fa62f971817f54d8a80dcd63fcf7098b3cbde9ae8ec1ee449013ec5db0";
+    const HELLO_WORLD_OID: &str =
+        "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    fn request(command: &str, content: &[u8]) -> Vec<u8> {
+        let mut input = vec![];
+        write_pkt_line(&mut input, &PktLine::text(format!("command={}", command))).unwrap();
+        write_pkt_line(&mut input, &PktLine::text("pathname=hello.txt")).unwrap();
+        write_pkt_line(&mut input, &PktLine::Flush).unwrap();
+        write_data(&mut input, content).unwrap();
+        write_pkt_line(&mut input, &PktLine::Flush).unwrap();
+        input
+    }
+
+    fn handshake_input() -> Vec<u8> {
+        let mut input = vec![];
+        for line in [CLIENT_WELCOME, VERSION] {
+            write_pkt_line(&mut input, &PktLine::text(line)).unwrap();
+        }
+        write_pkt_line(&mut input, &PktLine::Flush).unwrap();
+        for line in ["capability=clean", "capability=smudge", "capability=delay"] {
+            write_pkt_line(&mut input, &PktLine::text(line)).unwrap();
+        }
+        write_pkt_line(&mut input, &PktLine::Flush).unwrap();
+        input
+    }
+
+    fn response(content: &[u8]) -> Vec<u8> {
+        let mut expected = vec![];
+        write_pkt_line(&mut expected, &PktLine::text("status=success")).unwrap();
+        write_pkt_line(&mut expected, &PktLine::Flush).unwrap();
+        write_data(&mut expected, content).unwrap();
+        write_pkt_line(&mut expected, &PktLine::Flush).unwrap();
+        write_pkt_line(&mut expected, &PktLine::Flush).unwrap();
+        expected
+    }
+
+    fn handshake_output() -> Vec<u8> {
+        let mut expected = vec![];
+        for line in [SERVER_WELCOME, VERSION] {
+            write_pkt_line(&mut expected, &PktLine::text(line)).unwrap();
+        }
+        write_pkt_line(&mut expected, &PktLine::Flush).unwrap();
+        for line in CAPABILITIES {
+            write_pkt_line(&mut expected, &PktLine::text(line)).unwrap();
+        }
+        write_pkt_line(&mut expected, &PktLine::Flush).unwrap();
+        expected
+    }
+
+    fn pointer() -> Pointer {
+        Pointer {
+            file_oid: Some(HELLO_WORLD_OID.to_string()),
+            oid: RAW_BLOCK_OID.to_string(),
+            size: RAW_BLOCK.len() as u64,
+        }
+    }
+
+    #[test]
+    fn pointer_round_trips() {
+        let text = pointer().to_string();
+        assert_eq!(
+            text,
+            format!(
+                "{}\next-0-ipfs sha256:{}\noid sha256:{}\nsize {}\n",
+                POINTER_VERSION,
+                HELLO_WORLD_OID,
+                RAW_BLOCK_OID,
+                RAW_BLOCK.len()
+            )
+        );
+        assert_eq!(Pointer::parse(text.as_bytes()), Some(pointer()));
+        assert_eq!(Pointer::parse(b"hello world"), None);
+        let traversal = format!("{}\noid sha256:../../x\nsize 1\n", POINTER_VERSION);
+        assert_eq!(Pointer::parse(traversal.as_bytes()), None);
+    }
+
+    #[tokio::test]
+    async fn filter_process_smudges_from_cache() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join(MULTI_HASH), "hello world").unwrap();
+        let cache = Cache::new(dir.path(), u64::MAX);
+
+        let mut input = handshake_input();
+        input.extend(request("smudge", pointer().to_string().as_bytes()));
+        input.extend(request("unknown", b""));
+        let mut output = vec![];
+        filter_process(
+            client(),
+            Some(&cache),
+            // Kept out of IPFS since the pointer was committed, which it still says
+            &Policies::parse("hello.txt -ipfs").unwrap(),
+            None,
+            &Limits::default(),
+            Cursor::new(input),
+            &mut output,
+        )
+        .await
+        .unwrap();
+
+        let mut expected = handshake_output();
+        expected.extend(response(b"hello world"));
+        write_pkt_line(&mut expected, &PktLine::text("status=error")).unwrap();
+        write_pkt_line(&mut expected, &PktLine::Flush).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[tokio::test]
+    async fn filter_process_passes_through_pointers_and_other_files() {
+        let committed = pointer().to_string();
+        let mut input = handshake_input();
+        input.extend(request("clean", committed.as_bytes()));
+        input.extend(request("smudge", b"not a pointer"));
+        let mut output = vec![];
+        filter_process(
+            client(),
+            None,
+            &Policies::default(),
+            None,
+            &Limits::default(),
+            Cursor::new(input),
+            &mut output,
+        )
+        .await
+        .unwrap();
+
+        let mut expected = handshake_output();
+        expected.extend(response(committed.as_bytes()));
+        expected.extend(response(b"not a pointer"));
+        assert_eq!(output, expected);
+    }
+
+    #[tokio::test]
+    async fn filter_process_rejects_unknown_client() {
+        let mut input = vec![];
+        write_pkt_line(&mut input, &PktLine::text("git-other-client")).unwrap();
+        write_pkt_line(&mut input, &PktLine::Flush).unwrap();
+        assert!(filter_process(
+            client(),
+            None,
+            &Policies::default(),
+            None,
+            &Limits::default(),
+            Cursor::new(input),
+            &mut vec![]
+        )
+        .await
+        .is_err());
+    }
+}
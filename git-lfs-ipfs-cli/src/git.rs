@@ -0,0 +1,132 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result};
+
+/// Value of a git config key, or `None` if it is not set
+///
+/// `value_type` has git canonicalize the value, e.g. `int` applies `k`/`m`/`g` suffixes
+/// and `path` expands `~`.
+pub fn config(key: &str, value_type: Option<&str>) -> Result<Option<String>> {
+    Ok(get_config(None, "--get", key, value_type)?.pop())
+}
+
+/// Values of a key that git-lfs also reads from `.lfsconfig`, from git config if it is
+/// set there and from the `.lfsconfig` at the top of the working tree otherwise
+///
+/// Every value of a multi-valued key is returned.
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/man/git-lfs-config.adoc#lfsconfig>
+pub fn lfs_config(key: &str, value_type: Option<&str>) -> Result<Vec<String>> {
+    let values = get_config(None, "--get-all", key, value_type)?;
+    if !values.is_empty() {
+        return Ok(values);
+    }
+    match toplevel().map(|toplevel| toplevel.join(".lfsconfig")) {
+        Some(lfsconfig) if lfsconfig.is_file() => {
+            get_config(Some(&lfsconfig), "--get-all", key, value_type)
+        }
+        _ => Ok(vec![]),
+    }
+}
+
+fn get_config(
+    file: Option<&Path>,
+    get: &str,
+    key: &str,
+    value_type: Option<&str>,
+) -> Result<Vec<String>> {
+    let mut command = Command::new("git");
+    // Values end in NUL rather than a newline, which they may contain
+    command.args(["config", "-z"]);
+    if let Some(file) = file {
+        command.arg("--file").arg(file);
+    }
+    if let Some(value_type) = value_type {
+        command.arg(format!("--type={}", value_type));
+    }
+    let output = command
+        .args([get, key])
+        .output()
+        .context("could not run git config")?;
+    match output.status.code() {
+        Some(0) => Ok(String::from_utf8(output.stdout)
+            .context("git config value is not UTF-8")?
+            .split_terminator('\0')
+            .map(str::to_string)
+            .collect()),
+        // The key is not set
+        Some(1) => Ok(vec![]),
+        _ => Err(anyhow::anyhow!(
+            "git config {} {} failed: {}",
+            get,
+            key,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// The `.git` directory shared by all worktrees, if running inside a repository
+pub fn common_dir() -> Option<PathBuf> {
+    rev_parse("--git-common-dir")
+}
+
+/// The top of the working tree, if running inside one
+pub fn toplevel() -> Option<PathBuf> {
+    rev_parse("--show-toplevel")
+}
+
+/// Username and password for `url` from git's credential helpers, which may prompt
+///
+/// <https://git-scm.com/docs/git-credential>
+pub fn credential_fill(url: &str) -> Result<(String, String)> {
+    let mut child = Command::new("git")
+        .args(["credential", "fill"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("could not run git credential")?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(format!("url={}\n\n", url).as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("git credential fill failed for {}", url));
+    }
+    let output = String::from_utf8(output.stdout).context("credential is not UTF-8")?;
+    let value = |key: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .map(str::to_string)
+            .with_context(|| format!("git credential fill gave no {}", key))
+    };
+    Ok((value("username")?, value("password")?))
+}
+
+/// Short name of the checked out branch, if HEAD is on one
+pub fn current_branch() -> Option<String> {
+    let output = Command::new("git")
+        .args(["symbolic-ref", "--short", "-q", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let branch = String::from_utf8(output.stdout).ok()?;
+    Some(branch.trim_end_matches('\n').to_string())
+}
+
+fn rev_parse(arg: &str) -> Option<PathBuf> {
+    let output = Command::new("git").args(["rev-parse", arg]).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let path = String::from_utf8(output.stdout).ok()?;
+    Some(PathBuf::from(path.trim_end_matches('\n')))
+}
@@ -0,0 +1,233 @@
+use std::{fmt, fs, io::ErrorKind, path::PathBuf, str::FromStr};
+
+use anyhow::{Context, Result};
+use cid::Cid;
+
+use crate::{git, ipfs::sha256_to_cid, notes::Notes};
+
+/// What LFS oids are the SHA-256 of, `block` (the default) or `stock`
+const OID_MODE_KEY: &str = "lfs.ipfs.oidmode";
+
+/// What the LFS oid of a file is the SHA-256 of
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum OidMode {
+    /// The raw root block clean turns the file into, so the oid is also its CID
+    #[default]
+    Block,
+    /// The file itself, like stock git-lfs, with CIDs kept in an [Index]
+    ///
+    /// Clean and smudge leave files alone, and the transfer agent adds them to IPFS.
+    /// LFS servers and `git lfs fsck` see oids that match the files.
+    Stock,
+}
+
+impl OidMode {
+    pub fn from_git_config() -> Result<Self> {
+        match git::config(OID_MODE_KEY, None)?.as_deref() {
+            None | Some("block") => Ok(Self::Block),
+            Some("stock") => Ok(Self::Stock),
+            Some(other) => Err(anyhow::anyhow!(
+                "invalid {} {}, expected block or stock",
+                OID_MODE_KEY,
+                other
+            )),
+        }
+    }
+}
+
+/// Chunker that `ipfs add` uses when none is given
+pub const DEFAULT_CHUNKER: &str = "size-262144";
+
+/// Where an object is in IPFS, and how it was added so the CID can be reproduced
+///
+/// Written as a line like `<cid> chunker=size-262144 cid-version=0 codec=dag-pb`. The
+/// CID version and codec follow from the CID, so they are only there for people
+/// reading the line.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Entry {
+    pub cid: Cid,
+    pub chunker: String,
+}
+
+impl Entry {
+    pub fn new(cid: Cid, chunker: impl Into<String>) -> Self {
+        Self {
+            cid,
+            chunker: chunker.into(),
+        }
+    }
+
+    /// <https://github.com/multiformats/multicodec/blob/master/table.csv>
+    fn codec(&self) -> String {
+        match self.cid.codec() {
+            0x55 => "raw".to_string(),
+            0x70 => "dag-pb".to_string(),
+            other => format!("{:#x}", other),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} chunker={} cid-version={} codec={}",
+            self.cid,
+            self.chunker,
+            u64::from(self.cid.version()),
+            self.codec()
+        )
+    }
+}
+
+impl FromStr for Entry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let cid = fields.next().context("empty index entry")?;
+        let cid = Cid::from_str(cid).with_context(|| format!("invalid CID {}", cid))?;
+        let chunker = fields
+            .find_map(|field| field.strip_prefix("chunker="))
+            .unwrap_or(DEFAULT_CHUNKER);
+        Ok(Self::new(cid, chunker))
+    }
+}
+
+/// CIDs of objects whose oid is the SHA-256 of the file, for [OidMode::Stock]
+///
+/// Each entry is a file named after the oid, so concurrent transfers can add entries
+/// without locking. With [Notes], entries are also kept in git notes that can be shared
+/// with other clones, and entries missing locally are looked up there.
+pub struct Index {
+    dir: PathBuf,
+    notes: Option<Notes>,
+}
+
+impl Index {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            notes: None,
+        }
+    }
+
+    pub fn with_notes(mut self, notes: Notes) -> Self {
+        self.notes = Some(notes);
+        self
+    }
+
+    /// The index of the current repository, backed by its notes
+    pub fn from_git_dir() -> Result<Self> {
+        let git_dir = git::common_dir().context("not in a git repository")?;
+        Ok(Self::new(git_dir.join("lfs").join("ipfs").join("index")).with_notes(Notes::default()))
+    }
+
+    /// The index of the current repository if it uses [OidMode::Stock], since otherwise
+    /// oids lead to CIDs by themselves
+    pub fn for_oid_mode() -> Result<Option<Self>> {
+        match OidMode::from_git_config()? {
+            OidMode::Block => Ok(None),
+            OidMode::Stock => Self::from_git_dir().map(Some),
+        }
+    }
+
+    pub fn get(&self, oid: &str) -> Result<Option<Entry>> {
+        let path = self.path(oid)?;
+        match fs::read_to_string(&path) {
+            Ok(entry) => {
+                return Entry::from_str(&entry)
+                    .map(Some)
+                    .with_context(|| format!("invalid entry in {}", path.display()))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read {}", path.display()))
+            }
+        }
+        let entry = match &self.notes {
+            Some(notes) => notes.get(oid)?,
+            None => None,
+        };
+        if let Some(entry) = &entry {
+            self.write(oid, entry)?;
+        }
+        Ok(entry)
+    }
+
+    pub fn insert(&self, oid: &str, entry: &Entry) -> Result<()> {
+        self.write(oid, entry)?;
+        match &self.notes {
+            Some(notes) => notes.insert(oid, entry),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&self, oid: &str, entry: &Entry) -> Result<()> {
+        let path = self.path(oid)?;
+        let parent = path.parent().expect("entries are in a directory");
+        fs::create_dir_all(parent)
+            .with_context(|| format!("could not create {}", parent.display()))?;
+        let partial_path = path.with_extension(format!("{}.partial", std::process::id()));
+        fs::write(&partial_path, format!("{}\n", entry))?;
+        fs::rename(&partial_path, &path)
+            .with_context(|| format!("could not write {}", path.display()))
+    }
+
+    fn path(&self, oid: &str) -> Result<PathBuf> {
+        if oid.len() != 64 || !oid.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(anyhow::anyhow!("invalid oid {}", oid));
+        }
+        Ok(self.dir.join(&oid[0..2]).join(oid))
+    }
+}
+
+/// CID of the LFS object `oid`, from `index` if there is one
+pub fn cid(index: Option<&Index>, oid: &str) -> Result<Option<Cid>> {
+    match index {
+        Some(index) => Ok(index.get(oid)?.map(|entry| entry.cid)),
+        None => sha256_to_cid(oid).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    const OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    const CID: &str = "Qmf412jQZiuVUtdgnB36FXFX7xg5V6KEbSJ4dpQuhkLyfD";
+
+    fn entry() -> Entry {
+        Entry::new(Cid::from_str(CID).unwrap(), DEFAULT_CHUNKER)
+    }
+
+    #[test]
+    fn index_returns_inserted_cids() {
+        let dir = tempdir().unwrap();
+        let index = Index::new(dir.path());
+        assert_eq!(index.get(OID).unwrap(), None);
+        index.insert(OID, &entry()).unwrap();
+        assert_eq!(index.get(OID).unwrap(), Some(entry()));
+        assert_eq!(fs::read_dir(dir.path().join("b9")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn entry_round_trips_with_options() {
+        let line = format!("{} chunker=size-262144 cid-version=0 codec=dag-pb", CID);
+        assert_eq!(entry().to_string(), line);
+        assert_eq!(Entry::from_str(&line).unwrap(), entry());
+        // Entries from before options were recorded
+        assert_eq!(Entry::from_str(CID).unwrap(), entry());
+        assert!(Entry::from_str("").is_err());
+    }
+
+    #[test]
+    fn index_rejects_invalid_oids() {
+        let dir = tempdir().unwrap();
+        let index = Index::new(dir.path());
+        assert!(index.get("../../etc/passwd").is_err());
+        assert!(index.insert("not-an-oid", &entry()).is_err());
+    }
+}
@@ -1,7 +1,12 @@
 use anyhow::Result;
 use hyper::client::HttpConnector;
-use ipfs_api_backend_hyper::IpfsClient;
+use ipfs_api_backend_hyper::{
+    request::ApiRequest, response::BlockStatResponse, IpfsApi, IpfsClient,
+};
 use multihash::{Code, MultihashDigest};
+use serde::Serialize;
+use std::{collections::HashSet, future::Future, time::Instant};
+use tracing::{debug, info_span, Instrument};
 
 /// Assuming that the sha256 hash is for a Qmhash
 pub fn sha256_to_cid(sha256_str: &str) -> Result<cid::Cid> {
@@ -16,6 +21,137 @@ pub fn sha256_to_cid(sha256_str: &str) -> Result<cid::Cid> {
     }
 }
 
+/// Largest block IPFS nodes exchange, so larger objects can't be raw root blocks
+pub const MAX_BLOCK_LEN: usize = 1024 * 1024;
+
+/// Whether `block` looks like a dag-pb node holding UnixFS data
+///
+/// That is what clean turns files into, so it tells objects that went through IPFS
+/// apart from plain LFS objects, which are very unlikely to parse as one.
+///
+/// <https://ipld.io/specs/codecs/dag-pb/spec/#serial-form>
+pub fn is_unixfs_block(block: &[u8]) -> bool {
+    /// Field 2, length-delimited
+    const LINKS: u64 = 0x12;
+    /// Field 1, length-delimited
+    const DATA: u64 = 0x0a;
+    /// Field 1 of UnixFS data, a varint
+    const UNIXFS_TYPE: u8 = 0x08;
+
+    let mut rest = block;
+    let mut data = None;
+    while !rest.is_empty() {
+        let (key, len, tail) = match varint(rest).and_then(|(key, tail)| {
+            let (len, tail) = varint(tail)?;
+            Some((key, usize::try_from(len).ok()?, tail))
+        }) {
+            Some(field) => field,
+            None => return false,
+        };
+        if tail.len() < len {
+            return false;
+        }
+        let (value, tail) = tail.split_at(len);
+        match key {
+            // Links all come before the data
+            LINKS if data.is_none() => {}
+            DATA if data.is_none() => data = Some(value),
+            _ => return false,
+        }
+        rest = tail;
+    }
+    matches!(data, Some([UNIXFS_TYPE, ..]))
+}
+
+/// Protobuf varint at the start of `bytes`, and what follows it
+fn varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+/// Whether the node holds a recursive pin on `cid`, like the ones clean and the
+/// transfer agent add
+///
+/// The daemon fails when asked about a CID that isn't pinned, so a failure is followed
+/// by asking for its version: if the node answers that, it is up and the CID is simply
+/// not pinned. This keeps each check to the one CID rather than all pins of the node.
+pub async fn is_pinned<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    cid: &str,
+) -> Result<bool> {
+    match client.pin_ls(Some(cid), Some("recursive")).await {
+        Ok(pins) => Ok(pins.keys.contains_key(cid)),
+        Err(err) => match client.version().await {
+            Ok(_) => Ok(false),
+            Err(_) => Err(err.into()),
+        },
+    }
+}
+
+/// `block stat` that only looks in the node's own blockstore
+///
+/// `offline` is an option of every command of the daemon, which keeps it from asking
+/// the network for blocks it doesn't have.
+#[derive(Serialize)]
+struct LocalBlockStat<'a> {
+    #[serde(rename = "arg")]
+    hash: &'a str,
+    offline: bool,
+}
+
+impl ApiRequest for LocalBlockStat<'_> {
+    const PATH: &'static str = "/block/stat";
+}
+
+/// Whether the node has the block `cid` itself, which it answers right away
+///
+/// Like in [is_pinned], a failure only means the block is missing if the node answers
+/// when asked for its version.
+pub async fn has_block<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    cid: &str,
+) -> Result<bool> {
+    let request = LocalBlockStat {
+        hash: cid,
+        offline: true,
+    };
+    let stat = client.request::<_, BlockStatResponse>(request, None);
+    match traced("block_stat", cid, stat).await {
+        Ok(_) => Ok(true),
+        Err(err) => match client.version().await {
+            Ok(_) => Ok(false),
+            Err(_) => Err(err.into()),
+        },
+    }
+}
+
+/// CIDs of all recursive pins of the node
+pub async fn recursive_pins<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+) -> Result<HashSet<String>> {
+    Ok(client
+        .pin_ls(None, Some("recursive"))
+        .await?
+        .keys
+        .into_keys()
+        .collect())
+}
+
+/// Run an IPFS request in a span of its own, logging how long it took
+pub async fn traced<T>(request: &'static str, cid: &str, future: impl Future<Output = T>) -> T {
+    let span = info_span!("ipfs", request, cid);
+    let started = Instant::now();
+    let output = future.instrument(span.clone()).await;
+    span.in_scope(|| debug!(latency_ms = started.elapsed().as_millis() as u64, "done"));
+    output
+}
+
 pub fn client() -> IpfsClient<hyper_rustls::HttpsConnector<HttpConnector>> {
     IpfsClient::default()
 }
@@ -39,6 +175,39 @@ mod tests {
         assert!(sha256_to_cid("abcd").is_err());
     }
 
+    #[tokio::test]
+    #[ignore]
+    async fn is_pinned_is_false_for_cids_the_node_never_pinned() {
+        // The sha256 of "hello world" as a CID, which is no block anyone adds
+        assert!(!is_pinned(&client(), QM_HASH_SUM).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn has_block_answers_without_looking_on_the_network() {
+        let client = client();
+        let has_block = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            has_block(&client, QM_HASH_SUM),
+        );
+        assert!(!has_block.await.unwrap().unwrap());
+    }
+
+    #[test]
+    fn is_unixfs_block_tells_raw_blocks_from_files() {
+        assert!(is_unixfs_block(include_bytes!(
+            "../test/hello_world_raw_block"
+        )));
+        assert!(!is_unixfs_block(_INPUT.as_bytes()));
+        assert!(!is_unixfs_block(b""));
+        // Truncated
+        assert!(!is_unixfs_block(
+            &include_bytes!("../test/hello_world_raw_block")[..10]
+        ));
+        // Data that isn't UnixFS
+        assert!(!is_unixfs_block(&[0x0a, 0x01, 0x00]));
+    }
+
     #[test]
     fn sha256_to_cid_returns_err_for_non_hex_string() {
         assert!(sha256_to_cid("foo").is_err());
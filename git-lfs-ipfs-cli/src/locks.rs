@@ -0,0 +1,339 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use git_lfs_spec::locks::{
+    ListLocksQuery, ListLocksResponse, Lock, Owner, VerifyLocksRequest, VerifyLocksResponse,
+};
+use multihash::{Hasher, Sha2_256};
+use tokio::sync::{Mutex, MutexGuard};
+
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Someone already holds a lock on the path
+    Conflict(Lock),
+    NotFound,
+    /// The lock belongs to another user and deletion was not forced
+    Forbidden(Lock),
+    Storage(anyhow::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockError::Conflict(lock) => write!(f, "{} is already locked", lock.path),
+            LockError::NotFound => write!(f, "lock does not exist"),
+            LockError::Forbidden(lock) => write!(
+                f,
+                "lock on {} is owned by {}",
+                lock.path,
+                lock.owner.as_ref().map(|o| o.name.as_str()).unwrap_or("?")
+            ),
+            LockError::Storage(err) => write!(f, "lock database error: {:#}", err),
+        }
+    }
+}
+
+impl From<anyhow::Error> for LockError {
+    fn from(err: anyhow::Error) -> Self {
+        LockError::Storage(err)
+    }
+}
+
+/// File locks of a repository, kept in a JSON database on the server's disk
+///
+/// Requests wait their turn on an async mutex and read and write the database with
+/// tokio's file system functions, so the server's other requests keep being answered.
+/// Each read, change and write of the database also holds an exclusive lock on a
+/// `.lock` file next to it, since the SSH server runs one process per connection.
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/locking.md>
+pub struct LockStore {
+    path: PathBuf,
+    guard: Mutex<()>,
+}
+
+impl LockStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            guard: Mutex::new(()),
+        }
+    }
+
+    pub async fn create(&self, path: String, owner: &str) -> Result<Lock, LockError> {
+        let _guard = self.lock().await?;
+        let mut locks = self.load().await?;
+        if let Some(existing) = locks.iter().find(|lock| lock.path == path) {
+            return Err(LockError::Conflict(existing.clone()));
+        }
+        let lock = Lock {
+            id: lock_id(&path),
+            path,
+            locked_at: Utc::now().into(),
+            owner: Some(Owner {
+                name: owner.to_string(),
+            }),
+        };
+        locks.push(lock.clone());
+        self.save(&locks).await?;
+        Ok(lock)
+    }
+
+    pub async fn list(&self, query: &ListLocksQuery) -> Result<ListLocksResponse, LockError> {
+        let _guard = self.lock().await?;
+        let matching = self
+            .load()
+            .await?
+            .into_iter()
+            .filter(|lock| query.path.iter().all(|path| &lock.path == path))
+            .filter(|lock| query.id.iter().all(|id| &lock.id == id))
+            .collect::<Vec<_>>();
+        let (locks, next_cursor) = page(matching, query.cursor.as_deref(), query.limit)?;
+        Ok(ListLocksResponse { locks, next_cursor })
+    }
+
+    pub async fn verify(
+        &self,
+        owner: &str,
+        request: &VerifyLocksRequest,
+    ) -> Result<VerifyLocksResponse, LockError> {
+        let _guard = self.lock().await?;
+        let (locks, next_cursor) =
+            page(self.load().await?, request.cursor.as_deref(), request.limit)?;
+        let (ours, theirs) = locks
+            .into_iter()
+            .partition(|lock| lock.owner.as_ref().map(|o| o.name.as_str()) == Some(owner));
+        Ok(VerifyLocksResponse {
+            ours,
+            theirs,
+            next_cursor,
+        })
+    }
+
+    pub async fn unlock(&self, id: &str, owner: &str, force: bool) -> Result<Lock, LockError> {
+        let _guard = self.lock().await?;
+        let mut locks = self.load().await?;
+        let index = locks
+            .iter()
+            .position(|lock| lock.id == id)
+            .ok_or(LockError::NotFound)?;
+        let is_owner = locks[index].owner.as_ref().map(|o| o.name.as_str()) == Some(owner);
+        if !is_owner && !force {
+            return Err(LockError::Forbidden(locks[index].clone()));
+        }
+        let lock = locks.remove(index);
+        self.save(&locks).await?;
+        Ok(lock)
+    }
+
+    /// Wait for the other requests of this process, then for other processes
+    async fn lock(&self) -> Result<(MutexGuard<'_, ()>, FileLock)> {
+        let guard = self.guard.lock().await;
+        let path = self.path.with_extension("lock");
+        let file_lock = tokio::task::spawn_blocking(move || FileLock::acquire(&path))
+            .await
+            .context("could not lock the lock database")??;
+        Ok((guard, file_lock))
+    }
+
+    async fn load(&self) -> Result<Vec<Lock>> {
+        match tokio::fs::read(&self.path).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("could not parse {}", self.path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(err) => Err(err).with_context(|| format!("could not open {}", self.path.display())),
+        }
+    }
+
+    async fn save(&self, locks: &[Lock]) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(locks)?).await?;
+        tokio::fs::rename(&tmp_path, &self.path)
+            .await
+            .with_context(|| format!("could not write {}", self.path.display()))
+    }
+}
+
+/// Exclusive lock on a file, released when it is dropped and the file is closed
+struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    fn acquire(path: &std::path::Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        lock_exclusive(&file).with_context(|| format!("could not lock {}", path.display()))?;
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the descriptor belongs to `file`, which is open for the whole call
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Elsewhere, only requests of the same process take turns
+#[cfg(not(unix))]
+fn lock_exclusive(_: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Cursors are offsets into the list of locks
+fn page(
+    locks: Vec<Lock>,
+    cursor: Option<&str>,
+    limit: Option<usize>,
+) -> Result<(Vec<Lock>, Option<String>)> {
+    let start = cursor
+        .map(|cursor| cursor.parse::<usize>().context("invalid cursor"))
+        .transpose()?
+        .unwrap_or(0);
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).max(1);
+    let end = locks.len().min(start.saturating_add(limit));
+    let next_cursor = (end < locks.len()).then(|| end.to_string());
+    Ok((
+        locks
+            .into_iter()
+            .skip(start)
+            .take(end.saturating_sub(start))
+            .collect(),
+        next_cursor,
+    ))
+}
+
+fn lock_id(path: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let mut hasher = Sha2_256::default();
+    hasher.update(path.as_bytes());
+    hasher.update(&nanos.to_le_bytes());
+    hex::encode(&hasher.finalize()[..16])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn create_conflicts_on_locked_path() {
+        let dir = tempdir().unwrap();
+        let store = LockStore::new(dir.path().join("locks.json"));
+        let lock = store
+            .create("foo/bar.zip".to_string(), "jane")
+            .await
+            .unwrap();
+        match store.create("foo/bar.zip".to_string(), "john").await {
+            Err(LockError::Conflict(existing)) => assert_eq!(existing, lock),
+            other => panic!("expected conflict, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stores_of_the_same_database_take_turns() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("locks.json");
+        // Like two SSH server processes, which share nothing but the file
+        let stores = (0..8).map(|_| LockStore::new(&path)).collect::<Vec<_>>();
+        let created = futures::future::join_all(
+            stores
+                .iter()
+                .map(|store| store.create("a.psd".to_string(), "jane")),
+        )
+        .await;
+        assert_eq!(created.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(created
+            .iter()
+            .all(|result| matches!(result, Ok(_) | Err(LockError::Conflict(_)))));
+        // None of the locks on other paths are lost
+        let created = futures::future::join_all(
+            stores
+                .iter()
+                .enumerate()
+                .map(|(i, store)| store.create(format!("{}.psd", i), "jane")),
+        )
+        .await;
+        assert!(created.iter().all(Result::is_ok));
+        let listed = stores[0].list(&ListLocksQuery::default()).await.unwrap();
+        assert_eq!(listed.locks.len(), 1 + stores.len());
+    }
+
+    #[tokio::test]
+    async fn verify_splits_ours_and_theirs() {
+        let dir = tempdir().unwrap();
+        let store = LockStore::new(dir.path().join("locks.json"));
+        let ours = store.create("a.psd".to_string(), "jane").await.unwrap();
+        let theirs = store.create("b.psd".to_string(), "john").await.unwrap();
+        let response = store
+            .verify("jane", &VerifyLocksRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(response.ours, vec![ours]);
+        assert_eq!(response.theirs, vec![theirs]);
+        assert_eq!(response.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn list_pages_with_cursor() {
+        let dir = tempdir().unwrap();
+        let store = LockStore::new(dir.path().join("locks.json"));
+        for path in ["a", "b", "c"] {
+            store.create(path.to_string(), "jane").await.unwrap();
+        }
+        let query = ListLocksQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = store.list(&query).await.unwrap();
+        assert_eq!(first.locks.len(), 2);
+        assert_eq!(first.next_cursor.as_deref(), Some("2"));
+        let second = store
+            .list(&ListLocksQuery {
+                cursor: first.next_cursor,
+                ..query
+            })
+            .await
+            .unwrap();
+        assert_eq!(second.locks.len(), 1);
+        assert_eq!(second.locks[0].path, "c");
+        assert_eq!(second.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn unlock_requires_owner_or_force() {
+        let dir = tempdir().unwrap();
+        let store = LockStore::new(dir.path().join("locks.json"));
+        let lock = store.create("a.psd".to_string(), "jane").await.unwrap();
+        assert!(matches!(
+            store.unlock(&lock.id, "john", false).await,
+            Err(LockError::Forbidden(_))
+        ));
+        assert_eq!(store.unlock(&lock.id, "john", true).await.unwrap(), lock);
+        assert!(matches!(
+            store.unlock(&lock.id, "jane", false).await,
+            Err(LockError::NotFound)
+        ));
+    }
+}
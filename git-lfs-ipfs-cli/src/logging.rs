@@ -0,0 +1,63 @@
+use std::{fs::OpenOptions, sync::Mutex};
+
+use anyhow::{Context, Result};
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, writer::BoxMakeWriter},
+    EnvFilter,
+};
+
+/// File to append logs to, instead of stderr
+///
+/// stdout is never used: it carries the git-lfs protocols.
+const LOG_ENV: &str = "GIT_LFS_IPFS_LOG";
+/// Filter directives like `debug` or `git_lfs_ipfs_cli=trace`
+const LOG_LEVEL_ENV: &str = "GIT_LFS_IPFS_LOG_LEVEL";
+/// `json` to write one JSON object per line
+const LOG_FORMAT_ENV: &str = "GIT_LFS_IPFS_LOG_FORMAT";
+
+const DEFAULT_LEVEL: &str = "warn";
+
+/// Send logs to stderr, where `GIT_TRACE` picks them up, or to the file in [LOG_ENV]
+pub fn init() -> Result<()> {
+    let filter = filter(std::env::var(LOG_LEVEL_ENV).ok().as_deref())?;
+    let json = std::env::var(LOG_FORMAT_ENV).is_ok_and(|format| format == "json");
+    let (writer, ansi) = match std::env::var_os(LOG_ENV) {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("could not open log file {:?}", path))?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), true),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(writer);
+    let result = if json {
+        builder.json().try_init()
+    } else {
+        builder.with_ansi(ansi).try_init()
+    };
+    result.map_err(|err| anyhow::anyhow!(err))
+}
+
+fn filter(directives: Option<&str>) -> Result<EnvFilter> {
+    let directives = directives.unwrap_or(DEFAULT_LEVEL);
+    EnvFilter::try_new(directives)
+        .with_context(|| format!("invalid {} {:?}", LOG_LEVEL_ENV, directives))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_rejects_invalid_level() {
+        assert!(filter(None).is_ok());
+        assert!(filter(Some("git_lfs_ipfs_cli=trace,info")).is_ok());
+        assert!(filter(Some("git_lfs_ipfs_cli=loud")).is_err());
+    }
+}
@@ -1,15 +1,36 @@
 use anyhow::Result;
-use futures::StreamExt;
-use git_lfs_spec::transfer::custom::Event;
-use std::path::PathBuf;
+use git_lfs_spec::transfer::custom::agent;
+use std::{ffi::OsString, net::SocketAddr, path::PathBuf};
 use structopt::StructOpt;
 use tokio::io::{stdin, stdout, BufReader};
 
 use crate::{clean::clean, smudge::smudge};
 
+mod cache;
 mod clean;
+mod compression;
+mod encryption;
+mod filter_process;
+mod git;
+mod index;
+mod locks;
+mod logging;
+mod manifest;
+mod migrate;
+mod notes;
+mod peers;
+mod pins;
+mod policy;
+mod prefetch;
+mod prune;
+mod rate_limit;
+mod remote;
+mod server;
 mod smudge;
+mod ssh_transfer;
+mod timeouts;
 mod transfer;
+mod verify;
 
 mod ipfs;
 
@@ -20,7 +41,9 @@ enum GitLfsIpfs {
     ///
     /// https://github.com/git-lfs/git-lfs/blob/main/docs/extensions.md#smudge
     Smudge {
-        /// Name of the file
+        /// Name of the file, which git-lfs passes though the object alone says how to
+        /// smudge it
+        #[allow(dead_code)]
         filename: PathBuf,
     },
     /// git-lfs clean filter extension for IPFS
@@ -30,33 +53,309 @@ enum GitLfsIpfs {
         /// Name of the file
         filename: PathBuf,
     },
+    /// Long-running git filter that cleans and smudges many files for IPFS
+    ///
+    /// <https://git-scm.com/docs/gitattributes#_long_running_filter_process>
+    FilterProcess,
     /// git-lfs custom transfer for IPFS
     ///
     /// <https://github.com/git-lfs/git-lfs/blob/main/docs/custom-transfers.md>
     Transfer,
+    /// Git LFS HTTP server backed by IPFS
+    ///
+    /// Serves object verification and the file locking API.
+    ///
+    /// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/basic-transfers.md#verification>
+    ///
+    /// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/locking.md>
+    Serve {
+        /// Address to listen on
+        #[structopt(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+        /// JSON file the server keeps file locks in
+        #[structopt(long, default_value = "git-lfs-ipfs-locks.json")]
+        lock_db: PathBuf,
+    },
+    /// git-lfs pure SSH transfer server for IPFS
+    ///
+    /// This is also what runs when the binary is invoked as `git-lfs-transfer`.
+    ///
+    /// <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md>
+    SshTransfer {
+        /// Path of the repository on the server, where file locks are kept
+        path: PathBuf,
+        /// Either upload or download
+        #[structopt(parse(try_from_str = ssh_transfer::parse_operation))]
+        operation: git_lfs_spec::batch::Operation,
+        /// Name that owns the locks created in this session
+        #[structopt(long, env = "USER")]
+        user: String,
+    },
+    /// Add the objects of existing LFS storage to IPFS
+    ///
+    /// Objects that are missing locally are fetched from the remote's LFS server first.
+    /// Prints a line of JSON with the CID of each object.
+    Migrate {
+        /// Remote to fetch missing objects from
+        #[structopt(long, default_value = "origin")]
+        remote: String,
+        /// Only report what would be migrated, without fetching or pinning anything
+        #[structopt(long)]
+        dry_run: bool,
+        /// Refs whose objects are migrated
+        #[structopt(default_value = "HEAD")]
+        refs: Vec<String>,
+    },
+    /// Publish a manifest of the CIDs of LFS objects to IPNS
+    ///
+    /// Anyone who knows the IPNS name can then mirror or pin every object.
+    Publish {
+        /// Name of the IPFS key whose IPNS name is published to
+        #[structopt(long, default_value = "self")]
+        key: String,
+        /// How long the record stays valid, like `24h`
+        #[structopt(long)]
+        lifetime: Option<String>,
+        /// Refs whose objects are in the manifest
+        #[structopt(default_value = "HEAD")]
+        refs: Vec<String>,
+    },
+    /// Check that the LFS objects of refs can be retrieved in full from IPFS and are pinned
+    ///
+    /// Exits with 2 if some objects are only unpinned, and with 3 if some are missing,
+    /// incomplete or don't match their pointers.
+    Verify {
+        /// Print a line of JSON per object instead of only the problems
+        #[structopt(long)]
+        json: bool,
+        /// Seconds to wait for each request to IPFS
+        #[structopt(long, default_value = "60")]
+        timeout: u64,
+        /// Refs whose objects are verified
+        #[structopt(default_value = "HEAD")]
+        refs: Vec<String>,
+    },
+    /// Fetch the LFS objects of refs into the local IPFS node, without checking them out
+    Prefetch {
+        /// Objects fetched at once
+        #[structopt(long, default_value = "8")]
+        jobs: usize,
+        /// Pin the objects too, so the node keeps them
+        #[structopt(long)]
+        pin: bool,
+        /// Refs whose objects are fetched
+        #[structopt(default_value = "HEAD")]
+        refs: Vec<String>,
+    },
+    /// Unpin the LFS objects of the history that retained refs no longer reference
+    Prune {
+        /// Latest commits of each retained ref whose objects are kept
+        #[structopt(long, default_value = "1")]
+        depth: usize,
+        /// Keep objects pinned this many days ago or later
+        #[structopt(long, default_value = "14")]
+        window_days: u64,
+        /// Only report what would be unpinned
+        #[structopt(long)]
+        dry_run: bool,
+        /// Patterns of retained refs, like for `git for-each-ref`, by default all
+        /// branches, remote branches and tags
+        refs: Vec<String>,
+    },
+    /// Merge the CIDs noted for stock oids with those of a remote and push them back
+    ///
+    /// Run it after pushing, so others find the objects in IPFS, and after fetching.
+    NotesSync {
+        /// Remote to sync the notes with
+        #[structopt(default_value = "origin")]
+        remote: String,
+    },
+}
+
+/// Name git-lfs invokes over SSH for the pure SSH transfer protocol
+const GIT_LFS_TRANSFER: &str = "git-lfs-transfer";
+
+fn parse_args() -> GitLfsIpfs {
+    let mut args = std::env::args_os().collect::<Vec<_>>();
+    let invoked_as_transfer = args
+        .first()
+        .and_then(|arg0| std::path::Path::new(arg0).file_name())
+        .is_some_and(|name| name == GIT_LFS_TRANSFER);
+    if invoked_as_transfer {
+        args.insert(1, OsString::from("ssh-transfer"));
+    }
+    GitLfsIpfs::from_iter(args)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    logging::init()?;
     let client = crate::ipfs::client();
-    match GitLfsIpfs::from_args() {
-        GitLfsIpfs::Smudge { filename: _ } => smudge(client, stdin(), stdout()).await,
-        GitLfsIpfs::Clean { filename: _ } => clean(client, std::io::stdin(), stdout()).await,
+    match parse_args() {
+        GitLfsIpfs::Clean { filename }
+            if index::OidMode::from_git_config()? == index::OidMode::Stock =>
+        {
+            // The transfer adds files with its own settings, so these would be lost
+            let policy = policy::Policies::from_working_tree()?.for_path(&filename);
+            if policy != policy::Policy::default() {
+                return Err(anyhow::anyhow!(
+                    "{} has settings in {}, which lfs.ipfs.oidmode stock does not support",
+                    filename.display(),
+                    policy::POLICY_FILE
+                ));
+            }
+            // LFS objects are the files themselves
+            tokio::io::copy(&mut stdin(), &mut stdout()).await?;
+            Ok(())
+        }
+        GitLfsIpfs::Smudge { .. }
+            if index::OidMode::from_git_config()? == index::OidMode::Stock =>
+        {
+            tokio::io::copy(&mut stdin(), &mut stdout()).await?;
+            Ok(())
+        }
+        GitLfsIpfs::Smudge { .. } => {
+            let cache = cache::Cache::from_git_config()?;
+            let dictionaries = policy::Policies::from_working_tree()?.dictionaries();
+            let key = encryption::Key::from_git_config()?;
+            smudge(
+                client,
+                cache.as_ref(),
+                &dictionaries,
+                key.as_ref(),
+                &rate_limit::Limits::from_git_config(None)?,
+                stdin(),
+                stdout(),
+            )
+            .await
+        }
+        GitLfsIpfs::Clean { filename } => {
+            let policy = policy::Policies::from_working_tree()?.for_path(&filename);
+            let key = encryption::Key::from_git_config()?;
+            clean(
+                client,
+                &policy,
+                &pins::Pins::from_git_dir()?,
+                key.as_ref(),
+                &rate_limit::Limits::from_git_config(None)?,
+                std::io::stdin(),
+                stdout(),
+            )
+            .await
+        }
+        GitLfsIpfs::FilterProcess => {
+            if index::OidMode::from_git_config()? == index::OidMode::Stock {
+                // Stock pointers are git-lfs's own, and its filter writes them
+                return Err(anyhow::anyhow!(
+                    "filter-process does not support lfs.ipfs.oidmode stock"
+                ));
+            }
+            let cache = cache::Cache::from_git_config()?;
+            filter_process::filter_process(
+                client,
+                cache.as_ref(),
+                &policy::Policies::from_working_tree()?,
+                encryption::Key::from_git_config()?.as_ref(),
+                &rate_limit::Limits::from_git_config(None)?,
+                std::io::stdin(),
+                std::io::BufWriter::new(std::io::stdout()),
+            )
+            .await
+        }
         GitLfsIpfs::Transfer => {
-            let buffered_stdin = BufReader::new(stdin());
-            let input_event_stream = transfer::read_events(buffered_stdin);
-            let download_folder = std::env::current_dir()?;
-            let output_event_stream =
-                transfer::transfer(client, input_event_stream, download_folder);
-            futures_util::pin_mut!(output_event_stream);
-            while let Some(output_event) = output_event_stream.next().await.transpose()? {
-                if Event::AcknowledgeInit == output_event {
-                    println!("{{ }}");
-                } else {
-                    println!("{}", serde_json::to_string(&output_event)?);
+            let mut agent = transfer::IpfsAgent::new(client, std::env::current_dir()?)
+                .with_timeouts(timeouts::Timeouts::from_git_config()?);
+            if index::OidMode::from_git_config()? == index::OidMode::Stock {
+                agent = agent.with_index(index::Index::from_git_dir()?);
+                if let Some(key) = encryption::Key::from_git_config()? {
+                    agent = agent.with_key(key);
                 }
             }
-            Ok(())
+            Ok(agent::run(&agent, BufReader::new(stdin()), stdout()).await?)
+        }
+        GitLfsIpfs::Serve { listen, lock_db } => {
+            server::serve(client, listen, locks::LockStore::new(lock_db)).await
+        }
+        GitLfsIpfs::SshTransfer {
+            path,
+            operation,
+            user,
+        } => {
+            ssh_transfer::ssh_transfer(
+                client,
+                operation,
+                locks::LockStore::new(path.join("lfs-ipfs-locks.json")),
+                pins::Pins::for_git_dir(&path),
+                &user,
+                std::io::stdin(),
+                std::io::BufWriter::new(std::io::stdout()),
+            )
+            .await
+        }
+        GitLfsIpfs::Migrate {
+            remote,
+            dry_run,
+            refs,
+        } => migrate::migrate(client, &remote, &refs, dry_run, std::io::stdout().lock()).await,
+        GitLfsIpfs::Publish {
+            key,
+            lifetime,
+            refs,
+        } => {
+            manifest::publish(
+                client,
+                &key,
+                lifetime.as_deref(),
+                &refs,
+                std::io::stdout().lock(),
+            )
+            .await
+        }
+        GitLfsIpfs::Verify {
+            json,
+            timeout,
+            refs,
+        } => {
+            let code = verify::verify(
+                client,
+                &refs,
+                json,
+                std::time::Duration::from_secs(timeout),
+                std::io::stdout().lock(),
+            )
+            .await?;
+            std::process::exit(code)
+        }
+        GitLfsIpfs::Prefetch { jobs, pin, refs } => {
+            prefetch::prefetch(
+                client,
+                &refs,
+                jobs,
+                pin,
+                &rate_limit::Limits::from_git_config(None)?,
+                std::io::stderr().lock(),
+            )
+            .await
+        }
+        GitLfsIpfs::Prune {
+            depth,
+            window_days,
+            dry_run,
+            mut refs,
+        } => {
+            if refs.is_empty() {
+                refs = prune::DEFAULT_REFS.iter().map(|r| r.to_string()).collect();
+            }
+            prune::prune(
+                client,
+                &refs,
+                depth,
+                window_days,
+                dry_run,
+                std::io::stdout().lock(),
+            )
+            .await
         }
+        GitLfsIpfs::NotesSync { remote } => notes::Notes::default().sync(&remote),
     }
 }
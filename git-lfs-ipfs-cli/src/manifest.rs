@@ -0,0 +1,263 @@
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Write},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use cid::Cid;
+use futures::TryStreamExt;
+use ipfs_api_backend_hyper::{request, response::AddResponse, IpfsApi};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    git,
+    index::Index,
+    ipfs::sha256_to_cid,
+    migrate::list_objects,
+    timeouts::{TimedOut, Timeouts},
+    transfer::probe,
+};
+
+/// Version of the manifest format, bumped for incompatible changes
+const VERSION: u32 = 1;
+
+/// Map of the LFS objects of a repository to their CIDs, published to an IPNS name
+///
+/// Consumers who only know the name can mirror or pin every object of the project. The
+/// transfer agent also looks up stock oids in the manifest of a remote, when
+/// `remote.<name>.lfsipfsmanifest` is set.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    version: u32,
+    objects: BTreeMap<String, String>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            objects: BTreeMap::new(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn insert(&mut self, oid: &str, cid: &Cid) {
+        self.objects.insert(oid.to_string(), cid.to_string());
+    }
+
+    pub fn get(&self, oid: &str) -> Result<Option<Cid>> {
+        self.objects
+            .get(oid)
+            .map(|cid| Cid::from_str(cid))
+            .transpose()
+            .with_context(|| format!("invalid CID for {} in manifest", oid))
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// IPNS name of the manifest of `remote`, if one is configured
+    pub fn name_for_remote(remote: &str) -> Result<Option<String>> {
+        git::config(&format!("remote.{}.lfsipfsmanifest", remote), None)
+    }
+
+    /// Fetch the manifest that an IPNS name points to
+    pub async fn resolve(resolver: &impl NameResolver, name: &str) -> Result<Self> {
+        let name = if name.starts_with('/') {
+            name.to_string()
+        } else {
+            format!("/ipns/{}", name)
+        };
+        let path = resolver.resolve(&name).await?;
+        let contents = resolver.cat(&path).await?;
+        let manifest: Self = serde_json::from_slice(&contents)
+            .with_context(|| format!("invalid manifest at {}", path))?;
+        if manifest.version != VERSION {
+            return Err(anyhow::anyhow!(
+                "manifest at {} has unknown version {}",
+                path,
+                manifest.version
+            ));
+        }
+        info!(
+            name = name.as_str(),
+            path = path.as_str(),
+            objects = manifest.len(),
+            "resolved manifest"
+        );
+        Ok(manifest)
+    }
+}
+
+/// Follows IPNS names to the contents they point to
+#[async_trait]
+pub trait NameResolver {
+    /// IPFS path that `name` points to
+    async fn resolve(&self, name: &str) -> Result<String>;
+
+    async fn cat(&self, path: &str) -> Result<Vec<u8>>;
+}
+
+#[async_trait]
+impl<C, E> NameResolver for C
+where
+    C: IpfsApi<Error = E> + Send + Sync,
+    E: 'static + Send + Sync + std::error::Error,
+{
+    async fn resolve(&self, name: &str) -> Result<String> {
+        let response = self.name_resolve(Some(name), true, false).await?;
+        Ok(response.path)
+    }
+
+    async fn cat(&self, path: &str) -> Result<Vec<u8>> {
+        Ok(IpfsApi::cat(self, path)
+            .map_ok(|chunk| chunk.to_vec())
+            .try_concat()
+            .await?)
+    }
+}
+
+/// Publish a manifest of the LFS objects of `refs` to the IPNS name of `key`
+///
+/// Objects that aren't in IPFS, like those only on the remote's LFS server, are left
+/// out with a warning, and so are those IPFS doesn't find before the [Timeouts] give
+/// up. Publishing fails if the node can't be reached.
+pub async fn publish<E: 'static + Send + Sync + std::error::Error>(
+    client: impl IpfsApi<Error = E> + Send + Sync,
+    key: &str,
+    lifetime: Option<&str>,
+    refs: &[String],
+    mut report: impl Write,
+) -> Result<()> {
+    let index = Index::for_oid_mode()?;
+    let timeouts = Timeouts::from_git_config()?;
+    let objects = list_objects(refs)?;
+    let mut manifest = Manifest::default();
+    for object in &objects {
+        let cid = match &index {
+            Some(index) => index.get(&object.oid)?.map(|entry| entry.cid),
+            None => {
+                let cid = sha256_to_cid(&object.oid)?;
+                match probe(&client, &timeouts, &cid.to_string()).await {
+                    Ok(found) => found.then_some(cid),
+                    Err(err) if err.is::<TimedOut>() => None,
+                    Err(err) => return Err(err),
+                }
+            }
+        };
+        match cid {
+            Some(cid) => manifest.insert(&object.oid, &cid),
+            None => warn!(oid = object.oid.as_str(), "not in IPFS, leaving it out"),
+        }
+    }
+
+    let options = request::Add {
+        pin: Some(true),
+        ..Default::default()
+    };
+    let AddResponse { hash, .. } = client
+        .add_with_options(Cursor::new(serde_json::to_vec(&manifest)?), options)
+        .await?;
+    let path = format!("/ipfs/{}", hash);
+    let published = client
+        .name_publish(&path, false, lifetime, None, Some(key))
+        .await?;
+    info!(
+        name = published.name.as_str(),
+        path = path.as_str(),
+        objects = manifest.len(),
+        "published manifest"
+    );
+    writeln!(
+        report,
+        "Published {} of {} objects to /ipns/{}",
+        manifest.len(),
+        objects.len(),
+        published.name
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    const OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    const CID: &str = "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4";
+    const NAME: &str = "k51qzi5uqu5dlvj2baxnqndepeb86cbk3ng7n3i46uzyxzyqj2xjonzllnv0v8";
+    const PATH: &str = "/ipfs/QmNLei78zWmzUdbeRB3CiUfAizWUrbeeZh5K1rhAQKCh51";
+
+    /// Names and contents held in memory, instead of on an IPFS node
+    #[derive(Default)]
+    struct MockResolver {
+        names: HashMap<String, String>,
+        contents: HashMap<String, Vec<u8>>,
+    }
+
+    #[async_trait]
+    impl NameResolver for MockResolver {
+        async fn resolve(&self, name: &str) -> Result<String> {
+            self.names
+                .get(name)
+                .cloned()
+                .with_context(|| format!("could not resolve {}", name))
+        }
+
+        async fn cat(&self, path: &str) -> Result<Vec<u8>> {
+            self.contents
+                .get(path)
+                .cloned()
+                .with_context(|| format!("{} not found", path))
+        }
+    }
+
+    fn resolver(manifest: &[u8]) -> MockResolver {
+        let mut resolver = MockResolver::default();
+        resolver
+            .names
+            .insert(format!("/ipns/{}", NAME), PATH.to_string());
+        resolver
+            .contents
+            .insert(PATH.to_string(), manifest.to_vec());
+        resolver
+    }
+
+    #[tokio::test]
+    async fn manifest_resolves_through_ipns() {
+        let mut manifest = Manifest::default();
+        manifest.insert(OID, &Cid::from_str(CID).unwrap());
+        let resolver = resolver(&serde_json::to_vec(&manifest).unwrap());
+
+        let resolved = Manifest::resolve(&resolver, NAME).await.unwrap();
+        assert_eq!(resolved, manifest);
+        assert_eq!(
+            resolved.get(OID).unwrap(),
+            Some(Cid::from_str(CID).unwrap())
+        );
+        assert_eq!(
+            Manifest::resolve(&resolver, &format!("/ipns/{}", NAME))
+                .await
+                .unwrap(),
+            manifest
+        );
+        assert!(Manifest::resolve(&resolver, "/ipns/unknown").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn manifest_is_json_with_a_version() {
+        let json = format!(r#"{{"version":1,"objects":{{"{}":"{}"}}}}"#, OID, CID);
+        let mut manifest = Manifest::default();
+        manifest.insert(OID, &Cid::from_str(CID).unwrap());
+        assert_eq!(serde_json::to_string(&manifest).unwrap(), json);
+
+        let resolver = resolver(br#"{"version":2,"objects":{}}"#);
+        assert!(Manifest::resolve(&resolver, NAME).await.is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+use git_lfs_spec::{
+    batch::{Actions, BatchRequest, ObjectResponse, Operation, Transfer},
+    Object,
+};
+use ipfs_api_backend_hyper::{request, response::AddResponse, IpfsApi};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{git, pins::Pins, remote::Remote};
+
+/// Objects asked for in one batch request, which servers commonly cap at 100
+const BATCH_SIZE: usize = 100;
+
+/// Where the contents of a migrated object came from
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Source {
+    /// The local LFS object store
+    Local,
+    /// The remote's LFS server, through the batch API
+    Remote,
+}
+
+/// One line of the report, as JSON
+#[derive(PartialEq, Eq, Debug, Serialize)]
+struct Migrated<'a> {
+    oid: &'a str,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<Source>,
+    /// Not known in a dry run for objects that would be fetched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Output of `git lfs ls-files --json`
+#[derive(Deserialize)]
+struct LsFiles {
+    #[serde(default)]
+    files: Vec<Object>,
+}
+
+/// Add the LFS objects of `refs` to IPFS, fetching any that are missing locally from
+/// `remote`, and report the CID of each as a line of JSON
+///
+/// Objects are pinned in the repository's [Pins]. In a dry run nothing is fetched or
+/// pinned, and CIDs are only computed.
+pub async fn migrate<E: 'static + Send + Sync + std::error::Error>(
+    client: impl IpfsApi<Error = E> + Send + Sync,
+    remote: &str,
+    refs: &[String],
+    dry_run: bool,
+    mut report: impl Write,
+) -> Result<()> {
+    let objects_dir = git::common_dir()
+        .context("not in a git repository")?
+        .join("lfs")
+        .join("objects");
+    let pins = (!dry_run).then(Pins::from_git_dir).transpose()?;
+    let objects = list_objects(refs)?;
+    let (local, missing): (Vec<_>, Vec<_>) = objects
+        .iter()
+        .partition(|object| object_path(&objects_dir, &object.oid).exists());
+    info!(
+        local = local.len(),
+        missing = missing.len(),
+        "found objects"
+    );
+
+    let mut fetched = HashMap::new();
+    if !missing.is_empty() {
+        let remote = Remote::from_git_config(remote)?;
+        for chunk in missing.chunks(BATCH_SIZE) {
+            let request = BatchRequest {
+                operation: Operation::Download,
+                transfer: vec![Transfer::Basic],
+                ref_property: None,
+                objects: chunk.iter().map(|object| (*object).clone()).collect(),
+            };
+            for response in remote.batch(&request).await?.objects {
+                let (oid, result) = match response {
+                    ObjectResponse::Success {
+                        object, actions, ..
+                    } => match *actions {
+                        Actions::Download { .. } if dry_run => (object.oid, Ok(())),
+                        Actions::Download { download } => {
+                            let path = object_path(&objects_dir, &object.oid);
+                            let result = remote
+                                .download(&download, &object, &path)
+                                .await
+                                .map_err(|err| format!("{:#}", err));
+                            (object.oid, result)
+                        }
+                        _ => (object.oid, Err("no download action".to_string())),
+                    },
+                    ObjectResponse::Error { object, error } => (object.oid, Err(error.to_string())),
+                };
+                fetched.insert(oid, result);
+            }
+        }
+    }
+
+    let mut failures = 0;
+    for object in &objects {
+        let path = object_path(&objects_dir, &object.oid);
+        let source = match fetched.remove(&object.oid) {
+            None if path.exists() => Ok(Source::Local),
+            None => Err("not in the batch response".to_string()),
+            Some(result) => result.map(|()| Source::Remote),
+        };
+        let result = match source {
+            Ok(source) if dry_run && source == Source::Remote => Ok((source, None)),
+            Ok(source) => add(&client, &path, pins.as_ref())
+                .await
+                .map(|cid| (source, Some(cid)))
+                .map_err(|err| format!("{:#}", err)),
+            Err(err) => Err(err),
+        };
+        let line = match result {
+            Ok((source, cid)) => Migrated {
+                oid: &object.oid,
+                size: object.size,
+                source: Some(source),
+                cid,
+                error: None,
+            },
+            Err(error) => {
+                warn!(oid = %object.oid, error = error.as_str(), "could not migrate");
+                failures += 1;
+                Migrated {
+                    oid: &object.oid,
+                    size: object.size,
+                    source: None,
+                    cid: None,
+                    error: Some(error),
+                }
+            }
+        };
+        serde_json::to_writer(&mut report, &line)?;
+        writeln!(report)?;
+    }
+    report.flush()?;
+
+    if failures > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} objects could not be migrated",
+            failures,
+            objects.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Add an object's contents and pin them in `pins`, or only hash them without
+async fn add<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    path: &Path,
+    pins: Option<&Pins>,
+) -> Result<String> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let options = request::Add {
+        pin: Some(false),
+        only_hash: Some(pins.is_none()),
+        ..Default::default()
+    };
+    let AddResponse { hash, .. } = client.add_with_options(file, options).await?;
+    if let Some(pins) = pins {
+        pins.pin(client, &hash).await?;
+    }
+    Ok(hash)
+}
+
+/// LFS objects in the trees of `refs`, once each
+pub fn list_objects(refs: &[String]) -> Result<Vec<Object>> {
+    let mut objects = BTreeMap::new();
+    for reference in refs {
+        for object in ls_files(reference)? {
+            objects.insert(object.oid.clone(), object);
+        }
+    }
+    Ok(objects.into_values().collect())
+}
+
+/// LFS objects anywhere in the history of any ref, which may repeat
+pub fn list_all_objects() -> Result<Vec<Object>> {
+    ls_files("--all")
+}
+
+fn ls_files(arg: &str) -> Result<Vec<Object>> {
+    let output = Command::new("git")
+        .args(["lfs", "ls-files", "--json", arg])
+        .output()
+        .context("could not run git lfs ls-files")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "git lfs ls-files {} failed: {}",
+            arg,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_ls_files(&output.stdout)
+}
+
+fn parse_ls_files(output: &[u8]) -> Result<Vec<Object>> {
+    let ls_files: LsFiles =
+        serde_json::from_slice(output).context("invalid git lfs ls-files output")?;
+    Ok(ls_files.files)
+}
+
+/// Where git-lfs keeps an object locally
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/spec.md#intercepting-git>
+pub fn object_path(objects_dir: &Path, oid: &str) -> PathBuf {
+    objects_dir
+        .join(oid.get(0..2).unwrap_or_default())
+        .join(oid.get(2..4).unwrap_or_default())
+        .join(oid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    #[test]
+    fn parse_ls_files_reads_objects() {
+        let output = format!(
+            r#"{{"files":[{{"name":"hello.txt","size":11,"checkout":true,"downloaded":true,"oid_type":"sha256","oid":"{}","version":"https://git-lfs.github.com/spec/v1"}}]}}"#,
+            OID
+        );
+        assert_eq!(
+            parse_ls_files(output.as_bytes()).unwrap(),
+            vec![Object {
+                oid: OID.to_string(),
+                size: 11,
+            }]
+        );
+        assert_eq!(parse_ls_files(b"{}").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn object_path_matches_git_lfs() {
+        assert_eq!(
+            object_path(Path::new(".git/lfs/objects"), OID),
+            Path::new(".git/lfs/objects/b9/4d").join(OID)
+        );
+    }
+
+    #[test]
+    fn report_lines_leave_out_unknowns() {
+        let line = Migrated {
+            oid: OID,
+            size: 11,
+            source: Some(Source::Remote),
+            cid: None,
+            error: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&line).unwrap(),
+            format!(r#"{{"oid":"{}","size":11,"source":"remote"}}"#, OID)
+        );
+    }
+}
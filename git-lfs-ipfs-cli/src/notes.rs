@@ -0,0 +1,351 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, Result};
+use tracing::{debug, info};
+
+use crate::index::Entry;
+
+/// Notes ref that maps oids to CIDs
+pub const NOTES_REF: &str = "refs/notes/lfs-ipfs";
+
+/// Tries at updating a ref that other processes keep updating first
+const ATTEMPTS: usize = 5;
+
+/// Scratch refs of this process, which may insert from several tasks at once
+static SCRATCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Index entries kept in git notes, so they travel with the repository
+///
+/// Notes are attached to git objects, so an oid is attached to the id of a blob holding
+/// `sha256:<oid>`, which is never written. Each note holds one entry per line, sorted,
+/// so notes merged with the `cat_sort_uniq` strategy look like notes written here.
+/// Objects added with different options can have several entries, and any of them will do.
+///
+/// <https://git-scm.com/docs/git-notes>
+pub struct Notes {
+    reference: String,
+    /// Where to run git, rather than the current directory
+    dir: Option<PathBuf>,
+}
+
+impl Default for Notes {
+    fn default() -> Self {
+        Self::new(NOTES_REF)
+    }
+}
+
+impl Notes {
+    pub fn new(reference: impl Into<String>) -> Self {
+        Self {
+            reference: reference.into(),
+            dir: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn in_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    pub fn get(&self, oid: &str) -> Result<Option<Entry>> {
+        let key = self.key(oid)?;
+        self.lines(&key)?
+            .first()
+            .map(|line| Entry::from_str(line))
+            .transpose()
+            .with_context(|| format!("invalid note for {} in {}", oid, self.reference))
+    }
+
+    /// Add an entry for `oid`, keeping any that other processes add at the same time
+    ///
+    /// The note is committed on a scratch ref, and the notes ref is only moved to it if
+    /// nobody else moved it in the meantime. Otherwise this starts over.
+    pub fn insert(&self, oid: &str, entry: &Entry) -> Result<()> {
+        let key = self.key(oid)?;
+        let line = entry.to_string();
+        let scratch = format!(
+            "{}-scratch/{}-{}",
+            self.reference,
+            std::process::id(),
+            SCRATCH.fetch_add(1, Ordering::Relaxed)
+        );
+        for _ in 0..ATTEMPTS {
+            let old = self.resolve(&self.reference)?;
+            let mut lines = self.lines(&key)?;
+            if lines.contains(&line) {
+                return Ok(());
+            }
+            lines.push(line.clone());
+            lines.sort();
+
+            match &old {
+                Some(old) => self.run(&["update-ref", &scratch, old], None)?,
+                None => self.run(&["update-ref", "-d", &scratch], None)?,
+            };
+            let note = lines.join("\n") + "\n";
+            self.run(
+                &["notes", "--ref", &scratch, "add", "-f", "-F", "-", &key],
+                Some(note.as_bytes()),
+            )?;
+            let new = self.run(&["rev-parse", &scratch], None)?;
+            // An empty old value makes sure the ref still doesn't exist
+            let updated = self
+                .command(&[
+                    "update-ref",
+                    &self.reference,
+                    &new,
+                    old.as_deref().unwrap_or_default(),
+                ])
+                .output()
+                .context("could not run git update-ref")?
+                .status
+                .success();
+            self.run(&["update-ref", "-d", &scratch], None)?;
+            if updated {
+                debug!(oid, notes = self.reference.as_str(), "noted");
+                return Ok(());
+            }
+        }
+        Err(anyhow::anyhow!(
+            "{} kept changing while adding a note for {}",
+            self.reference,
+            oid
+        ))
+    }
+
+    /// Merge the notes of `remote` into the local ones and push the result back
+    ///
+    /// Both then have every entry. If someone else pushed in the meantime, this starts over.
+    pub fn sync(&self, remote: &str) -> Result<()> {
+        let tracking = format!("{}-remotes/{}", self.reference, remote);
+        for _ in 0..ATTEMPTS {
+            let remote_notes = self.run(&["ls-remote", remote, &self.reference], None)?;
+            let remote_notes = remote_notes.split_whitespace().next();
+            if let Some(remote_notes) = remote_notes {
+                let refspec = format!("+{}:{}", self.reference, tracking);
+                self.run(&["fetch", "-q", remote, &refspec], None)?;
+                self.run(
+                    &[
+                        "notes",
+                        "--ref",
+                        &self.reference,
+                        "merge",
+                        "-q",
+                        "-s",
+                        "cat_sort_uniq",
+                        &tracking,
+                    ],
+                    None,
+                )?;
+                info!(remote, notes = remote_notes, "merged");
+            }
+
+            let local_notes = match self.resolve(&self.reference)? {
+                Some(local_notes) => local_notes,
+                None => return Ok(()),
+            };
+            if remote_notes == Some(local_notes.as_str()) {
+                return Ok(());
+            }
+            let refspec = format!("{}:{}", self.reference, self.reference);
+            let pushed = self
+                .command(&["push", "-q", remote, &refspec])
+                .output()
+                .context("could not run git push")?
+                .status
+                .success();
+            if pushed {
+                info!(remote, notes = local_notes.as_str(), "pushed");
+                return Ok(());
+            }
+        }
+        Err(anyhow::anyhow!(
+            "{} on {} kept changing while syncing",
+            self.reference,
+            remote
+        ))
+    }
+
+    /// Object that notes for `oid` are attached to
+    fn key(&self, oid: &str) -> Result<String> {
+        self.run(
+            &["hash-object", "--stdin"],
+            Some(format!("sha256:{}", oid).as_bytes()),
+        )
+    }
+
+    /// Lines of the note on `key`, if there is one
+    fn lines(&self, key: &str) -> Result<Vec<String>> {
+        let output = self
+            .command(&["notes", "--ref", &self.reference, "show", key])
+            .output()
+            .context("could not run git notes")?;
+        if !output.status.success() {
+            if String::from_utf8_lossy(&output.stderr).contains("no note found") {
+                return Ok(vec![]);
+            }
+            return Err(failure(&["notes", "show"], &output));
+        }
+        Ok(String::from_utf8(output.stdout)
+            .context("note is not UTF-8")?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn resolve(&self, reference: &str) -> Result<Option<String>> {
+        let output = self
+            .command(&["rev-parse", "-q", "--verify", reference])
+            .output()
+            .context("could not run git rev-parse")?;
+        Ok(output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string()))
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new("git");
+        if let Some(dir) = &self.dir {
+            command.current_dir(dir);
+        }
+        // Messages are matched on, so they can't be translated
+        command
+            .env("LC_ALL", "C")
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+
+    /// Run git to completion and return its trimmed output
+    fn run(&self, args: &[&str], input: Option<&[u8]>) -> Result<String> {
+        let mut command = self.command(args);
+        if input.is_some() {
+            command.stdin(Stdio::piped());
+        }
+        let mut child = command.spawn().context("could not run git")?;
+        if let Some(input) = input {
+            child
+                .stdin
+                .take()
+                .expect("stdin is piped")
+                .write_all(input)?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(failure(args, &output));
+        }
+        Ok(String::from_utf8(output.stdout)
+            .context("git output is not UTF-8")?
+            .trim()
+            .to_string())
+    }
+}
+
+fn failure(args: &[&str], output: &Output) -> anyhow::Error {
+    anyhow::anyhow!(
+        "git {} failed: {}",
+        args.first().unwrap_or(&""),
+        String::from_utf8_lossy(&output.stderr).trim()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::Cid;
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    const OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    const OTHER_OID: &str = "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447";
+    const CID: &str = "Qmf412jQZiuVUtdgnB36FXFX7xg5V6KEbSJ4dpQuhkLyfD";
+    const CID_V1: &str = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+    fn entry(cid: &str, chunker: &str) -> Entry {
+        Entry::new(Cid::from_str(cid).unwrap(), chunker)
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .current_dir(dir)
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    fn repo(dir: &Path, remote: Option<&Path>) -> Notes {
+        std::fs::create_dir(dir).unwrap();
+        git(dir, &["init", "-q"]);
+        if let Some(remote) = remote {
+            git(dir, &["remote", "add", "origin", remote.to_str().unwrap()]);
+        }
+        git(dir, &["config", "user.name", "Test"]);
+        git(dir, &["config", "user.email", "test@example.com"]);
+        Notes::default().in_dir(dir)
+    }
+
+    #[test]
+    fn notes_return_inserted_entries() {
+        let dir = tempdir().unwrap();
+        let notes = repo(&dir.path().join("repo"), None);
+        assert_eq!(notes.get(OID).unwrap(), None);
+
+        notes.insert(OID, &entry(CID, "size-262144")).unwrap();
+        notes.insert(OID, &entry(CID, "size-262144")).unwrap();
+        assert_eq!(notes.get(OID).unwrap(), Some(entry(CID, "size-262144")));
+        assert_eq!(notes.get(OTHER_OID).unwrap(), None);
+
+        notes.insert(OID, &entry(CID_V1, "rabin")).unwrap();
+        assert_eq!(notes.lines(&notes.key(OID).unwrap()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn notes_sync_merges_both_ways() {
+        let dir = tempdir().unwrap();
+        let remote = dir.path().join("remote.git");
+        git(
+            dir.path(),
+            &["init", "-q", "--bare", remote.to_str().unwrap()],
+        );
+
+        let alice = repo(&dir.path().join("alice"), Some(&remote));
+        let bob = repo(&dir.path().join("bob"), Some(&remote));
+        // Nothing to sync yet
+        alice.sync("origin").unwrap();
+
+        alice.insert(OID, &entry(CID, "size-262144")).unwrap();
+        bob.insert(OID, &entry(CID_V1, "rabin")).unwrap();
+        bob.insert(OTHER_OID, &entry(CID, "size-262144")).unwrap();
+        alice.sync("origin").unwrap();
+        bob.sync("origin").unwrap();
+        alice.sync("origin").unwrap();
+
+        for notes in [&alice, &bob] {
+            let key = notes.key(OID).unwrap();
+            assert_eq!(
+                notes.lines(&key).unwrap(),
+                vec![
+                    entry(CID, "size-262144").to_string(),
+                    entry(CID_V1, "rabin").to_string()
+                ]
+            );
+            assert_eq!(
+                notes.get(OTHER_OID).unwrap(),
+                Some(entry(CID, "size-262144"))
+            );
+        }
+    }
+}
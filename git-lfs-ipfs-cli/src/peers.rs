@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::{future::join_all, StreamExt};
+use ipfs_api_backend_hyper::{response::DhtType, IpfsApi};
+use tracing::{debug, info, warn};
+
+use crate::{git, ipfs::traced};
+
+/// Multiaddrs of peers to connect to, for all remotes
+const PEERS_KEY: &str = "lfs.ipfs.peers";
+/// Whether to look up who provides an object before downloading it
+const FIND_PROVIDERS_KEY: &str = "lfs.ipfs.findproviders";
+
+/// How long to wait for a connection, or for the DHT to name a provider
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the objects of a remote can be found in IPFS
+///
+/// The node otherwise relies on its own DHT lookups, which are slow and can miss
+/// content that was only just added. Connecting to the peers that hold it lets bitswap
+/// ask them for blocks right away.
+///
+/// `remote.<name>.lfsipfspeers` and `lfs.ipfs.peers` hold multiaddrs, one per value,
+/// and `lfs.ipfs.findproviders` looks up the providers of every object. They can be
+/// set in git config or in `.lfsconfig`, so a project can ship them to every clone.
+#[derive(PartialEq, Eq, Debug, Default, Clone)]
+pub struct Hints {
+    /// Bootstrap peers or providers, like `/ip4/10.0.0.1/tcp/4001/p2p/<peer id>`
+    pub peers: Vec<String>,
+    pub find_providers: bool,
+}
+
+impl Hints {
+    pub fn from_git_config(remote: &str) -> Result<Self> {
+        Self::from_config(remote, git::lfs_config)
+    }
+
+    fn from_config(
+        remote: &str,
+        config: impl Fn(&str, Option<&str>) -> Result<Vec<String>>,
+    ) -> Result<Self> {
+        let mut peers = config(&format!("remote.{}.lfsipfspeers", remote), None)?;
+        peers.extend(config(PEERS_KEY, None)?);
+        peers.sort();
+        peers.dedup();
+        let find_providers = config(FIND_PROVIDERS_KEY, Some("bool"))?
+            .pop()
+            .is_some_and(|value| value == "true");
+        Ok(Self {
+            peers,
+            find_providers,
+        })
+    }
+
+    /// Connect to all of the peers at once, returning how many could be reached
+    pub async fn connect<E: 'static + Send + Sync + std::error::Error>(
+        &self,
+        client: &(impl IpfsApi<Error = E> + Send + Sync),
+    ) -> usize {
+        let connected = join_all(self.peers.iter().map(|peer| connect(client, peer))).await;
+        let connected = connected.into_iter().filter(|connected| *connected).count();
+        if !self.peers.is_empty() {
+            info!(connected, peers = self.peers.len(), "connected to peers");
+        }
+        connected
+    }
+}
+
+/// Have the node connect to `peer`, which failing to only makes transfers slower
+async fn connect<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    peer: &str,
+) -> bool {
+    match tokio::time::timeout(TIMEOUT, client.swarm_connect(peer)).await {
+        Ok(Ok(_)) => {
+            debug!(peer, "connected");
+            true
+        }
+        Ok(Err(err)) => {
+            warn!(peer, %err, "could not connect");
+            false
+        }
+        Err(_) => {
+            warn!(peer, "timed out connecting");
+            false
+        }
+    }
+}
+
+/// Look up the providers of `cid` in the DHT and connect to the first one found,
+/// returning its peer id
+pub async fn find_provider<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    cid: &str,
+) -> Option<String> {
+    let lookup = traced("dht_findprovs", cid, async {
+        let mut messages = client.dht_findprovs(cid);
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(message) if matches!(message.typ, DhtType::Provider) => {
+                    if let Some(provider) = message.responses.into_iter().next() {
+                        return Some(provider.id);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(cid, %err, "could not look up providers");
+                    return None;
+                }
+            }
+        }
+        None
+    });
+    let provider = match tokio::time::timeout(TIMEOUT, lookup).await {
+        Ok(Some(provider)) => provider,
+        Ok(None) | Err(_) => {
+            debug!(cid, "no providers found");
+            return None;
+        }
+    };
+    // The lookup told the node the provider's addresses, so its id is enough
+    connect(client, &format!("/p2p/{}", provider))
+        .await
+        .then_some(provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    const PEER: &str =
+        "/ip4/10.0.0.1/tcp/4001/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+    const OTHER_PEER: &str = "/dnsaddr/bootstrap.example.com";
+
+    #[test]
+    fn hints_merge_peers_of_the_remote_and_all_remotes() {
+        let config = HashMap::from([
+            ("remote.origin.lfsipfspeers", vec![PEER]),
+            (PEERS_KEY, vec![OTHER_PEER, PEER]),
+            (FIND_PROVIDERS_KEY, vec!["false", "true"]),
+        ]);
+        let lookup = |key: &str, _: Option<&str>| {
+            Ok(config
+                .get(key)
+                .map(|values| values.iter().map(|value| value.to_string()).collect())
+                .unwrap_or_default())
+        };
+        assert_eq!(
+            Hints::from_config("origin", lookup).unwrap(),
+            Hints {
+                peers: vec![OTHER_PEER.to_string(), PEER.to_string()],
+                find_providers: true,
+            }
+        );
+        assert_eq!(
+            Hints::from_config("upstream", |_: &str, _: Option<&str>| Ok(vec![])).unwrap(),
+            Hints::default()
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use ipfs_api_backend_hyper::IpfsApi;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    git,
+    ipfs::{self, traced},
+};
+
+/// MFS directory that holds the pin directories of all repositories on the node
+const ROOT: &str = "/git-lfs-ipfs";
+
+/// Objects a repository pinned, kept as entries of a directory of its own in the
+/// node's MFS
+///
+/// The node keeps everything in MFS through garbage collection, like it keeps pinned
+/// objects. Unlike pins, which the node only has one of per CID, each entry belongs to
+/// the repository that made it, so unpinning an object leaves it to the other
+/// repositories on the node that still use it. When each object was pinned is noted in
+/// `.git/lfs/ipfs/pins`, as the modification time of a file named after its CID.
+pub struct Pins {
+    dir: String,
+    times: PathBuf,
+}
+
+impl Pins {
+    pub fn new(dir: impl Into<String>, times: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            times: times.into(),
+        }
+    }
+
+    /// Pins of the current repository
+    pub fn from_git_dir() -> Result<Self> {
+        let git_dir = git::common_dir().context("not in a git repository")?;
+        Ok(Self::for_git_dir(&git_dir))
+    }
+
+    /// Pins of the repository whose `.git` directory, or bare repository, is `git_dir`
+    ///
+    /// Its directory in MFS is named after a hash of where the repository is, which
+    /// only changes if it is moved.
+    pub fn for_git_dir(git_dir: &Path) -> Self {
+        let git_dir = fs::canonicalize(git_dir).unwrap_or_else(|_| git_dir.to_path_buf());
+        let digest = Sha256::digest(git_dir.to_string_lossy().as_bytes());
+        Self::new(
+            format!("{}/{}", ROOT, hex::encode(&digest[..8])),
+            git_dir.join("lfs").join("ipfs").join("pins"),
+        )
+    }
+
+    /// Pin `cid` for this repository and note when
+    ///
+    /// Pinning an object again only updates the time.
+    pub async fn pin<E: 'static + Send + Sync + std::error::Error>(
+        &self,
+        client: &(impl IpfsApi<Error = E> + Send + Sync),
+        cid: &str,
+    ) -> Result<()> {
+        if !self.contains(client, cid).await? {
+            traced("files_mkdir", cid, client.files_mkdir(&self.dir, true)).await?;
+            let source = format!("/ipfs/{}", cid);
+            traced("files_cp", cid, client.files_cp(&source, &self.path(cid))).await?;
+        }
+        fs::create_dir_all(&self.times)
+            .with_context(|| format!("could not create {}", self.times.display()))?;
+        fs::File::create(self.times.join(cid))
+            .and_then(|file| file.set_modified(SystemTime::now()))
+            .with_context(|| format!("could not note when {} was pinned", cid))
+    }
+
+    /// Remove this repository's pin of `cid`
+    pub async fn unpin<E: 'static + Send + Sync + std::error::Error>(
+        &self,
+        client: &(impl IpfsApi<Error = E> + Send + Sync),
+        cid: &str,
+    ) -> Result<()> {
+        traced("files_rm", cid, client.files_rm(&self.path(cid), true)).await?;
+        match fs::remove_file(self.times.join(cid)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether this repository pinned `cid`
+    ///
+    /// Like in [ipfs::is_pinned], a failure only means the object isn't there if the
+    /// node answers when asked for its version.
+    pub async fn contains<E: 'static + Send + Sync + std::error::Error>(
+        &self,
+        client: &(impl IpfsApi<Error = E> + Send + Sync),
+        cid: &str,
+    ) -> Result<bool> {
+        match traced("files_stat", cid, client.files_stat(&self.path(cid))).await {
+            Ok(_) => Ok(true),
+            Err(err) => match client.version().await {
+                Ok(_) => Ok(false),
+                Err(_) => Err(err.into()),
+            },
+        }
+    }
+
+    /// CIDs this repository pinned
+    pub async fn list<E: 'static + Send + Sync + std::error::Error>(
+        &self,
+        client: &(impl IpfsApi<Error = E> + Send + Sync),
+    ) -> Result<Vec<String>> {
+        match client.files_ls(Some(&self.dir)).await {
+            Ok(listing) => Ok(listing
+                .entries
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect()),
+            // Nothing was pinned yet
+            Err(err) => match client.version().await {
+                Ok(_) => Ok(vec![]),
+                Err(_) => Err(err.into()),
+            },
+        }
+    }
+
+    /// When this repository last pinned `cid`, if that was noted
+    pub fn pinned_at(&self, cid: &str) -> Option<SystemTime> {
+        fs::metadata(self.times.join(cid))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    fn path(&self, cid: &str) -> String {
+        format!("{}/{}", self.dir, cid)
+    }
+}
+
+/// Whether any repository pinned `cid`, or the node holds a recursive pin on it, like
+/// the ones made before pins were kept per repository
+pub async fn is_pinned<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    cid: &str,
+) -> Result<bool> {
+    if ipfs::is_pinned(client, cid).await? {
+        return Ok(true);
+    }
+    let repositories = match client.files_ls(Some(ROOT)).await {
+        Ok(listing) => listing.entries,
+        // No repository pinned anything yet
+        Err(_) => return Ok(false),
+    };
+    for repository in repositories {
+        let pins = Pins::new(format!("{}/{}", ROOT, repository.name), PathBuf::new());
+        if pins.contains(client, cid).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipfs::client;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    const CID: &str = "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4";
+
+    #[test]
+    fn repositories_get_directories_of_their_own() {
+        let dir = tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.git"), dir.path().join("b.git"));
+        let pins = Pins::for_git_dir(&a);
+        assert!(pins.dir.starts_with("/git-lfs-ipfs/"));
+        assert_eq!(pins.dir, Pins::for_git_dir(&a).dir);
+        assert_ne!(pins.dir, Pins::for_git_dir(&b).dir);
+        assert_eq!(pins.path(CID), format!("{}/{}", pins.dir, CID));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn pins_are_kept_per_repository() {
+        let dir = tempdir().unwrap();
+        let client = client();
+        let options = ipfs_api_backend_hyper::request::Add {
+            pin: Some(false),
+            ..Default::default()
+        };
+        let cid = client
+            .add_with_options(
+                std::io::Cursor::new("pins are kept per repository"),
+                options,
+            )
+            .await
+            .unwrap()
+            .hash;
+        let a = Pins::for_git_dir(&dir.path().join("a.git"));
+        let b = Pins::for_git_dir(&dir.path().join("b.git"));
+        a.pin(&client, &cid).await.unwrap();
+        b.pin(&client, &cid).await.unwrap();
+        assert!(a.pinned_at(&cid).is_some());
+
+        a.unpin(&client, &cid).await.unwrap();
+        assert!(!a.contains(&client, &cid).await.unwrap());
+        assert!(a.pinned_at(&cid).is_none());
+        assert_eq!(b.list(&client).await.unwrap(), vec![cid.clone()]);
+        assert!(is_pinned(&client, &cid).await.unwrap());
+        b.unpin(&client, &cid).await.unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{compression, git};
+
+/// Per-path storage settings, kept at the top of the working tree
+pub const POLICY_FILE: &str = ".lfsipfsconfig";
+
+/// How a file is stored in IPFS
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Policy {
+    /// Whether the file goes through IPFS at all; if not, clean and smudge pass it through
+    pub ipfs: bool,
+    /// Chunking strategy given to `ipfs add`, like `size-1048576` or `rabin`
+    pub chunker: Option<String>,
+    pub cid_version: Option<u32>,
+    /// Whether the local node pins what clean adds
+    pub pin: bool,
+    /// zstd level to compress with before adding, if any
+    pub zstd_level: Option<i32>,
+    pub zstd_dictionary: Option<PathBuf>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            ipfs: true,
+            chunker: None,
+            cid_version: None,
+            pin: true,
+            zstd_level: None,
+            zstd_dictionary: None,
+        }
+    }
+}
+
+/// Policies for paths, in the format of gitattributes
+///
+/// Each line is a pattern followed by attributes:
+///
+/// ```text
+/// *.mp4 chunker=size-1048576 cid-version=1
+/// assets/tmp/** -pin
+/// *.txt -ipfs
+/// *.csv zstd=19 zstd-dictionary=dictionaries/csv
+/// ```
+///
+/// Dictionary paths are relative to the directory of the policy file, which is the top
+/// of the working tree.
+///
+/// As in gitattributes, a later line overrides attributes set by an earlier one.
+///
+/// <https://git-scm.com/docs/gitattributes#_description>
+#[derive(PartialEq, Eq, Debug, Default)]
+pub struct Policies {
+    root: PathBuf,
+    rules: Vec<Rule>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+struct Rule {
+    pattern: String,
+    attributes: Vec<Attribute>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Attribute {
+    Ipfs(bool),
+    Chunker(String),
+    CidVersion(u32),
+    Pin(bool),
+    Zstd(Option<i32>),
+    ZstdDictionary(String),
+}
+
+impl Policies {
+    /// Read [POLICY_FILE] from the current working tree, if there is one
+    pub fn from_working_tree() -> Result<Self> {
+        match git::toplevel() {
+            Some(toplevel) => Self::load(&toplevel.join(POLICY_FILE)),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let mut policies = Self::parse(&contents)
+                    .with_context(|| format!("invalid {}", path.display()))?;
+                policies.root = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                Ok(policies)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("could not read {}", path.display())),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut rules = vec![];
+        for (number, line) in contents.lines().enumerate() {
+            let mut words = line.split_whitespace();
+            let pattern = match words.next() {
+                Some(pattern) if !pattern.starts_with('#') => pattern,
+                _ => continue,
+            };
+            let attributes = words
+                .map(parse_attribute)
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("line {}", number + 1))?;
+            rules.push(Rule {
+                pattern: pattern.trim_start_matches('/').to_string(),
+                attributes,
+            });
+        }
+        Ok(Self {
+            root: PathBuf::new(),
+            rules,
+        })
+    }
+
+    /// Policy for a path relative to the top of the working tree
+    pub fn for_path(&self, path: &Path) -> Policy {
+        let path = path.to_string_lossy().replace('\\', "/");
+        let path = path.trim_start_matches("./");
+        let basename = path.rsplit('/').next().unwrap_or(path);
+        let mut policy = Policy::default();
+        for rule in &self.rules {
+            // Patterns without a slash match the file name at any depth
+            let subject = if rule.pattern.contains('/') {
+                path
+            } else {
+                basename
+            };
+            if !glob(rule.pattern.as_bytes(), subject.as_bytes()) {
+                continue;
+            }
+            for attribute in &rule.attributes {
+                match attribute {
+                    Attribute::Ipfs(ipfs) => policy.ipfs = *ipfs,
+                    Attribute::Chunker(chunker) => policy.chunker = Some(chunker.clone()),
+                    Attribute::CidVersion(version) => policy.cid_version = Some(*version),
+                    Attribute::Pin(pin) => policy.pin = *pin,
+                    Attribute::Zstd(level) => policy.zstd_level = *level,
+                    Attribute::ZstdDictionary(dictionary) => {
+                        policy.zstd_dictionary = Some(self.root.join(dictionary))
+                    }
+                }
+            }
+        }
+        policy
+    }
+
+    /// Every dictionary that some path is compressed with, which smudge looks objects'
+    /// dictionaries up in whatever their paths' policies are now
+    pub fn dictionaries(&self) -> Vec<PathBuf> {
+        let mut dictionaries = vec![];
+        for attribute in self.rules.iter().flat_map(|rule| &rule.attributes) {
+            if let Attribute::ZstdDictionary(dictionary) = attribute {
+                let path = self.root.join(dictionary);
+                if !dictionaries.contains(&path) {
+                    dictionaries.push(path);
+                }
+            }
+        }
+        dictionaries
+    }
+}
+
+fn parse_attribute(word: &str) -> Result<Attribute> {
+    let (name, value) = match word.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (word, None),
+    };
+    match (name, value) {
+        ("ipfs", None) => Ok(Attribute::Ipfs(true)),
+        ("-ipfs", None) => Ok(Attribute::Ipfs(false)),
+        ("pin", None) => Ok(Attribute::Pin(true)),
+        ("-pin", None) => Ok(Attribute::Pin(false)),
+        ("chunker", Some(chunker)) => Ok(Attribute::Chunker(chunker.to_string())),
+        ("cid-version", Some(version @ ("0" | "1"))) => Ok(Attribute::CidVersion(version.parse()?)),
+        ("zstd", None) => Ok(Attribute::Zstd(Some(compression::DEFAULT_LEVEL))),
+        ("-zstd", None) => Ok(Attribute::Zstd(None)),
+        ("zstd", Some(level)) => match level.parse() {
+            Ok(level) if zstd::compression_level_range().contains(&level) => {
+                Ok(Attribute::Zstd(Some(level)))
+            }
+            _ => Err(anyhow::anyhow!("invalid zstd level {}", level)),
+        },
+        ("zstd-dictionary", Some(dictionary)) => {
+            Ok(Attribute::ZstdDictionary(dictionary.to_string()))
+        }
+        _ => Err(anyhow::anyhow!("unknown attribute {}", word)),
+    }
+}
+
+/// Match a gitignore-style glob, where `*` and `?` stop at slashes and `**` does not
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+            if rest.is_empty() {
+                return true;
+            }
+            (0..=text.len())
+                .filter(|&i| i == 0 || text[i - 1] == b'/')
+                .any(|i| glob(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob(rest, &text[i..])),
+        [b'?', rest @ ..] => matches!(text, [c, text @ ..] if *c != b'/' && glob(rest, text)),
+        [c, rest @ ..] => matches!(text, [t, text @ ..] if t == c && glob(rest, text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn glob_matches_like_gitattributes() {
+        assert!(glob(b"*.mp4", b"intro.mp4"));
+        assert!(!glob(b"*.mp4", b"videos/intro.mp4"));
+        assert!(glob(b"videos/*.mp4", b"videos/intro.mp4"));
+        assert!(!glob(b"videos/*.mp4", b"videos/old/intro.mp4"));
+        assert!(glob(b"videos/**/*.mp4", b"videos/intro.mp4"));
+        assert!(glob(b"videos/**/*.mp4", b"videos/old/intro.mp4"));
+        assert!(glob(b"**/tmp/*", b"a/b/tmp/c"));
+        assert!(glob(b"assets/**", b"assets/a/b"));
+        assert!(glob(b"?.psd", b"a.psd"));
+        assert!(!glob(b"?.psd", b"ab.psd"));
+    }
+
+    #[test]
+    fn later_rules_override_earlier_ones() {
+        let policies = Policies::parse(
+            "# videos are big\n\
+             *.mp4 chunker=size-1048576 cid-version=1\n\
+             \n\
+             /videos/drafts/** -pin\n\
+             *.txt -ipfs\n\
+             keep.txt ipfs\n",
+        )
+        .unwrap();
+        assert_eq!(
+            policies.for_path(Path::new("videos/drafts/intro.mp4")),
+            Policy {
+                ipfs: true,
+                chunker: Some("size-1048576".to_string()),
+                cid_version: Some(1),
+                pin: false,
+                ..Default::default()
+            }
+        );
+        assert!(!policies.for_path(Path::new("docs/notes.txt")).ipfs);
+        assert!(policies.for_path(Path::new("docs/keep.txt")).ipfs);
+        assert_eq!(policies.for_path(Path::new("model.bin")), Policy::default());
+    }
+
+    #[test]
+    fn compression_settings_resolve_dictionary_from_policy_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(POLICY_FILE);
+        std::fs::write(
+            &path,
+            "*.csv zstd-dictionary=dictionaries/csv\n\
+             data/** zstd\n\
+             data/raw/*.csv zstd=19\n\
+             data/tmp/** -zstd\n",
+        )
+        .unwrap();
+        let policies = Policies::load(&path).unwrap();
+
+        let policy = policies.for_path(Path::new("data/raw/dump.csv"));
+        assert_eq!(policy.zstd_level, Some(19));
+        assert_eq!(
+            policy.zstd_dictionary,
+            Some(dir.path().join("dictionaries/csv"))
+        );
+        assert_eq!(
+            policies.for_path(Path::new("data/a.wav")).zstd_level,
+            Some(compression::DEFAULT_LEVEL)
+        );
+        assert_eq!(
+            policies.for_path(Path::new("data/tmp/a.wav")).zstd_level,
+            None
+        );
+        assert_eq!(policies.for_path(Path::new("a.wav")).zstd_level, None);
+        assert_eq!(
+            policies.dictionaries(),
+            vec![dir.path().join("dictionaries/csv")]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unknown_attributes() {
+        assert!(Policies::parse("*.mp4 cid-version=2").is_err());
+        assert!(Policies::parse("*.mp4 compress").is_err());
+        assert!(Policies::parse("*.wav zstd=fast").is_err());
+        assert!(Policies::parse("*.wav zstd=100").is_err());
+    }
+}
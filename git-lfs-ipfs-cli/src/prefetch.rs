@@ -0,0 +1,197 @@
+use std::io::{IsTerminal, Write};
+
+use anyhow::Result;
+use futures::{stream, StreamExt, TryStreamExt};
+use git_lfs_spec::Object;
+use ipfs_api_backend_hyper::IpfsApi;
+use tracing::{info, warn};
+
+use crate::{
+    index::{self, Index},
+    ipfs::traced,
+    migrate::list_objects,
+    pins::Pins,
+    rate_limit::Limits,
+    timeouts::Timeouts,
+    transfer::probe,
+};
+
+/// How fetching an object went
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Outcome {
+    Fetched,
+    /// Only on the remote's LFS server, or no CID is known for it
+    NotInIpfs,
+    Failed,
+}
+
+/// Objects done so far, for the progress line
+#[derive(PartialEq, Eq, Debug, Default)]
+struct Progress {
+    total: usize,
+    fetched: usize,
+    not_in_ipfs: usize,
+    failed: usize,
+}
+
+impl Progress {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Fetched => self.fetched += 1,
+            Outcome::NotInIpfs => self.not_in_ipfs += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+
+    fn line(&self) -> String {
+        let mut line = format!(
+            "Prefetched {}/{} objects",
+            self.fetched + self.not_in_ipfs + self.failed,
+            self.total
+        );
+        if self.not_in_ipfs > 0 {
+            line.push_str(&format!(", {} not in IPFS", self.not_in_ipfs));
+        }
+        if self.failed > 0 {
+            line.push_str(&format!(", {} failed", self.failed));
+        }
+        line
+    }
+}
+
+/// Fetch the DAGs of the LFS objects of `refs` into the blockstore of the local node,
+/// `jobs` at a time, so later checkouts don't wait on the network
+///
+/// Nothing is written to the working tree or the LFS object store. Objects are read
+/// in full to fetch every block, at the download rate of the [Limits], and then pinned
+/// in the repository's [Pins] if `pin` is set.
+pub async fn prefetch<E: 'static + Send + Sync + std::error::Error>(
+    client: impl IpfsApi<Error = E> + Send + Sync,
+    refs: &[String],
+    jobs: usize,
+    pin: bool,
+    limits: &Limits,
+    mut progress_output: impl Write,
+) -> Result<()> {
+    let index = Index::for_oid_mode()?;
+    let timeouts = Timeouts::from_git_config()?;
+    let pins = pin.then(Pins::from_git_dir).transpose()?;
+    let objects = list_objects(refs)?;
+    let mut cids = Vec::with_capacity(objects.len());
+    for object in &objects {
+        cids.push(index::cid(index.as_ref(), &object.oid)?.map(|cid| cid.to_string()));
+    }
+
+    let interactive = std::io::stderr().is_terminal();
+    let mut progress = Progress {
+        total: objects.len(),
+        ..Default::default()
+    };
+    let mut outcomes = stream::iter(objects.iter().zip(&cids))
+        .map(|(object, cid)| {
+            fetch(
+                &client,
+                &timeouts,
+                object,
+                cid.as_deref(),
+                pins.as_ref(),
+                limits,
+            )
+        })
+        .buffer_unordered(jobs.max(1));
+    while let Some(outcome) = outcomes.next().await {
+        progress.add(outcome);
+        if interactive {
+            write!(progress_output, "\r{}", progress.line())?;
+            progress_output.flush()?;
+        }
+    }
+    if interactive {
+        writeln!(progress_output)?;
+    } else {
+        writeln!(progress_output, "{}", progress.line())?;
+    }
+
+    if progress.failed > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} objects could not be prefetched",
+            progress.failed,
+            progress.total
+        ));
+    }
+    Ok(())
+}
+
+async fn fetch<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    timeouts: &Timeouts,
+    object: &Object,
+    cid: Option<&str>,
+    pins: Option<&Pins>,
+    limits: &Limits,
+) -> Outcome {
+    let cid = match cid {
+        Some(cid) => cid,
+        None => {
+            warn!(oid = %object.oid, "no CID in the index");
+            return Outcome::NotInIpfs;
+        }
+    };
+    match probe(client, timeouts, cid).await {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!(oid = %object.oid, cid, "not in IPFS");
+            return Outcome::NotInIpfs;
+        }
+        Err(err) => {
+            warn!(oid = %object.oid, cid, %err, "could not look for it in IPFS");
+            return Outcome::Failed;
+        }
+    }
+    let result = traced(
+        "cat",
+        cid,
+        limits
+            .download(client.cat(&format!("/ipfs/{}", cid)))
+            .try_for_each(|_| async { Ok(()) }),
+    )
+    .await
+    .map_err(anyhow::Error::from);
+    let result = match (result, pins) {
+        (Ok(()), Some(pins)) => pins.pin(client, cid).await,
+        (result, _) => result,
+    };
+    match result {
+        Ok(()) => {
+            info!(oid = %object.oid, cid, pinned = pins.is_some(), "prefetched");
+            Outcome::Fetched
+        }
+        Err(err) => {
+            warn!(oid = %object.oid, cid, %err, "could not prefetch");
+            Outcome::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn progress_counts_outcomes() {
+        let mut progress = Progress {
+            total: 4,
+            ..Default::default()
+        };
+        progress.add(Outcome::Fetched);
+        assert_eq!(progress.line(), "Prefetched 1/4 objects");
+        progress.add(Outcome::NotInIpfs);
+        progress.add(Outcome::Failed);
+        progress.add(Outcome::Fetched);
+        assert_eq!(
+            progress.line(),
+            "Prefetched 4/4 objects, 1 not in IPFS, 1 failed"
+        );
+    }
+}
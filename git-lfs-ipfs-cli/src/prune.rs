@@ -0,0 +1,231 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Write,
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use git_lfs_spec::Object;
+use ipfs_api_backend_hyper::IpfsApi;
+use tracing::{info, warn};
+
+use crate::{
+    index::{self, Index},
+    migrate::{list_all_objects, list_objects},
+    pins::Pins,
+};
+
+/// Refs kept when none are given: local and remote branches, and tags
+pub const DEFAULT_REFS: &[&str] = &["refs/heads", "refs/remotes", "refs/tags"];
+
+/// Unpin the LFS objects of the repository's history that are no longer referenced
+///
+/// Objects are kept if they are in any of the `depth` latest commits of a ref matching
+/// `refs`, or if they were pinned in the last `window_days` days. Only the repository's
+/// own [Pins] are removed, so objects that other repositories on the node pinned stay
+/// pinned for them. Of those, only objects that are in the history at all are
+/// unpinned, so files that were cleaned but not committed yet are left alone. Objects
+/// that nothing pins anymore are removed by the node's next garbage collection.
+pub async fn prune<E: 'static + Send + Sync + std::error::Error>(
+    client: impl IpfsApi<Error = E> + Send + Sync,
+    refs: &[String],
+    depth: usize,
+    window_days: u64,
+    dry_run: bool,
+    mut report: impl Write,
+) -> Result<()> {
+    let commits = retained_commits(refs, depth)?;
+    let retained = list_objects(&commits)?
+        .into_iter()
+        .map(|object| object.oid)
+        .collect::<HashSet<_>>();
+    let index = Index::for_oid_mode()?;
+    let mut history = vec![];
+    for object in list_all_objects()? {
+        let cid = index::cid(index.as_ref(), &object.oid)?.map(|cid| cid.to_string());
+        history.push((object, cid));
+    }
+    let pins = Pins::from_git_dir()?;
+    let window = Duration::from_secs(window_days * 24 * 60 * 60);
+    let now = SystemTime::now();
+    let (recent, pinned): (HashSet<_>, HashSet<_>) = pins
+        .list(&client)
+        .await?
+        .into_iter()
+        .partition(|cid| is_recent(pins.pinned_at(cid), window, now));
+    info!(
+        commits = commits.len(),
+        retained = retained.len(),
+        pinned = pinned.len() + recent.len(),
+        recent = recent.len(),
+        "computed retained objects"
+    );
+
+    let unpinnable = select(&history, &retained, &pinned);
+    let mut failures = 0;
+    for (object, cid) in &unpinnable {
+        if dry_run {
+            writeln!(report, "would unpin {} {}", cid, object.oid)?;
+            continue;
+        }
+        match pins.unpin(&client, cid).await {
+            Ok(_) => writeln!(report, "unpinned {} {}", cid, object.oid)?,
+            Err(err) => {
+                warn!(oid = %object.oid, cid, %err, "could not unpin");
+                failures += 1;
+            }
+        }
+    }
+    writeln!(
+        report,
+        "{} {} objects, keeping {} referenced and {} recently pinned ones",
+        if dry_run { "Would unpin" } else { "Unpinned" },
+        unpinnable.len() - failures,
+        retained.len(),
+        recent.len()
+    )?;
+    report.flush()?;
+
+    if failures > 0 {
+        return Err(anyhow::anyhow!(
+            "{} objects could not be unpinned",
+            failures
+        ));
+    }
+    Ok(())
+}
+
+/// Pinned objects of the history that aren't retained, once each
+fn select<'a>(
+    history: &'a [(Object, Option<String>)],
+    retained: &HashSet<String>,
+    pinned: &HashSet<String>,
+) -> Vec<(&'a Object, &'a str)> {
+    let mut seen = HashSet::new();
+    history
+        .iter()
+        .filter(|(object, _)| !retained.contains(&object.oid))
+        .filter_map(|(object, cid)| Some((object, cid.as_deref()?)))
+        .filter(|(_, cid)| pinned.contains(*cid) && seen.insert(*cid))
+        .collect()
+}
+
+/// Whether an object pinned at `pinned_at` is still within the safety window
+///
+/// Objects whose pin time wasn't noted are old enough, and so are those of a window of
+/// 0 days, but pins from the future are not.
+fn is_recent(pinned_at: Option<SystemTime>, window: Duration, now: SystemTime) -> bool {
+    match pinned_at {
+        Some(pinned_at) => match now.duration_since(pinned_at) {
+            Ok(age) => age < window,
+            Err(_) => true,
+        },
+        None => false,
+    }
+}
+
+/// Commits whose objects are kept: the `depth` latest of each ref under `refs`
+///
+/// Commits with the same tree have the same objects, so only one of them is listed.
+fn retained_commits(refs: &[String], depth: usize) -> Result<Vec<String>> {
+    let mut lines = vec![];
+    let mut for_each_ref = vec!["for-each-ref", "--format=%(objectname)"];
+    for_each_ref.extend(refs.iter().map(String::as_str));
+    for tip in git_lines(&for_each_ref)? {
+        let max_count = format!("--max-count={}", depth.max(1));
+        lines.extend(git_lines(&["log", "--format=%T %H", &max_count, &tip])?);
+    }
+    Ok(one_commit_per_tree(&lines))
+}
+
+/// The first commit of each tree in lines of `git log --format="%T %H"`
+fn one_commit_per_tree(lines: &[String]) -> Vec<String> {
+    let mut trees = BTreeMap::new();
+    for (tree, commit) in lines.iter().filter_map(|line| line.split_once(' ')) {
+        trees.entry(tree).or_insert(commit);
+    }
+    trees.into_values().map(str::to_string).collect()
+}
+
+fn git_lines(args: &[&str]) -> Result<Vec<String>> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("could not run git")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)
+        .context("git output is not UTF-8")?
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const OLD_OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    const OLD_CID: &str = "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4";
+    const OID: &str = "a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447";
+    const CID: &str = "QmZ4tDuvesekSs4qM5ZBKpXiZGun7S2CYtEZRB3DYXkjGx";
+    const UNPINNED_OID: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const UNPINNED_CID: &str = "QmRN6wdp1S2A5EtjW9A3M1vKSBuQQGcgvuhoMUoEz4iiT5";
+
+    fn object(oid: &str) -> Object {
+        Object {
+            oid: oid.to_string(),
+            size: 11,
+        }
+    }
+
+    #[test]
+    fn select_keeps_retained_and_unpinned_objects() {
+        let history = vec![
+            (object(OLD_OID), Some(OLD_CID.to_string())),
+            (object(OID), Some(CID.to_string())),
+            (object(OLD_OID), Some(OLD_CID.to_string())),
+            (object(UNPINNED_OID), Some(UNPINNED_CID.to_string())),
+            // Not in the index
+            (object(UNPINNED_OID), None),
+        ];
+        let retained = HashSet::from([OID.to_string()]);
+        let pinned = HashSet::from([OLD_CID.to_string(), CID.to_string()]);
+        let selected = select(&history, &retained, &pinned)
+            .into_iter()
+            .map(|(object, cid)| (object.oid.as_str(), cid))
+            .collect::<Vec<_>>();
+        assert_eq!(selected, vec![(OLD_OID, OLD_CID)]);
+    }
+
+    #[test]
+    fn only_objects_pinned_within_the_window_are_recent() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let window = day * 14;
+        let now = SystemTime::now();
+        assert!(is_recent(Some(now - day), window, now));
+        assert!(!is_recent(Some(now - day * 15), window, now));
+        assert!(is_recent(Some(now + day), window, now));
+        assert!(!is_recent(None, window, now));
+        assert!(!is_recent(Some(now - day), Duration::ZERO, now));
+    }
+
+    #[test]
+    fn retained_commits_are_listed_once_per_tree() {
+        let lines = [
+            "tree1 commit3",
+            "tree2 commit2",
+            "tree1 commit1",
+            "tree2 commit2",
+        ]
+        .map(String::from);
+        assert_eq!(one_commit_per_tree(&lines), vec!["commit3", "commit2"]);
+    }
+}
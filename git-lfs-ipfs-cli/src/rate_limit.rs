@@ -0,0 +1,255 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use futures::{stream::BoxStream, AsyncRead, Stream, StreamExt};
+use tokio::time::Sleep;
+
+use crate::git;
+
+/// Bytes per second added to IPFS, for all remotes
+const UPLOAD_RATE_KEY: &str = "lfs.ipfs.uploadrate";
+/// Bytes per second read from IPFS, for all remotes
+const DOWNLOAD_RATE_KEY: &str = "lfs.ipfs.downloadrate";
+
+/// Token bucket shared by everything that clones it, like the concurrent transfers of
+/// one agent
+///
+/// Up to a second's worth of bytes can go at once after a pause.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bytes_per_second: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Goes below zero when bytes were let through before there were enough for them
+    available: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second as f64;
+        Self {
+            bytes_per_second,
+            bucket: Arc::new(Mutex::new(Bucket {
+                available: bytes_per_second,
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    /// Take `bytes` from the bucket and return how long to wait before sending them
+    fn reserve(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.bytes_per_second;
+        bucket.available = (bucket.available + refilled).min(self.bytes_per_second);
+        bucket.updated = now;
+        bucket.available -= bytes as f64;
+        if bucket.available >= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.available / self.bytes_per_second)
+        }
+    }
+
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Upload and download rates of the remote being transferred for, if any are set
+///
+/// `remote.<name>.lfsipfsuploadrate` and `remote.<name>.lfsipfsdownloadrate` take
+/// precedence over `lfs.ipfs.uploadrate` and `lfs.ipfs.downloadrate`. Rates are in
+/// bytes per second with an optional `k`, `m` or `g` suffix, and 0 means no limit.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    upload: Option<RateLimiter>,
+    download: Option<RateLimiter>,
+}
+
+impl Limits {
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        let limiter = |rate: Option<u64>| rate.filter(|rate| *rate > 0).map(RateLimiter::new);
+        Self {
+            upload: limiter(upload),
+            download: limiter(download),
+        }
+    }
+
+    pub fn from_git_config(remote: Option<&str>) -> Result<Self> {
+        let rate = |key: &str, remote_key: &str| -> Result<Option<u64>> {
+            let remote_key = remote.map(|remote| format!("remote.{}.{}", remote, remote_key));
+            for key in remote_key.iter().map(String::as_str).chain([key]) {
+                if let Some(rate) = git::config(key, Some("int"))? {
+                    return rate
+                        .parse()
+                        .map(Some)
+                        .with_context(|| format!("invalid {} {}", key, rate));
+                }
+            }
+            Ok(None)
+        };
+        Ok(Self::new(
+            rate(UPLOAD_RATE_KEY, "lfsipfsuploadrate")?,
+            rate(DOWNLOAD_RATE_KEY, "lfsipfsdownloadrate")?,
+        ))
+    }
+
+    /// Wait until `bytes` may be uploaded
+    pub async fn upload(&self, bytes: usize) {
+        if let Some(limiter) = &self.upload {
+            limiter.acquire(bytes).await;
+        }
+    }
+
+    /// Throttle a reader that is uploaded as it is read, like the file in clean
+    pub fn upload_reader<R: AsyncRead + Unpin>(&self, input: R) -> LimitedReader<R> {
+        LimitedReader {
+            inner: input,
+            limiter: self.upload.clone(),
+            wait: None,
+        }
+    }
+
+    /// Throttle a stream of chunks downloaded from IPFS
+    pub fn download<'a, T, E>(
+        &self,
+        stream: impl Stream<Item = Result<T, E>> + Send + 'a,
+    ) -> BoxStream<'a, Result<T, E>>
+    where
+        T: AsRef<[u8]> + Send + 'a,
+        E: Send + 'a,
+    {
+        let limiter = match &self.download {
+            Some(limiter) => limiter.clone(),
+            None => return stream.boxed(),
+        };
+        stream
+            .then(move |chunk| {
+                let limiter = limiter.clone();
+                async move {
+                    if let Ok(chunk) = &chunk {
+                        limiter.acquire(chunk.as_ref().len()).await;
+                    }
+                    chunk
+                }
+            })
+            .boxed()
+    }
+}
+
+/// Reader that waits out the bytes it has let through beyond the rate before reading
+/// more, without blocking the thread
+pub struct LimitedReader<R> {
+    inner: R,
+    limiter: Option<RateLimiter>,
+    wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if let Some(wait) = &mut self.wait {
+            ready!(wait.as_mut().poll(cx));
+            self.wait = None;
+        }
+        let bytes = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some(limiter) = &self.limiter {
+            let wait = limiter.reserve(bytes);
+            if !wait.is_zero() {
+                self.wait = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        Poll::Ready(Ok(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, AsyncReadExt};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn limiter_lets_a_second_through_then_waits() {
+        let limiter = RateLimiter::new(1000);
+        assert_eq!(limiter.reserve(1000), Duration::ZERO);
+        let wait = limiter.reserve(500);
+        assert!(
+            wait > Duration::from_millis(400) && wait <= Duration::from_millis(500),
+            "{:?}",
+            wait
+        );
+        // Clones share the bucket, like concurrent transfers
+        let wait = limiter.clone().reserve(500);
+        assert!(wait > Duration::from_millis(900), "{:?}", wait);
+    }
+
+    #[test]
+    fn limits_of_zero_are_unlimited() {
+        let limits = Limits::new(Some(0), None);
+        assert!(limits.upload.is_none());
+        assert!(limits.download.is_none());
+        assert!(Limits::new(Some(1), Some(2)).download.is_some());
+    }
+
+    #[tokio::test]
+    async fn download_is_throttled() {
+        let limits = Limits::new(None, Some(100_000));
+        let chunks = vec![Ok::<_, ()>(vec![0u8; 100_000]), Ok(vec![0u8; 20_000])];
+        let started = Instant::now();
+        let received = limits
+            .download(stream::iter(chunks))
+            .map(|chunk| chunk.unwrap().len())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(received, vec![100_000, 20_000]);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn upload_reader_is_throttled() {
+        let limits = Limits::new(Some(100_000), None);
+        let mut reader = limits.upload_reader(futures::io::Cursor::new(vec![0u8; 120_000]));
+        let started = Instant::now();
+        let mut contents = vec![];
+        reader.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(contents.len(), 120_000);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn upload_reader_leaves_the_runtime_free_while_it_waits() {
+        let limits = Limits::new(Some(100_000), None);
+        let mut reader = limits.upload_reader(futures::io::Cursor::new(vec![0u8; 120_000]));
+        let ticks = Arc::new(Mutex::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    *ticks.lock().unwrap() += 1;
+                }
+            }
+        });
+        reader.read_to_end(&mut vec![]).await.unwrap();
+        ticker.abort();
+        assert!(*ticks.lock().unwrap() >= 5);
+    }
+}
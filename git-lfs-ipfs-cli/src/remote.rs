@@ -0,0 +1,351 @@
+use std::{io::Write, path::Path, sync::Mutex};
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use git_lfs_spec::{
+    batch::{Action, BatchRequest, BatchResponse, LfsErrorResponse},
+    Object, GIT_LFS_CONTENT_TYPE,
+};
+use hyper::{
+    body::Bytes,
+    body::HttpBody,
+    client::HttpConnector,
+    header::{ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Client, Method, Request, Response, StatusCode,
+};
+use hyper_rustls::HttpsConnector;
+use multihash::{Hasher, Sha2_256};
+use tokio::io::AsyncReadExt;
+use tracing::{debug, info_span, warn, Instrument};
+use url::Url;
+
+use crate::git;
+
+/// Endpoint for all remotes, overriding the ones derived from their URLs
+const LFS_URL_KEY: &str = "lfs.url";
+/// Remote that git-lfs fetches from when it isn't given one
+const DEFAULT_REMOTE_KEY: &str = "remote.lfsdefault";
+
+/// How much of an object is read at a time while uploading it
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Client for the Git LFS API of a remote, for objects that aren't in IPFS
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/batch.md>
+pub struct Remote {
+    endpoint: Url,
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// Basic authorization from git's credential helpers, once the server asked for it
+    authorization: Mutex<Option<String>>,
+}
+
+impl Remote {
+    pub fn new(endpoint: Url) -> Self {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Self {
+            endpoint,
+            client: Client::builder().build(connector),
+            authorization: Mutex::new(None),
+        }
+    }
+
+    /// Name of the remote git-lfs fetches from when it isn't given one:
+    /// `remote.lfsdefault`, then the remote the current branch tracks, then `origin`
+    ///
+    /// <https://github.com/git-lfs/git-lfs/blob/main/docs/man/git-lfs-config.adoc>
+    pub fn default_name() -> Result<String> {
+        default_name(|key| git::config(key, None), git::current_branch())
+    }
+
+    /// Endpoint of `remote` the way git-lfs finds it
+    ///
+    /// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/server-discovery.md>
+    pub fn from_git_config(remote: &str) -> Result<Self> {
+        if let Some(url) = git::config(LFS_URL_KEY, None)? {
+            return Ok(Self::new(Url::parse(&url)?));
+        }
+        if let Some(url) = git::config(&format!("remote.{}.lfsurl", remote), None)? {
+            return Ok(Self::new(Url::parse(&url)?));
+        }
+        let url = git::config(&format!("remote.{}.url", remote), None)?
+            .with_context(|| format!("no such remote {}", remote))?;
+        Ok(Self::new(endpoint(&url)?))
+    }
+
+    pub async fn batch(&self, request: &BatchRequest) -> Result<BatchResponse> {
+        let url = format!(
+            "{}/objects/batch",
+            self.endpoint.as_str().trim_end_matches('/')
+        );
+        let body = serde_json::to_vec(request)?;
+        let span = info_span!("batch", url = url.as_str(), objects = request.objects.len());
+        async {
+            let mut response = self.post(&url, &body).await?;
+            if response.status() == StatusCode::UNAUTHORIZED {
+                let (username, password) = git::credential_fill(self.endpoint.as_str())?;
+                let credentials = STANDARD.encode(format!("{}:{}", username, password));
+                *self.authorization.lock().unwrap() = Some(format!("Basic {}", credentials));
+                response = self.post(&url, &body).await?;
+            }
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            debug!(status = status.as_u16(), "done");
+            if !status.is_success() {
+                return Err(match serde_json::from_slice::<LfsErrorResponse>(&body) {
+                    Ok(error) => error.with_status(status.as_u16()).into(),
+                    Err(_) => anyhow::anyhow!("batch request to {} failed: {}", url, status),
+                });
+            }
+            serde_json::from_slice(&body).context("invalid batch response")
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn post(&self, url: &str, body: &[u8]) -> Result<Response<Body>> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(ACCEPT, GIT_LFS_CONTENT_TYPE)
+            .header(CONTENT_TYPE, GIT_LFS_CONTENT_TYPE);
+        if let Some(authorization) = self.authorization.lock().unwrap().as_ref() {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        Ok(self
+            .client
+            .request(request.body(Body::from(body.to_vec()))?)
+            .await?)
+    }
+
+    /// Download an object with the basic transfer, checking it against its oid
+    ///
+    /// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/basic-transfers.md#downloads>
+    pub async fn download(&self, action: &Action, object: &Object, path: &Path) -> Result<()> {
+        let request = action_request(Method::GET, action).body(Body::empty())?;
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "download of {} failed: {}",
+                object.oid,
+                response.status()
+            ));
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let partial_path = path.with_extension("partial");
+        let mut file = std::fs::File::create(&partial_path)
+            .with_context(|| format!("could not create {}", partial_path.display()))?;
+        let mut hasher = Sha2_256::default();
+        let mut size = 0;
+        let mut body = response.into_body();
+        while let Some(chunk) = body.data().await.transpose()? {
+            hasher.update(&chunk);
+            file.write_all(&chunk)?;
+            size += chunk.len() as u64;
+        }
+        if size != object.size || hex::encode(hasher.finalize()) != object.oid {
+            std::fs::remove_file(&partial_path)?;
+            return Err(anyhow::anyhow!(
+                "download of {} does not match it",
+                object.oid
+            ));
+        }
+        std::fs::rename(&partial_path, path)?;
+        Ok(())
+    }
+
+    /// Upload an object with the basic transfer, streaming it from `path`
+    ///
+    /// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/basic-transfers.md#uploads>
+    pub async fn upload(&self, action: &Action, object: &Object, path: &Path) -> Result<()> {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("could not open {}", path.display()))?;
+        let (mut sender, body) = Body::channel();
+        let request = action_request(Method::PUT, action)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(CONTENT_LENGTH, object.size)
+            .body(body)?;
+        // Fed from the file as the server reads it; a failed read aborts the request
+        tokio::spawn(async move {
+            let mut buffer = vec![0; BUFFER_SIZE];
+            loop {
+                match file.read(&mut buffer).await {
+                    Ok(0) => return,
+                    Ok(read) => {
+                        let chunk = Bytes::copy_from_slice(&buffer[..read]);
+                        if sender.send_data(chunk).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        warn!(%err, "could not read the object");
+                        sender.abort();
+                        return;
+                    }
+                }
+            }
+        });
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "upload of {} failed: {}",
+                object.oid,
+                response.status()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Have the server confirm that an upload arrived
+    ///
+    /// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/basic-transfers.md#verification>
+    pub async fn verify(&self, action: &Action, object: &Object) -> Result<()> {
+        let request = action_request(Method::POST, action)
+            .header(ACCEPT, GIT_LFS_CONTENT_TYPE)
+            .header(CONTENT_TYPE, GIT_LFS_CONTENT_TYPE)
+            .body(Body::from(serde_json::to_vec(object)?))?;
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "verification of {} failed: {}",
+                object.oid,
+                response.status()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Request to the href of an action, with the headers the server asked for
+fn action_request(method: Method, action: &Action) -> hyper::http::request::Builder {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(action.href().as_str());
+    for (name, value) in action.header().into_iter().flatten() {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    request
+}
+
+fn default_name(
+    config: impl Fn(&str) -> Result<Option<String>>,
+    branch: Option<String>,
+) -> Result<String> {
+    if let Some(remote) = config(DEFAULT_REMOTE_KEY)? {
+        return Ok(remote);
+    }
+    if let Some(branch) = branch {
+        // A branch that tracks another local branch has `.` as its remote
+        let tracked = config(&format!("branch.{}.remote", branch))?;
+        if let Some(remote) = tracked.filter(|remote| remote != ".") {
+            return Ok(remote);
+        }
+    }
+    Ok("origin".to_string())
+}
+
+/// LFS endpoint for a git remote URL, over HTTPS for SSH remotes
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/server-discovery.md>
+pub fn endpoint(remote_url: &str) -> Result<Url> {
+    let url = match Url::parse(remote_url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        Ok(url) if url.scheme() == "ssh" => {
+            let host = url.host_str().context("SSH remote without a host")?;
+            Url::parse(&format!("https://{}{}", host, url.path()))?
+        }
+        Ok(url) => {
+            return Err(anyhow::anyhow!(
+                "no LFS endpoint for {} remotes",
+                url.scheme()
+            ))
+        }
+        // Like `git@example.com:user/repo.git`
+        Err(_) => {
+            let (user_and_host, path) = remote_url
+                .split_once(':')
+                .with_context(|| format!("unknown remote URL {}", remote_url))?;
+            let host = user_and_host.rsplit('@').next().unwrap_or(user_and_host);
+            Url::parse(&format!(
+                "https://{}/{}",
+                host,
+                path.trim_start_matches('/')
+            ))?
+        }
+    };
+    let path = url.path().trim_end_matches('/');
+    let path = if path.ends_with(".git") {
+        format!("{}/info/lfs", path)
+    } else {
+        format!("{}.git/info/lfs", path)
+    };
+    let mut endpoint = url.clone();
+    endpoint.set_path(&path);
+    endpoint.set_username("").ok();
+    endpoint.set_password(None).ok();
+    Ok(endpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn endpoint_is_derived_like_git_lfs() {
+        for (remote_url, expected) in [
+            (
+                "https://git-server.com/foo/bar",
+                "https://git-server.com/foo/bar.git/info/lfs",
+            ),
+            (
+                "https://git-server.com/foo/bar.git/",
+                "https://git-server.com/foo/bar.git/info/lfs",
+            ),
+            (
+                "ssh://git@git-server.com:2222/foo/bar.git",
+                "https://git-server.com/foo/bar.git/info/lfs",
+            ),
+            (
+                "git@git-server.com:foo/bar.git",
+                "https://git-server.com/foo/bar.git/info/lfs",
+            ),
+        ] {
+            assert_eq!(endpoint(remote_url).unwrap().as_str(), expected);
+        }
+        assert!(endpoint("file:///srv/git/bar.git").is_err());
+    }
+
+    #[test]
+    fn default_name_is_found_like_git_lfs() {
+        let config = |values: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                Ok(values
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, value)| value.to_string()))
+            }
+        };
+        let branch = || Some("main".to_string());
+        let tracking = &[("branch.main.remote", "upstream")];
+        let both = &[
+            ("branch.main.remote", "upstream"),
+            (DEFAULT_REMOTE_KEY, "lfs"),
+        ];
+        let local = &[("branch.main.remote", ".")];
+        assert_eq!(default_name(config(both), branch()).unwrap(), "lfs");
+        assert_eq!(
+            default_name(config(tracking), branch()).unwrap(),
+            "upstream"
+        );
+        assert_eq!(default_name(config(tracking), None).unwrap(), "origin");
+        assert_eq!(default_name(config(local), branch()).unwrap(), "origin");
+    }
+}
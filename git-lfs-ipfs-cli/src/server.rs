@@ -0,0 +1,425 @@
+// Handlers return the error body itself, which is as large as any other response.
+#![allow(clippy::result_large_err)]
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use git_lfs_spec::{
+    batch::LfsErrorResponse,
+    locks::{
+        CreateLockRequest, DeleteLockRequest, ListLocksQuery, LockConflict, LockResponse,
+        VerifyLocksRequest,
+    },
+    transfer::basic::VerifyRequest,
+    GIT_LFS_CONTENT_TYPE,
+};
+use hyper::{
+    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use ipfs_api_backend_hyper::IpfsApi;
+use url::form_urlencoded;
+
+use crate::{
+    ipfs::sha256_to_cid,
+    locks::{LockError, LockStore},
+    pins::is_pinned,
+};
+
+fn invalid_oid() -> LfsErrorResponse {
+    LfsErrorResponse::new(422, "Object ID is not a SHA-256 hash of an IPFS block.")
+}
+
+fn invalid_request(err: impl std::fmt::Display) -> LfsErrorResponse {
+    LfsErrorResponse::new(422, format!("Request body could not be parsed: {}", err))
+}
+
+fn not_pinned() -> LfsErrorResponse {
+    LfsErrorResponse::new(404, "Object is not pinned on the IPFS node.")
+}
+
+fn size_mismatch(expected: u64, actual: u64) -> LfsErrorResponse {
+    LfsErrorResponse::new(
+        422,
+        format!(
+            "Object size {} does not match the IPFS block size {}.",
+            expected, actual
+        ),
+    )
+}
+
+fn node_unavailable(err: impl std::fmt::Display) -> LfsErrorResponse {
+    LfsErrorResponse::new(503, format!("The IPFS node could not be queried: {}", err))
+}
+
+fn unauthorized() -> LfsErrorResponse {
+    LfsErrorResponse::new(401, "Credentials needed to identify the lock owner.")
+}
+
+fn lock_not_found() -> LfsErrorResponse {
+    LfsErrorResponse::new(404, "Lock does not exist.")
+}
+
+fn lock_error(err: LockError) -> LfsErrorResponse {
+    match err {
+        LockError::NotFound => lock_not_found(),
+        LockError::Forbidden(_) => LfsErrorResponse::new(403, err.to_string()),
+        LockError::Conflict(_) => LfsErrorResponse::new(409, err.to_string()),
+        LockError::Storage(_) => LfsErrorResponse::internal_server_error(err),
+    }
+}
+
+/// Serve the parts of the Git LFS HTTP API that can be answered by an IPFS node
+///
+/// File locks are kept in `locks`, since IPFS has no notion of ownership.
+pub async fn serve<E: 'static + Send + Sync + std::error::Error>(
+    client: impl IpfsApi<Error = E> + Clone + Send + Sync + 'static,
+    addr: SocketAddr,
+    locks: LockStore,
+) -> Result<()> {
+    let locks = Arc::new(locks);
+    let make_service = make_service_fn(move |_| {
+        let client = client.clone();
+        let locks = locks.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let client = client.clone();
+                let locks = locks.clone();
+                async move { Ok::<_, Infallible>(route(&client, &locks, request).await) }
+            }))
+        }
+    });
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+async fn route<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    locks: &LockStore,
+    request: Request<Body>,
+) -> Response<Body> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let result = match (method, path.as_str()) {
+        (Method::POST, "/verify") => match parse::<VerifyRequest>(request).await {
+            Ok(verify_request) => verify(client, verify_request)
+                .await
+                .map(|()| empty_response(StatusCode::OK)),
+            Err(err) => Err(err),
+        },
+        (Method::POST, "/locks") => create_lock(locks, request).await,
+        (Method::GET, "/locks") => list_locks(locks, &request).await,
+        (Method::POST, "/locks/verify") => verify_locks(locks, request).await,
+        (Method::POST, path) => match path
+            .strip_prefix("/locks/")
+            .and_then(|rest| rest.strip_suffix("/unlock"))
+        {
+            Some(id) => {
+                let id = id.to_string();
+                unlock(locks, &id, request).await
+            }
+            None => Ok(empty_response(StatusCode::NOT_FOUND)),
+        },
+        _ => Ok(empty_response(StatusCode::NOT_FOUND)),
+    };
+    result.unwrap_or_else(|err| error_response(&err))
+}
+
+/// Check that the object git-lfs just uploaded is present and pinned on the IPFS node
+///
+/// The oid is the SHA-256 hash of the raw root block, so the block size must match
+/// the object size as well.
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/basic-transfers.md#verification>
+pub async fn verify<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    request: VerifyRequest,
+) -> Result<(), LfsErrorResponse> {
+    let cid = sha256_to_cid(&request.object.oid)
+        .map_err(|_| invalid_oid())?
+        .to_string();
+
+    if !is_pinned(client, &cid).await.map_err(node_unavailable)? {
+        return Err(not_pinned());
+    }
+
+    let stat = client.block_stat(&cid).await.map_err(node_unavailable)?;
+    if stat.size != request.object.size {
+        return Err(size_mismatch(request.object.size, stat.size));
+    }
+    Ok(())
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/locking.md#create-lock>
+async fn create_lock(
+    locks: &LockStore,
+    request: Request<Body>,
+) -> Result<Response<Body>, LfsErrorResponse> {
+    let owner = owner(&request)?;
+    let CreateLockRequest { path, .. } = parse(request).await?;
+    match locks.create(path, &owner).await {
+        Ok(lock) => Ok(json_response(StatusCode::CREATED, &LockResponse { lock })),
+        Err(LockError::Conflict(lock)) => Ok(json_response(
+            StatusCode::CONFLICT,
+            &LockConflict {
+                lock,
+                message: "already created lock".to_string(),
+                documentation_url: None,
+                request_id: None,
+            },
+        )),
+        Err(err) => Err(lock_error(err)),
+    }
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/locking.md#list-locks>
+async fn list_locks(
+    locks: &LockStore,
+    request: &Request<Body>,
+) -> Result<Response<Body>, LfsErrorResponse> {
+    let mut query = ListLocksQuery::default();
+    for (key, value) in form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes()) {
+        match key.as_ref() {
+            "path" => query.path = Some(value.into_owned()),
+            "id" => query.id = Some(value.into_owned()),
+            "cursor" => query.cursor = Some(value.into_owned()),
+            "limit" => query.limit = Some(value.parse().map_err(invalid_request)?),
+            "refspec" => query.refspec = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    let response = locks.list(&query).await.map_err(lock_error)?;
+    Ok(json_response(StatusCode::OK, &response))
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/locking.md#list-locks-for-verification>
+async fn verify_locks(
+    locks: &LockStore,
+    request: Request<Body>,
+) -> Result<Response<Body>, LfsErrorResponse> {
+    let owner = owner(&request)?;
+    let verify_request: VerifyLocksRequest = parse(request).await?;
+    let response = locks
+        .verify(&owner, &verify_request)
+        .await
+        .map_err(lock_error)?;
+    Ok(json_response(StatusCode::OK, &response))
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/api/locking.md#delete-lock>
+async fn unlock(
+    locks: &LockStore,
+    id: &str,
+    request: Request<Body>,
+) -> Result<Response<Body>, LfsErrorResponse> {
+    let owner = owner(&request)?;
+    let DeleteLockRequest { force, .. } = parse(request).await?;
+    let lock = locks.unlock(id, &owner, force).await.map_err(lock_error)?;
+    Ok(json_response(StatusCode::OK, &LockResponse { lock }))
+}
+
+/// The lock owner is the user name git-lfs sends with HTTP basic authentication
+fn owner(request: &Request<Body>) -> Result<String, LfsErrorResponse> {
+    let credentials = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| STANDARD.decode(encoded).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or_else(unauthorized)?;
+    match credentials.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(user.to_string()),
+        _ => Err(unauthorized()),
+    }
+}
+
+async fn parse<T: serde::de::DeserializeOwned>(
+    request: Request<Body>,
+) -> Result<T, LfsErrorResponse> {
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .map_err(invalid_request)?;
+    serde_json::from_slice(&body).map_err(invalid_request)
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn json_response(status: StatusCode, body: &impl serde::Serialize) -> Response<Body> {
+    let body = serde_json::to_vec(body).expect("responses always serialize");
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, GIT_LFS_CONTENT_TYPE)
+        .body(Body::from(body))
+        .expect("status and header are valid")
+}
+
+fn error_response(err: &LfsErrorResponse) -> Response<Body> {
+    let status = StatusCode::from_u16(err.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut response = json_response(status, err);
+    if status == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            "LFS-Authenticate",
+            HeaderValue::from_static("Basic realm=\"Git LFS\""),
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ipfs::client, policy::Policy};
+    use git_lfs_spec::Object;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    const RAW_BLOCK: &[u8] = include_bytes!("../test/hello_world_raw_block");
+    const OID: &str = "f852c7fa62f971817f54d8a80dcd63fcf7098b3cbde9ae8ec1ee449013ec5db0";
+
+    #[tokio::test]
+    async fn verify_rejects_oid_that_is_not_a_hash() {
+        let request = VerifyRequest {
+            object: Object {
+                oid: "foo".to_string(),
+                size: 1,
+            },
+        };
+        assert_eq!(verify(&client(), request).await, Err(invalid_oid()));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn verify_accepts_pinned_raw_block() {
+        let client = client();
+        let dir = tempfile::tempdir().unwrap();
+        crate::clean::clean(
+            client.clone(),
+            &Policy::default(),
+            &crate::pins::Pins::for_git_dir(dir.path()),
+            None,
+            &crate::rate_limit::Limits::default(),
+            &b"hello world"[..],
+            tokio::io::sink(),
+        )
+        .await
+        .unwrap();
+        let request = VerifyRequest {
+            object: Object {
+                oid: OID.to_string(),
+                size: RAW_BLOCK.len() as u64,
+            },
+        };
+        assert_eq!(verify(&client, request).await, Ok(()));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn verify_rejects_size_mismatch() {
+        let client = client();
+        let dir = tempfile::tempdir().unwrap();
+        crate::clean::clean(
+            client.clone(),
+            &Policy::default(),
+            &crate::pins::Pins::for_git_dir(dir.path()),
+            None,
+            &crate::rate_limit::Limits::default(),
+            &b"hello world"[..],
+            tokio::io::sink(),
+        )
+        .await
+        .unwrap();
+        let request = VerifyRequest {
+            object: Object {
+                oid: OID.to_string(),
+                size: RAW_BLOCK.len() as u64 + 1,
+            },
+        };
+        assert_eq!(
+            verify(&client, request).await,
+            Err(size_mismatch(
+                RAW_BLOCK.len() as u64 + 1,
+                RAW_BLOCK.len() as u64
+            ))
+        );
+    }
+
+    fn lock_request(method: Method, uri: &str, user: Option<&str>, body: &str) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(user) = user {
+            let credentials = STANDARD.encode(format!("{}:secret", user));
+            builder = builder.header(AUTHORIZATION, format!("Basic {}", credentials));
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn create_lock_requires_credentials() {
+        let dir = tempdir().unwrap();
+        let locks = LockStore::new(dir.path().join("locks.json"));
+        let request = lock_request(Method::POST, "/locks", None, r#"{"path":"a.psd"}"#);
+        let response = route(&client(), &locks, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key("LFS-Authenticate"));
+    }
+
+    #[tokio::test]
+    async fn create_lock_conflicts_for_second_owner() {
+        let dir = tempdir().unwrap();
+        let locks = LockStore::new(dir.path().join("locks.json"));
+        let body = r#"{"path":"a.psd","ref":{"name":"refs/heads/main"}}"#;
+        let first = route(
+            &client(),
+            &locks,
+            lock_request(Method::POST, "/locks", Some("jane"), body),
+        )
+        .await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        let second = route(
+            &client(),
+            &locks,
+            lock_request(Method::POST, "/locks", Some("john"), body),
+        )
+        .await;
+        assert_eq!(second.status(), StatusCode::CONFLICT);
+        let conflict: LockConflict =
+            serde_json::from_slice(&hyper::body::to_bytes(second.into_body()).await.unwrap())
+                .unwrap();
+        assert_eq!(conflict.lock.owner.unwrap().name, "jane");
+    }
+
+    #[tokio::test]
+    async fn unlock_route_removes_lock() {
+        let dir = tempdir().unwrap();
+        let locks = LockStore::new(dir.path().join("locks.json"));
+        let lock = locks.create("a.psd".to_string(), "jane").await.unwrap();
+        let response = route(
+            &client(),
+            &locks,
+            lock_request(
+                Method::POST,
+                &format!("/locks/{}/unlock", lock.id),
+                Some("jane"),
+                "{}",
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = route(
+            &client(),
+            &locks,
+            lock_request(Method::GET, "/locks?path=a.psd", Some("jane"), ""),
+        )
+        .await;
+        let list: git_lfs_spec::locks::ListLocksResponse =
+            serde_json::from_slice(&hyper::body::to_bytes(response.into_body()).await.unwrap())
+                .unwrap();
+        assert!(list.locks.is_empty());
+    }
+}
@@ -3,17 +3,33 @@ use cid::Cid;
 use futures::stream::StreamExt;
 use ipfs_api_backend_hyper::IpfsApi;
 use multihash::{Code, Hasher, Multihash, MultihashDigest, Sha2_256};
+use std::{io::Write, path::PathBuf};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::{
+    cache::{Cache, Entry},
+    compression,
+    encryption::{self, Key},
+    ipfs::{is_unixfs_block, MAX_BLOCK_LEN},
+    rate_limit::Limits,
+};
+
 /// Verbatim from IPFS cli docs:
 ///
 /// > Different chunking strategies will produce different
-/// hashes for the same file. The default is a fixed block size of
-/// 256 * 1024 bytes
+/// > hashes for the same file. The default is a fixed block size of
+/// > 256 * 1024 bytes
 const CHUNKER_FIXED_BLOCK_SIZE: usize = 256 * 1024;
 
 const BUFFER_SIZE: usize = CHUNKER_FIXED_BLOCK_SIZE / 256;
 
+/// Enough of an object to tell whether it is encrypted or compressed
+const HEADER_LEN: usize = if encryption::HEADER_LEN > compression::HEADER_LEN {
+    encryption::HEADER_LEN
+} else {
+    compression::HEADER_LEN
+};
+
 async fn sha256_hash_of_raw_block(mut input: impl AsyncRead + Unpin) -> Result<Multihash> {
     let mut buffer = [0u8; BUFFER_SIZE];
     let mut hasher = Sha2_256::default();
@@ -37,16 +53,88 @@ async fn cid_of_raw_block(input: impl AsyncRead + Unpin) -> Result<Cid> {
 /// Recall that git-lfs is actually storing the QmHash but it
 /// wants to get the file's original SHA-256 back.
 ///
+/// The file is read from `cache` if it is there, and added to it otherwise.
+/// Objects that are not raw blocks were kept out of IPFS and are passed through
+/// unchanged, whatever the policy of the path is now. Encrypted files are decrypted
+/// with `key` and compressed files are decompressed with the one of `dictionaries` they
+/// were compressed with. Files are read from IPFS at the download rate of the [Limits].
+///
 /// <https://github.com/git-lfs/git-lfs/blob/main/docs/extensions.md#smudge>
 pub async fn smudge<E: 'static + Send + Sync + std::error::Error>(
     client: impl IpfsApi<Error = E>,
+    cache: Option<&Cache>,
+    dictionaries: &[PathBuf],
+    key: Option<&Key>,
+    limits: &Limits,
     input: impl AsyncRead + Unpin,
     mut output: impl AsyncWrite + Unpin,
 ) -> Result<()> {
-    let cid = cid_of_raw_block(input).await?;
-    let mut stream = client.cat(&format!("/ipfs/{}", cid));
+    let mut input = input.take(MAX_BLOCK_LEN as u64 + 1);
+    let mut block = vec![];
+    input.read_to_end(&mut block).await?;
+    if block.len() > MAX_BLOCK_LEN || !is_unixfs_block(&block) {
+        output.write_all(&block).await?;
+        tokio::io::copy(&mut input.into_inner(), &mut output).await?;
+        return Ok(());
+    }
+    let cid = cid_of_raw_block(block.as_slice()).await?;
+    smudge_cid(client, cache, dictionaries, key, limits, &cid, output).await
+}
+
+/// Write out the file that was added to IPFS as `cid`, like [smudge] does for its raw
+/// block
+pub async fn smudge_cid<E: 'static + Send + Sync + std::error::Error>(
+    client: impl IpfsApi<Error = E>,
+    cache: Option<&Cache>,
+    dictionaries: &[PathBuf],
+    key: Option<&Key>,
+    limits: &Limits,
+    cid: &Cid,
+    mut output: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    if let Some(path) = cache.and_then(|cache| cache.get(cid)) {
+        let mut file = tokio::fs::File::open(path).await?;
+        tokio::io::copy(&mut file, &mut output).await?;
+        return Ok(());
+    }
+    let mut entry = cache.map(|cache| cache.entry(cid)).transpose()?;
+    let mut stream = limits.download(client.cat(&format!("/ipfs/{}", cid)));
+    // Held back until it is known whether the object is encrypted or compressed, since
+    // decoding those needs all of it
+    let mut pending = vec![];
+    let mut encoded = None;
     while let Some(bytes) = stream.next().await.transpose()? {
-        output.write_all(&bytes).await?;
+        if encoded == Some(false) {
+            emit(&mut output, entry.as_mut(), &bytes).await?;
+            continue;
+        }
+        pending.extend_from_slice(&bytes);
+        if encoded.is_none() && pending.len() >= HEADER_LEN {
+            encoded =
+                Some(encryption::is_encrypted(&pending) || compression::is_compressed(&pending));
+            if encoded == Some(false) {
+                emit(&mut output, entry.as_mut(), &pending).await?;
+                pending.clear();
+            }
+        }
+    }
+    let contents = encryption::decrypt(key, &pending)?;
+    let contents = compression::decompress(&contents, dictionaries)?;
+    emit(&mut output, entry.as_mut(), &contents).await?;
+    if let Some(entry) = entry {
+        entry.commit()?;
+    }
+    Ok(())
+}
+
+async fn emit(
+    output: &mut (impl AsyncWrite + Unpin),
+    entry: Option<&mut Entry<'_>>,
+    bytes: &[u8],
+) -> Result<()> {
+    output.write_all(bytes).await?;
+    if let Some(entry) = entry {
+        entry.file().write_all(bytes)?;
     }
     Ok(())
 }
@@ -85,7 +173,97 @@ mod tests {
     async fn smudge_converts_raw_block_into_file_contents() {
         let client = client();
         let mut cursor = Cursor::new(vec![]);
-        smudge(client, RAW_BLOCK, &mut cursor).await.unwrap();
+        smudge(
+            client,
+            None,
+            &[],
+            None,
+            &Limits::default(),
+            RAW_BLOCK,
+            &mut cursor,
+        )
+        .await
+        .unwrap();
         assert_eq!(String::from_utf8_lossy(&cursor.into_inner()), "hello world");
     }
+
+    #[tokio::test]
+    async fn smudge_reads_cached_file_without_ipfs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(MULTI_HASH), "hello world").unwrap();
+        let cache = Cache::new(dir.path(), u64::MAX);
+        let mut cursor = Cursor::new(vec![]);
+        smudge(
+            client(),
+            Some(&cache),
+            &[],
+            None,
+            &Limits::default(),
+            RAW_BLOCK,
+            &mut cursor,
+        )
+        .await
+        .unwrap();
+        assert_eq!(String::from_utf8_lossy(&cursor.into_inner()), "hello world");
+    }
+
+    #[tokio::test]
+    async fn smudge_goes_by_the_object_rather_than_the_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(MULTI_HASH), "hello world").unwrap();
+        let cache = Cache::new(dir.path(), u64::MAX);
+        // Cleaned while the paths were kept out of IPFS, which they may not be anymore
+        let large = vec![b'a'; MAX_BLOCK_LEN + 1];
+        for file in [&b"hello world"[..], &large] {
+            let mut cursor = Cursor::new(vec![]);
+            smudge(
+                client(),
+                Some(&cache),
+                &[],
+                None,
+                &Limits::default(),
+                file,
+                &mut cursor,
+            )
+            .await
+            .unwrap();
+            assert_eq!(cursor.into_inner(), file);
+        }
+        // Cleaned into IPFS, whatever the policy of the path is now
+        let mut cursor = Cursor::new(vec![]);
+        smudge(
+            client(),
+            Some(&cache),
+            &[],
+            None,
+            &Limits::default(),
+            RAW_BLOCK,
+            &mut cursor,
+        )
+        .await
+        .unwrap();
+        assert_eq!(String::from_utf8_lossy(&cursor.into_inner()), "hello world");
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn smudge_fills_cache_on_miss() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path(), u64::MAX);
+        smudge(
+            client(),
+            Some(&cache),
+            &[],
+            None,
+            &Limits::default(),
+            RAW_BLOCK,
+            &mut Cursor::new(vec![]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join(MULTI_HASH)).unwrap(),
+            b"hello world"
+        );
+    }
 }
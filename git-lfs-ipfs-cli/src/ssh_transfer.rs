@@ -0,0 +1,628 @@
+use std::io::{self, Cursor, Read, Write};
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use git_lfs_spec::{
+    batch::Operation,
+    locks::ListLocksQuery,
+    pkt_line::{
+        copy_data_until_flush, read_text_until_separator, write_data, write_pkt_line, PktLine,
+    },
+    transfer::{
+        basic::VerifyRequest,
+        ssh::{
+            format_batch_object, lock_args, lock_lines, parse_batch_object, read_request,
+            write_capabilities, write_error, write_status, write_status_with_data, BatchAction,
+            Command, Request, Status, VERSION,
+        },
+    },
+    Object,
+};
+use ipfs_api_backend_hyper::IpfsApi;
+use multihash::{Hasher, Sha2_256};
+use tracing::warn;
+
+use crate::{
+    ipfs::{is_unixfs_block, sha256_to_cid, MAX_BLOCK_LEN},
+    locks::{LockError, LockStore},
+    pins::Pins,
+    server,
+};
+
+/// Status for requests that failed because the IPFS node could not be queried
+const NODE_UNAVAILABLE: u16 = 503;
+/// Status for uploads larger than any raw root block
+const TOO_LARGE: u16 = 413;
+
+/// Server side of git-lfs's pure SSH transfer protocol, serving objects out of IPFS
+///
+/// git-lfs runs `git-lfs-transfer <path> <operation>` on the remote and talks pkt-lines
+/// over stdin/stdout. A request that fails gets an error status, and the session goes
+/// on with the next one. Uploaded raw blocks are put on the node and pinned in the
+/// repository's [Pins], which pulls the rest of the DAG from the uploader's node.
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md>
+pub async fn ssh_transfer<E: 'static + Send + Sync + std::error::Error>(
+    client: impl IpfsApi<Error = E> + Send + Sync,
+    operation: Operation,
+    locks: LockStore,
+    pins: Pins,
+    user: &str,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<()> {
+    write_capabilities(&mut output)?;
+    output.flush()?;
+    while let Some(request) = read_request(&mut input)? {
+        match &request.command {
+            Command::Version(VERSION) => write_status(&mut output, &Status::new(200), None)?,
+            Command::Version(version) => write_error(
+                &mut output,
+                400,
+                &format!("unsupported version {}", version),
+            )?,
+            Command::Batch => {
+                batch(
+                    &client,
+                    &pins,
+                    &operation,
+                    &request,
+                    &mut input,
+                    &mut output,
+                )
+                .await?
+            }
+            Command::GetObject(oid) => get_object(&client, oid, &mut output).await?,
+            Command::PutObject(oid) => {
+                put_object(&client, &pins, oid, &request, &mut input, &mut output).await?
+            }
+            Command::VerifyObject(oid) => {
+                verify_object(&client, oid, &request, &mut output).await?
+            }
+            Command::Lock => lock(&locks, user, &request, &mut output).await?,
+            Command::ListLock => list_lock(&locks, user, &request, &mut output).await?,
+            Command::Unlock(id) => unlock(&locks, user, id, &request, &mut output).await?,
+            Command::Quit => {
+                write_status(&mut output, &Status::new(200), None)?;
+                output.flush()?;
+                break;
+            }
+        }
+        let consumes_body = matches!(request.command, Command::Batch | Command::PutObject(_));
+        if request.has_body && !consumes_body {
+            copy_data_until_flush(&mut input, &mut std::io::sink())?;
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Parse the operation argument git-lfs passes to `git-lfs-transfer`
+pub fn parse_operation(operation: &str) -> Result<Operation> {
+    match operation {
+        "upload" => Ok(Operation::Upload),
+        "download" => Ok(Operation::Download),
+        other => Err(anyhow::anyhow!("unknown operation {}", other)),
+    }
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#batch>
+async fn batch<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    pins: &Pins,
+    operation: &Operation,
+    request: &Request,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<()> {
+    let lines = if request.has_body {
+        read_text_until_separator(input)?.0
+    } else {
+        vec![]
+    };
+    if request.arg("transfer").unwrap_or("basic") != "basic" {
+        return Ok(write_error(
+            output,
+            400,
+            "only the basic transfer is supported",
+        )?);
+    }
+    let mut response = vec![];
+    for line in lines {
+        let object = match parse_batch_object(&line) {
+            Ok(object) => object,
+            Err(err) => return Ok(write_error(output, 400, &err.to_string())?),
+        };
+        let action = match operation {
+            Operation::Download => BatchAction::Download,
+            Operation::Upload => match sha256_to_cid(&object.oid) {
+                // Uploading again is harmless, so the node failing only costs time
+                Ok(cid) => match pins.contains(client, &cid.to_string()).await {
+                    Ok(true) => BatchAction::Noop,
+                    Ok(false) => BatchAction::Upload,
+                    Err(err) => {
+                        warn!(oid = %object.oid, %err, "could not check pin");
+                        BatchAction::Upload
+                    }
+                },
+                Err(_) => BatchAction::Upload,
+            },
+        };
+        response.push(format_batch_object(&object, action));
+    }
+    Ok(write_status(output, &Status::new(200), Some(&response))?)
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#downloads>
+async fn get_object<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    oid: &str,
+    output: &mut impl Write,
+) -> Result<()> {
+    let cid = match sha256_to_cid(oid) {
+        Ok(cid) => cid.to_string(),
+        Err(err) => return Ok(write_error(output, 400, &err.to_string())?),
+    };
+    if let Err(err) = client.block_stat(&cid).await {
+        return Ok(write_error(output, 404, &err.to_string())?);
+    }
+    // Objects are root blocks, which are small enough to fetch before answering, so
+    // that failing to is still an error status rather than a broken data stream
+    let block = match client
+        .block_get(&cid)
+        .map_ok(|chunk| chunk.to_vec())
+        .try_concat()
+        .await
+    {
+        Ok(block) => block,
+        Err(err) => return Ok(write_error(output, NODE_UNAVAILABLE, &err.to_string())?),
+    };
+    write_status_with_data(output, &Status::new(200).with_arg("size", block.len()))?;
+    write_data(output, &block)?;
+    write_pkt_line(output, &PktLine::Flush)?;
+    Ok(())
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#uploads>
+async fn put_object<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    pins: &Pins,
+    oid: &str,
+    request: &Request,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<()> {
+    let size = request
+        .arg("size")
+        .and_then(|size| size.parse::<u64>().ok());
+    let mut upload = Upload::new(size.map_or(MAX_BLOCK_LEN, |size| {
+        size.min(MAX_BLOCK_LEN as u64) as usize
+    }));
+    if request.has_body {
+        copy_data_until_flush(input, &mut upload)?;
+    }
+    let cid = match sha256_to_cid(oid) {
+        Ok(cid) => cid.to_string(),
+        Err(err) => return Ok(write_error(output, 400, &err.to_string())?),
+    };
+    if let Some(size) = size.filter(|size| *size != upload.size) {
+        let message = format!("object is {} bytes, not {}", upload.size, size);
+        return Ok(write_error(output, 400, &message)?);
+    }
+    if upload.data.is_none() {
+        let message =
+            "only IPFS raw root blocks are accepted, and this object is larger than any block";
+        return Ok(write_error(output, TOO_LARGE, message)?);
+    }
+    if hex::encode(upload.hasher.finalize()) != oid {
+        return Ok(write_error(output, 400, "object does not match its oid")?);
+    }
+    let data = upload.data.unwrap_or_default();
+    if !is_unixfs_block(&data) {
+        let message = "only IPFS raw root blocks are accepted";
+        return Ok(write_error(output, 400, message)?);
+    }
+    if let Err(err) = client.block_put(Cursor::new(data)).await {
+        let message = format!("could not put raw block: {}", err);
+        return Ok(write_error(output, NODE_UNAVAILABLE, &message)?);
+    }
+    // Reading the file in full fetches the rest of its DAG from the uploader's node,
+    // which pinning in MFS would not
+    let fetched = client
+        .cat(&format!("/ipfs/{}", cid))
+        .try_for_each(|_| async { Ok(()) })
+        .await;
+    if let Err(err) = fetched {
+        let message = format!("could not fetch object: {}", err);
+        return Ok(write_error(output, NODE_UNAVAILABLE, &message)?);
+    }
+    if let Err(err) = pins.pin(client, &cid).await {
+        let message = format!("could not pin object: {}", err);
+        return Ok(write_error(output, NODE_UNAVAILABLE, &message)?);
+    }
+    Ok(write_status(output, &Status::new(200), None)?)
+}
+
+/// Body of a `put-object` request, hashed as it arrives and only kept while it is no
+/// larger than its limit
+struct Upload {
+    data: Option<Vec<u8>>,
+    hasher: Sha2_256,
+    size: u64,
+    limit: usize,
+}
+
+impl Upload {
+    fn new(limit: usize) -> Self {
+        Self {
+            data: Some(vec![]),
+            hasher: Sha2_256::default(),
+            size: 0,
+            limit,
+        }
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        self.size += buf.len() as u64;
+        let limit = self.limit;
+        self.data = self
+            .data
+            .take()
+            .filter(|data| data.len() + buf.len() <= limit);
+        if let Some(data) = &mut self.data {
+            data.extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#uploads>
+async fn verify_object<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    oid: &str,
+    request: &Request,
+    output: &mut impl Write,
+) -> Result<()> {
+    let size = match request.arg("size").and_then(|size| size.parse().ok()) {
+        Some(size) => size,
+        None => return Ok(write_error(output, 400, "verify-object needs a size")?),
+    };
+    let verify_request = VerifyRequest {
+        object: Object {
+            oid: oid.to_string(),
+            size,
+        },
+    };
+    match server::verify(client, verify_request).await {
+        Ok(()) => write_status(output, &Status::new(200), None)?,
+        Err(err) => write_error(output, err.status(), err.message())?,
+    }
+    Ok(())
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#locking>
+async fn lock(
+    locks: &LockStore,
+    user: &str,
+    request: &Request,
+    output: &mut impl Write,
+) -> Result<()> {
+    let path = match request.arg("path") {
+        Some(path) => path.to_string(),
+        None => return Ok(write_error(output, 400, "lock needs a path")?),
+    };
+    match locks.create(path, user).await {
+        Ok(lock) => write_status(
+            output,
+            &Status {
+                code: 201,
+                args: lock_args(&lock),
+            },
+            None,
+        )?,
+        Err(LockError::Conflict(lock)) => write_status(
+            output,
+            &Status {
+                code: 409,
+                args: lock_args(&lock),
+            },
+            Some(&["already created lock".to_string()]),
+        )?,
+        Err(err) => write_lock_error(output, err)?,
+    }
+    Ok(())
+}
+
+/// Lists locks, marking which are the user's own when a `refname` asks for verification
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#locking>
+async fn list_lock(
+    locks: &LockStore,
+    user: &str,
+    request: &Request,
+    output: &mut impl Write,
+) -> Result<()> {
+    let query = ListLocksQuery {
+        path: request.arg("path").map(str::to_string),
+        id: request.arg("id").map(str::to_string),
+        cursor: request.arg("cursor").map(str::to_string),
+        limit: request.arg("limit").and_then(|limit| limit.parse().ok()),
+        refspec: request.arg("refname").map(str::to_string),
+    };
+    let response = match locks.list(&query).await {
+        Ok(response) => response,
+        Err(err) => return Ok(write_lock_error(output, err)?),
+    };
+    let verifying = query.refspec.is_some();
+    let lines = response
+        .locks
+        .iter()
+        .flat_map(|lock| {
+            let ours = lock.owner.as_ref().map(|owner| owner.name == user);
+            lock_lines(lock, ours.filter(|_| verifying))
+        })
+        .collect::<Vec<_>>();
+    let mut status = Status::new(200);
+    if let Some(next_cursor) = response.next_cursor {
+        status = status.with_arg("next-cursor", next_cursor);
+    }
+    Ok(write_status(output, &status, Some(&lines))?)
+}
+
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#locking>
+async fn unlock(
+    locks: &LockStore,
+    user: &str,
+    id: &str,
+    request: &Request,
+    output: &mut impl Write,
+) -> Result<()> {
+    let force = request.arg("force") == Some("true");
+    match locks.unlock(id, user, force).await {
+        Ok(lock) => write_status(
+            output,
+            &Status {
+                code: 200,
+                args: lock_args(&lock),
+            },
+            None,
+        )?,
+        Err(err) => write_lock_error(output, err)?,
+    }
+    Ok(())
+}
+
+fn write_lock_error(output: &mut impl Write, err: LockError) -> std::io::Result<()> {
+    let code = match err {
+        LockError::NotFound => 404,
+        LockError::Forbidden(_) => 403,
+        LockError::Conflict(_) => 409,
+        LockError::Storage(_) => 500,
+    };
+    write_error(output, code, &err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipfs::client;
+    use git_lfs_spec::{
+        pkt_line::read_pkt_line,
+        transfer::ssh::{read_status, write_request},
+    };
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn request(command: Command, args: &[(&str, &str)]) -> Request {
+        let mut request = Request::new(command);
+        request.args = args
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        request
+    }
+
+    #[tokio::test]
+    async fn ssh_transfer_handles_lock_commands() {
+        let dir = tempdir().unwrap();
+        let mut input = vec![];
+        for request in [
+            request(Command::Version(1), &[]),
+            request(Command::Lock, &[("path", "a.psd")]),
+            request(Command::Lock, &[("path", "a.psd")]),
+            request(Command::ListLock, &[("refname", "refs/heads/main")]),
+            request(Command::Quit, &[]),
+        ] {
+            write_request(&mut input, &request).unwrap();
+        }
+        let mut output = vec![];
+        ssh_transfer(
+            client(),
+            Operation::Upload,
+            LockStore::new(dir.path().join("locks.json")),
+            Pins::for_git_dir(dir.path()),
+            "jane",
+            input.as_slice(),
+            &mut output,
+        )
+        .await
+        .unwrap();
+
+        let mut reader = output.as_slice();
+        assert_eq!(
+            read_text_until_separator(&mut reader).unwrap().0,
+            ["version=1"]
+        );
+        assert_eq!(read_status(&mut reader).unwrap().0.code, 200);
+        let (created, _) = read_status(&mut reader).unwrap();
+        assert_eq!(created.code, 201);
+        assert_eq!(created.arg("ownername"), Some("jane"));
+        let (conflict, has_body) = read_status(&mut reader).unwrap();
+        assert_eq!(conflict.code, 409);
+        assert!(has_body);
+        read_text_until_separator(&mut reader).unwrap();
+        let (listed, _) = read_status(&mut reader).unwrap();
+        assert_eq!(listed.code, 200);
+        let (lines, _) = read_text_until_separator(&mut reader).unwrap();
+        assert_eq!(
+            lines.last().unwrap(),
+            &format!("owner {} ours", created.arg("id").unwrap())
+        );
+        assert_eq!(read_status(&mut reader).unwrap().0.code, 200);
+        assert_eq!(read_pkt_line(&mut reader).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn ssh_transfer_goes_on_after_a_bad_request() {
+        const OID: &str = "f852c7fa62f971817f54d8a80dcd63fcf7098b3cbde9ae8ec1ee449013ec5db0";
+        let dir = tempdir().unwrap();
+        let mut input = vec![];
+        for request in [
+            request(Command::VerifyObject(OID.to_string()), &[]),
+            request(Command::Lock, &[("path", "a.psd")]),
+            request(Command::Quit, &[]),
+        ] {
+            write_request(&mut input, &request).unwrap();
+        }
+        let mut output = vec![];
+        ssh_transfer(
+            client(),
+            Operation::Upload,
+            LockStore::new(dir.path().join("locks.json")),
+            Pins::for_git_dir(dir.path()),
+            "jane",
+            input.as_slice(),
+            &mut output,
+        )
+        .await
+        .unwrap();
+
+        let mut reader = output.as_slice();
+        read_text_until_separator(&mut reader).unwrap();
+        let (status, has_body) = read_status(&mut reader).unwrap();
+        assert_eq!(status.code, 400);
+        assert!(has_body);
+        assert_eq!(
+            read_text_until_separator(&mut reader).unwrap().0,
+            ["verify-object needs a size"]
+        );
+        assert_eq!(read_status(&mut reader).unwrap().0.code, 201);
+        assert_eq!(read_status(&mut reader).unwrap().0.code, 200);
+    }
+
+    #[tokio::test]
+    async fn ssh_transfer_only_accepts_raw_root_blocks() {
+        const FILE: &[u8] = b"hello world";
+        const OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let large = vec![0; MAX_BLOCK_LEN + 1];
+        let large_oid = {
+            let mut hasher = Sha2_256::default();
+            hasher.update(&large);
+            hex::encode(hasher.finalize())
+        };
+        let dir = tempdir().unwrap();
+        let mut input = vec![];
+        for (oid, size, body) in [
+            (OID, FILE.len(), FILE),
+            (OID, FILE.len() - 1, FILE),
+            (large_oid.as_str(), large.len(), large.as_slice()),
+        ] {
+            let mut request = request(
+                Command::PutObject(oid.to_string()),
+                &[("size", &size.to_string())],
+            );
+            request.has_body = true;
+            write_request(&mut input, &request).unwrap();
+            write_data(&mut input, body).unwrap();
+            write_pkt_line(&mut input, &PktLine::Flush).unwrap();
+        }
+        write_request(&mut input, &request(Command::Quit, &[])).unwrap();
+        let mut output = vec![];
+        ssh_transfer(
+            client(),
+            Operation::Upload,
+            LockStore::new(dir.path().join("locks.json")),
+            Pins::for_git_dir(dir.path()),
+            "jane",
+            input.as_slice(),
+            &mut output,
+        )
+        .await
+        .unwrap();
+
+        let mut reader = output.as_slice();
+        read_text_until_separator(&mut reader).unwrap();
+        for (code, message) in [
+            (400, "only IPFS raw root blocks are accepted"),
+            (400, "object is 11 bytes, not 10"),
+            (
+                413,
+                "only IPFS raw root blocks are accepted, and this object is larger than any block",
+            ),
+        ] {
+            let (status, _) = read_status(&mut reader).unwrap();
+            assert_eq!(status.code, code);
+            assert_eq!(read_text_until_separator(&mut reader).unwrap().0, [message]);
+        }
+        assert_eq!(read_status(&mut reader).unwrap().0.code, 200);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn ssh_transfer_serves_raw_block() {
+        const RAW_BLOCK: &[u8] = include_bytes!("../test/hello_world_raw_block");
+        const OID: &str = "f852c7fa62f971817f54d8a80dcd63fcf7098b3cbde9ae8ec1ee449013ec5db0";
+        let client = client();
+        let pins_dir = tempdir().unwrap();
+        crate::clean::clean(
+            client.clone(),
+            &crate::policy::Policy::default(),
+            &Pins::for_git_dir(pins_dir.path()),
+            None,
+            &crate::rate_limit::Limits::default(),
+            &b"hello world"[..],
+            tokio::io::sink(),
+        )
+        .await
+        .unwrap();
+
+        let dir = tempdir().unwrap();
+        let mut input = vec![];
+        write_request(
+            &mut input,
+            &request(Command::GetObject(OID.to_string()), &[]),
+        )
+        .unwrap();
+        let mut output = vec![];
+        ssh_transfer(
+            client,
+            Operation::Download,
+            LockStore::new(dir.path().join("locks.json")),
+            Pins::for_git_dir(dir.path()),
+            "jane",
+            input.as_slice(),
+            &mut output,
+        )
+        .await
+        .unwrap();
+
+        let mut reader = output.as_slice();
+        read_text_until_separator(&mut reader).unwrap();
+        let (status, has_body) = read_status(&mut reader).unwrap();
+        assert_eq!(
+            status.arg("size"),
+            Some(RAW_BLOCK.len().to_string().as_str())
+        );
+        assert!(has_body);
+        let mut data = vec![];
+        copy_data_until_flush(&mut reader, &mut data).unwrap();
+        assert_eq!(data, RAW_BLOCK);
+    }
+}
@@ -0,0 +1,196 @@
+use std::{fmt, future::Future, time::Duration};
+
+use anyhow::{Context, Result};
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+
+use crate::git;
+
+/// Seconds a whole object may take to arrive from IPFS, unlimited by default
+const OBJECT_TIMEOUT_KEY: &str = "lfs.ipfs.timeout";
+/// Seconds IPFS may go without sending any of an object
+const INACTIVITY_TIMEOUT_KEY: &str = "lfs.ipfs.inactivitytimeout";
+
+/// Long enough for the DHT to find a provider that exists
+const DEFAULT_INACTIVITY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Limits on how long reads from IPFS may take
+///
+/// Without them, a CID that nobody provides keeps the node looking for it forever.
+/// Giving up drops the request, which closes its connection to the node.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Timeouts {
+    object: Option<Duration>,
+    inactivity: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self::new(None, Some(DEFAULT_INACTIVITY_TIMEOUT))
+    }
+}
+
+/// A read from IPFS that gave up
+#[derive(PartialEq, Eq, Debug)]
+pub struct TimedOut {
+    inactivity: bool,
+    after: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.inactivity {
+            write!(f, "IPFS sent nothing for {:?}", self.after)
+        } else {
+            write!(f, "IPFS took longer than {:?}", self.after)
+        }
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+impl Timeouts {
+    pub fn new(object: Option<Duration>, inactivity: Option<Duration>) -> Self {
+        Self { object, inactivity }
+    }
+
+    /// Use `lfs.ipfs.timeout` and `lfs.ipfs.inactivitytimeout`, in seconds, where 0
+    /// turns a timeout off
+    pub fn from_git_config() -> Result<Self> {
+        let seconds = |key: &str, default: Option<Duration>| -> Result<Option<Duration>> {
+            match git::config(key, Some("int"))? {
+                Some(seconds) => {
+                    let seconds: u64 = seconds
+                        .parse()
+                        .with_context(|| format!("invalid {} {}", key, seconds))?;
+                    Ok((seconds > 0).then(|| Duration::from_secs(seconds)))
+                }
+                None => Ok(default),
+            }
+        };
+        Ok(Self::new(
+            seconds(OBJECT_TIMEOUT_KEY, None)?,
+            seconds(INACTIVITY_TIMEOUT_KEY, Some(DEFAULT_INACTIVITY_TIMEOUT))?,
+        ))
+    }
+
+    /// Give up on reading a whole object after the object timeout
+    pub async fn object<T>(&self, future: impl Future<Output = T>) -> Result<T, TimedOut> {
+        match self.object {
+            Some(after) => tokio::time::timeout(after, future)
+                .await
+                .map_err(|_| TimedOut {
+                    inactivity: false,
+                    after,
+                }),
+            None => Ok(future.await),
+        }
+    }
+
+    /// Give up on a request that IPFS answers all at once, like a block lookup, after
+    /// whichever of the timeouts is shorter
+    pub async fn request<T>(&self, future: impl Future<Output = T>) -> Result<T, TimedOut> {
+        let (after, inactivity) = match (self.object, self.inactivity) {
+            (Some(object), Some(inactivity)) if object < inactivity => (object, false),
+            (_, Some(inactivity)) => (inactivity, true),
+            (Some(object), None) => (object, false),
+            (None, None) => return Ok(future.await),
+        };
+        tokio::time::timeout(after, future)
+            .await
+            .map_err(|_| TimedOut { inactivity, after })
+    }
+
+    /// End a stream of chunks with a [TimedOut] error once the next one takes longer
+    /// than the inactivity timeout
+    pub fn stream<'a, T, E>(
+        &self,
+        stream: impl Stream<Item = Result<T, E>> + Send + Unpin + 'a,
+    ) -> BoxStream<'a, Result<T, anyhow::Error>>
+    where
+        T: Send + 'a,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let after = match self.inactivity {
+            Some(after) => after,
+            None => return stream.map(|chunk| chunk.map_err(Into::into)).boxed(),
+        };
+        stream::unfold(Some(stream), move |stream| async move {
+            let mut stream = stream?;
+            match tokio::time::timeout(after, stream.next()).await {
+                Ok(Some(chunk)) => Some((chunk.map_err(Into::into), Some(stream))),
+                Ok(None) => None,
+                Err(_) => {
+                    let timed_out = TimedOut {
+                        inactivity: true,
+                        after,
+                    };
+                    Some((Err(timed_out.into()), None))
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn stream_ends_when_ipfs_goes_quiet() {
+        let timeouts = Timeouts::new(None, Some(TIMEOUT));
+        let chunks = stream::iter([Ok::<_, std::io::Error>(vec![1u8])]).chain(stream::pending());
+        let mut stream = timeouts.stream(chunks);
+        assert_eq!(stream.next().await.unwrap().unwrap(), vec![1]);
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TimedOut>(),
+            Some(&TimedOut {
+                inactivity: true,
+                after: TIMEOUT
+            })
+        );
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn request_gives_up_after_the_shorter_timeout() {
+        let timeouts = Timeouts::new(Some(Duration::from_secs(60)), Some(TIMEOUT));
+        assert_eq!(
+            timeouts.request(futures::future::pending::<()>()).await,
+            Err(TimedOut {
+                inactivity: true,
+                after: TIMEOUT
+            })
+        );
+        let timeouts = Timeouts::new(Some(TIMEOUT), None);
+        assert_eq!(
+            timeouts.request(futures::future::pending::<()>()).await,
+            Err(TimedOut {
+                inactivity: false,
+                after: TIMEOUT
+            })
+        );
+        assert_eq!(Timeouts::new(None, None).request(async { 1 }).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn object_gives_up_after_its_timeout() {
+        let timeouts = Timeouts::new(Some(TIMEOUT), None);
+        assert_eq!(timeouts.object(async { 1 }).await, Ok(1));
+        assert!(timeouts
+            .object(futures::future::pending::<()>())
+            .await
+            .is_err());
+        // Without timeouts, streams pass through
+        let chunks = stream::iter([Ok::<_, std::io::Error>(vec![1u8])]);
+        let timeouts = Timeouts::new(None, None);
+        assert_eq!(timeouts.stream(chunks).count().await, 1);
+    }
+}
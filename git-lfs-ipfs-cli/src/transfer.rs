@@ -1,121 +1,547 @@
-use anyhow::{Context, Result};
-use futures::{Stream, StreamExt};
-use ipfs_api_backend_hyper::IpfsApi;
-use std::{io::Write, path::Path};
-use tokio::io::{AsyncBufRead, AsyncBufReadExt};
-
-use git_lfs_spec::transfer::custom::{self, Complete, Error, Event, Operation, Progress};
-
-pub fn read_events(input: impl AsyncBufRead + Unpin) -> impl Stream<Item = Result<Event>> {
-    async_stream::stream! {
-        let mut lines = input.lines();
-        while let Some(line) = lines.next_line().await? {
-            let parsed = serde_json::from_str(&line).context("could not parse JSON");
-            yield parsed
+use async_trait::async_trait;
+use futures::{io::AllowStdIo, StreamExt};
+use ipfs_api_backend_hyper::{request, response::AddResponse, IpfsApi};
+use multihash::{Hasher, Sha2_256};
+use std::{
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use tokio::sync::OnceCell;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    encryption::{self, Key},
+    index::{Entry, Index, DEFAULT_CHUNKER},
+    ipfs::{has_block, is_unixfs_block, traced, MAX_BLOCK_LEN},
+    manifest::Manifest,
+    peers::{self, Hints},
+    pins::Pins,
+    rate_limit::Limits,
+    remote::Remote,
+    timeouts::{TimedOut, Timeouts},
+};
+use git_lfs_spec::{
+    batch::{Actions, BatchRequest, ObjectResponse, Operation, Transfer},
+    transfer::custom::{
+        agent::{Agent, ProgressSink},
+        Download, Error, Init, Upload,
+    },
+    Object,
+};
+
+const BAD_REQUEST: i32 = 400;
+const INTERNAL_SERVER_ERROR: i32 = 500;
+/// IPFS gave up or stalled on an object, which may work when tried again
+const NOT_FOUND: i32 = 404;
+const GATEWAY_TIMEOUT: i32 = 504;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Custom transfer agent that moves raw blocks in and out of IPFS
+///
+/// Objects that never went through clean, like those from before IPFS was set up, are
+/// plain LFS objects. They are moved with the basic transfer of the remote's LFS
+/// server instead, so repositories with both kinds in their history keep working.
+///
+/// With an [Index], oids are the SHA-256 of files rather than of raw blocks, and the
+/// agent adds files to IPFS itself.
+///
+/// <https://github.com/git-lfs/git-lfs/blob/main/docs/custom-transfers.md>
+pub struct IpfsAgent<C> {
+    client: C,
+    download_folder: PathBuf,
+    /// CIDs of files, for [crate::index::OidMode::Stock]
+    index: Option<Index>,
+    /// Encrypts files that the agent adds itself
+    key: Option<Key>,
+    /// Name of the remote git-lfs is transferring for
+    remote_name: OnceLock<String>,
+    /// Only set up once a plain LFS object needs it
+    remote: OnceLock<Remote>,
+    /// Manifest of the remote, only fetched once a file is missing from the index
+    manifest: OnceCell<Option<Manifest>>,
+    /// Rates of the remote, shared by all concurrent transfers
+    limits: OnceLock<Limits>,
+    /// Pins of the repository, only looked up once something is uploaded
+    pins: OnceLock<Pins>,
+    /// Peers of the remote, connected to at init
+    hints: OnceLock<Hints>,
+    /// Limits on downloads from IPFS, which hang if nobody provides an object
+    timeouts: Timeouts,
+}
+
+impl<C> IpfsAgent<C> {
+    pub fn new(client: C, download_folder: impl Into<PathBuf>) -> Self {
+        Self {
+            client,
+            download_folder: download_folder.into(),
+            index: None,
+            key: None,
+            remote_name: OnceLock::new(),
+            remote: OnceLock::new(),
+            manifest: OnceCell::new(),
+            limits: OnceLock::new(),
+            pins: OnceLock::new(),
+            hints: OnceLock::new(),
+            timeouts: Timeouts::default(),
         }
     }
-}
 
-const INTERNAL_SERVER_ERROR: i32 = 500;
+    /// Treat oids as the SHA-256 of files, finding their CIDs in `index`
+    pub fn with_index(mut self, index: Index) -> Self {
+        self.index = Some(index);
+        self
+    }
 
-pub fn transfer<E: 'static + Send + Sync + std::error::Error>(
-    client: impl IpfsApi<Error = E>,
-    input_event_stream: impl Stream<Item = Result<Event>>,
-    download_folder: impl AsRef<Path>,
-) -> impl Stream<Item = Result<Event>> {
-    let mut init_opt = None;
-    async_stream::stream! {
-        futures_util::pin_mut!(input_event_stream);
-        while let Some(event) = input_event_stream.next().await.transpose()? {
-            match (init_opt.as_ref(), event) {
-                (None, Event::Init(init)) => {
-                    init_opt = Some(init);
-                    yield Ok(Event::AcknowledgeInit)
-                }
-                (None, event) => {
-                    yield Err(anyhow::anyhow!("Unexpected event: {:?}", event))
-                }
-                (Some(_), Event::Init(init)) => {
-                    yield Err(anyhow::anyhow!("Unexpected init event: {:?}", init))
+    pub fn with_key(mut self, key: Key) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    fn remote(&self) -> anyhow::Result<&Remote> {
+        if let Some(remote) = self.remote.get() {
+            return Ok(remote);
+        }
+        let name = self.remote_name.get().map_or("origin", String::as_str);
+        let remote = Remote::from_git_config(name)?;
+        Ok(self.remote.get_or_init(|| remote))
+    }
+
+    fn limits(&self) -> Result<&Limits, Error> {
+        if let Some(limits) = self.limits.get() {
+            return Ok(limits);
+        }
+        let remote = self.remote_name.get().map_or("origin", String::as_str);
+        let limits = Limits::from_git_config(Some(remote)).map_err(internal_error)?;
+        Ok(self.limits.get_or_init(|| limits))
+    }
+
+    fn pins(&self) -> Result<&Pins, Error> {
+        if let Some(pins) = self.pins.get() {
+            return Ok(pins);
+        }
+        let pins = Pins::from_git_dir().map_err(internal_error)?;
+        Ok(self.pins.get_or_init(|| pins))
+    }
+
+    /// Connect to a provider of `cid` if the remote asks for providers to be looked up
+    async fn find_provider<E>(&self, cid: &str)
+    where
+        C: IpfsApi<Error = E> + Send + Sync,
+        E: 'static + Send + Sync + std::error::Error,
+    {
+        if self.hints.get().is_some_and(|hints| hints.find_providers) {
+            if let Some(provider) = peers::find_provider(&self.client, cid).await {
+                debug!(cid, provider, "connected to provider");
+            }
+        }
+    }
+
+    async fn upload_to_remote(&self, object: &Object, path: &Path) -> anyhow::Result<()> {
+        let remote = self.remote()?;
+        match batch(remote, Operation::Upload, object).await? {
+            Actions::None => Ok(()),
+            Actions::Upload { upload } => remote.upload(&upload, object, path).await,
+            Actions::UploadAndVerify { upload, verify } => {
+                remote.upload(&upload, object, path).await?;
+                remote.verify(&verify, object).await
+            }
+            Actions::Download { .. } => Err(anyhow::anyhow!("no upload action")),
+        }
+    }
+
+    /// Add a file to IPFS and remember its CID
+    ///
+    /// The file is streamed from `path`, unless it is encrypted, which needs all of it.
+    /// Either way, it is added at the upload rate of the [Limits].
+    async fn upload_file<E>(&self, index: &Index, object: &Object, path: &Path) -> Result<(), Error>
+    where
+        C: IpfsApi<Error = E> + Send + Sync,
+        E: 'static + Send + Sync + std::error::Error,
+    {
+        let input: Box<dyn Read + Send + Sync> = match &self.key {
+            Some(key) => {
+                let data = std::fs::read(path).map_err(internal_error)?;
+                Box::new(Cursor::new(key.encrypt(&data).map_err(internal_error)?))
+            }
+            None => Box::new(std::fs::File::open(path).map_err(internal_error)?),
+        };
+        let input = self.limits()?.upload_reader(AllowStdIo::new(input));
+        let options = request::Add {
+            chunker: Some(DEFAULT_CHUNKER),
+            pin: Some(false),
+            ..Default::default()
+        };
+        let AddResponse { hash, .. } = self
+            .client
+            .add_async_with_options(input, options)
+            .await
+            .map_err(internal_error)?;
+        self.pins()?
+            .pin(&self.client, &hash)
+            .await
+            .map_err(internal_error)?;
+        let entry = Entry::new(hash.parse().map_err(internal_error)?, DEFAULT_CHUNKER);
+        index.insert(&object.oid, &entry).map_err(internal_error)?;
+        let cid = entry.cid;
+        info!(%cid, bytes = object.size, "uploaded");
+        Ok(())
+    }
+
+    /// Fetch a file by the CID in the index, or from the remote if it isn't there
+    async fn download_file<E>(
+        &self,
+        index: &Index,
+        object: &Object,
+        progress: &ProgressSink,
+    ) -> Result<PathBuf, Error>
+    where
+        C: IpfsApi<Error = E> + Send + Sync,
+        E: 'static + Send + Sync + std::error::Error,
+    {
+        let output_path = self.download_folder.join(&object.oid);
+        let cid = match index.get(&object.oid).map_err(internal_error)? {
+            Some(entry) => Some(entry.cid),
+            None => match self.manifest().await {
+                Some(manifest) => manifest.get(&object.oid).map_err(internal_error)?,
+                None => None,
+            },
+        };
+        let cid = match cid {
+            Some(cid) => cid.to_string(),
+            None => {
+                info!("not in the index or manifest, downloading from the remote");
+                self.download_from_remote(object, &output_path)
+                    .await
+                    .map_err(internal_error)?;
+                progress.advance(object.size);
+                return Ok(output_path);
+            }
+        };
+
+        self.find_provider(&cid).await;
+        let limits = self.limits()?;
+        let partial_path = output_path.with_extension("partial");
+        let mut output = std::fs::File::create(&partial_path).map_err(internal_error)?;
+        let cat = traced("cat", &cid, async {
+            let stream = self
+                .timeouts
+                .stream(self.client.cat(&format!("/ipfs/{}", cid)));
+            let mut stream = limits.download(stream);
+            let mut hasher = Sha2_256::default();
+            let mut bytes = 0;
+            // Decrypting needs all of the object, but anything else is written as it
+            // arrives
+            let mut encrypted = vec![];
+            while let Some(res) = stream.next().await {
+                let chunk = res.map_err(stream_error)?;
+                progress.advance(chunk.len() as u64);
+                if self.key.is_some() {
+                    encrypted.extend_from_slice(&chunk);
+                    continue;
                 }
+                hasher.update(&chunk);
+                output.write_all(&chunk).map_err(internal_error)?;
+                bytes += chunk.len();
+            }
+            if self.key.is_some() {
+                let contents =
+                    encryption::decrypt(self.key.as_ref(), &encrypted).map_err(internal_error)?;
+                hasher.update(&contents);
+                output.write_all(&contents).map_err(internal_error)?;
+                bytes = contents.len();
+            }
+            Ok::<_, Error>((hex::encode(hasher.finalize()), bytes))
+        });
+        let bytes = match self.timeouts.object(cat).await.map_err(timed_out) {
+            Ok(Ok((oid, bytes))) if oid == object.oid => bytes,
+            Ok(Ok(_)) => {
+                let _ = std::fs::remove_file(&partial_path);
+                return Err(internal_error(format!("{} does not match its oid", cid)));
+            }
+            Ok(Err(err)) | Err(err) => {
+                let _ = std::fs::remove_file(&partial_path);
+                return Err(err);
+            }
+        };
+        std::fs::rename(&partial_path, &output_path).map_err(internal_error)?;
+        info!(%cid, bytes, "downloaded");
+        Ok(output_path)
+    }
 
-                (Some(_), Event::Terminate) => {
-                    break
+    /// Manifest published for the remote, if one is configured and can be resolved
+    async fn manifest<E>(&self) -> Option<&Manifest>
+    where
+        C: IpfsApi<Error = E> + Send + Sync,
+        E: 'static + Send + Sync + std::error::Error,
+    {
+        self.manifest
+            .get_or_init(|| async {
+                let remote = self.remote_name.get().map_or("origin", String::as_str);
+                let name = match Manifest::name_for_remote(remote) {
+                    Ok(Some(name)) => name,
+                    Ok(None) => return None,
+                    Err(err) => {
+                        warn!(%err, "could not read the manifest name");
+                        return None;
+                    }
+                };
+                match Manifest::resolve(&self.client, &name).await {
+                    Ok(manifest) => Some(manifest),
+                    Err(err) => {
+                        warn!(%err, name = name.as_str(), "could not resolve the manifest");
+                        None
+                    }
                 }
-                (Some(init), event) => {
-                    match (event, &init.operation) {
-                        (Event::Download(download), Operation::Download) => {
-                            let cid_result = crate::ipfs::sha256_to_cid(&download.object.oid);
-                            match cid_result {
-                                Ok(cid) => {
-                                    let output_path = download_folder.as_ref().join(&download.object.oid);
-                                    let mut output = std::fs::File::create(&output_path)?;
-
-                                    let mut stream =
-                                        client.block_get(&format!("/ipfs/{}", cid));
-                                    let mut bytes_so_far = 0;
-                                    while let Some(res) = stream.next().await {
-                                        let bytes = res?;
-                                        output.write_all(&bytes)?;
-                                        bytes_so_far += bytes.len() as u64;
-                                            yield Ok(Event::Progress(
-                                                Progress {
-                                                    oid: download.object.oid.clone(),
-                                                    bytes_so_far,
-                                                    bytes_since_last: bytes.len() as u64,
-                                                }
-                                                .into()
-                                            ));
-                                    }
-                                    yield Ok(Event::Complete(
-                                        Complete {
-                                            oid: download.object.oid.clone(),
-                                            result: Some(custom::Result::Path(output_path)),
-                                        }
-                                        .into(),
-                                    ));
-                                }
-                                Err(err) => {
-                                    yield Ok(Event::Complete(
-                                        Complete {
-                                            oid: download.object.oid.clone(),
-                                            result: Some(custom::Result::Error(Error {
-                                                code: INTERNAL_SERVER_ERROR,
-                                                message: err.to_string(),
-                                            })),
-                                        }
-                                        .into(),
-                                    ))
-                                },
-                            }
-                        }
-                        // Upload transfer is dummy, clean adds files to IPFS already
-                        // TODO: just check the sha256 hash with a /api/v0/block/get
-                        (Event::Upload(upload), Operation::Upload) => { yield Ok(Event::Complete(
-                                Complete {
-                                    oid: upload.object.oid.clone(),
-                                    result: None,
-                                }
-                                .into(),
-                            ))
-                        },
-                        (event, _) => {yield Err(anyhow::anyhow!("Unexpected event: {:?}", event))},
-                    };
+            })
+            .await
+            .as_ref()
+    }
+
+    async fn download_from_remote(&self, object: &Object, path: &Path) -> anyhow::Result<()> {
+        let remote = self.remote()?;
+        match batch(remote, Operation::Download, object).await? {
+            Actions::Download { download } => remote.download(&download, object, path).await,
+            _ => Err(anyhow::anyhow!("no download action")),
+        }
+    }
+}
+
+/// Whether the node has the root block of `cid` or finds it before the [Timeouts] give
+/// up
+///
+/// Only the node answering that it can't find the block makes this false. The node
+/// being unreachable or the timeouts giving up are errors, since the block may well be
+/// in IPFS.
+pub async fn probe<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    timeouts: &Timeouts,
+    cid: &str,
+) -> anyhow::Result<bool> {
+    if has_block(client, cid).await? {
+        return Ok(true);
+    }
+    let probe = timeouts.request(client.block_stat(cid));
+    match traced("block_stat", cid, probe).await? {
+        Ok(_) => Ok(true),
+        Err(err) => {
+            debug!(%err, "block not found");
+            Ok(false)
+        }
+    }
+}
+
+/// Actions for a single object from the remote's batch API
+pub async fn batch(
+    remote: &Remote,
+    operation: Operation,
+    object: &Object,
+) -> anyhow::Result<Actions> {
+    let request = BatchRequest {
+        operation,
+        transfer: vec![Transfer::Basic],
+        ref_property: None,
+        objects: vec![object.clone()],
+    };
+    match remote.batch(&request).await?.objects.pop() {
+        Some(ObjectResponse::Success { actions, .. }) => Ok(*actions),
+        Some(ObjectResponse::Error { error, .. }) => Err(error.into()),
+        None => Err(anyhow::anyhow!(
+            "{} is not in the batch response",
+            object.oid
+        )),
+    }
+}
+
+#[async_trait]
+impl<C, E> Agent for IpfsAgent<C>
+where
+    C: IpfsApi<Error = E> + Send + Sync,
+    E: 'static + Send + Sync + std::error::Error,
+{
+    async fn init(&self, init: &Init) -> Result<(), Error> {
+        let _ = self.remote_name.set(init.remote.clone());
+        let hints = Hints::from_git_config(&init.remote).map_err(internal_error)?;
+        hints.connect(&self.client).await;
+        let _ = self.hints.set(hints);
+        Ok(())
+    }
+
+    /// Clean already added the object, but it is read back and put again in case the
+    /// node has since dropped it
+    #[instrument(skip_all, fields(oid = %upload.object.oid, size = upload.object.size))]
+    async fn upload(&self, upload: Upload, progress: ProgressSink) -> Result<(), Error> {
+        let cid = crate::ipfs::sha256_to_cid(&upload.object.oid)
+            .map_err(internal_error)?
+            .to_string();
+        let mut input = std::fs::File::open(&upload.path).map_err(internal_error)?;
+
+        // Only raw root blocks are needed in memory, so files and plain objects too
+        // large to be blocks are streamed from their path instead
+        let mut data = self.index.is_none().then(Vec::new);
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut hasher = Sha2_256::default();
+        loop {
+            let bytes_read = input.read(&mut buffer).map_err(internal_error)?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+            data = data.filter(|data| data.len() + bytes_read <= MAX_BLOCK_LEN);
+            if let Some(data) = &mut data {
+                data.extend_from_slice(&buffer[..bytes_read]);
+            }
+            progress.advance(bytes_read as u64);
+        }
+        if hex::encode(hasher.finalize()) != upload.object.oid {
+            warn!(path = %upload.path.display(), "object does not match its oid");
+            return Err(Error {
+                code: BAD_REQUEST,
+                message: format!("{} does not match its oid", upload.path.display()),
+            });
+        }
+
+        if let Some(index) = &self.index {
+            return self.upload_file(index, &upload.object, &upload.path).await;
+        }
+        let data = match data {
+            Some(data) if is_unixfs_block(&data) => data,
+            _ => {
+                info!("not an IPFS block, uploading to the remote");
+                self.upload_to_remote(&upload.object, &upload.path)
+                    .await
+                    .map_err(internal_error)?;
+                return Ok(());
+            }
+        };
+
+        let bytes = data.len();
+        self.limits()?.upload(bytes).await;
+        traced("block_put", &cid, self.client.block_put(Cursor::new(data)))
+            .await
+            .map_err(internal_error)?;
+        self.pins()?
+            .pin(&self.client, &cid)
+            .await
+            .map_err(internal_error)?;
+        info!(%cid, bytes, "uploaded");
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(oid = %download.object.oid, size = download.object.size))]
+    async fn download(&self, download: Download, progress: ProgressSink) -> Result<PathBuf, Error> {
+        if let Some(index) = &self.index {
+            return self.download_file(index, &download.object, &progress).await;
+        }
+        let cid = crate::ipfs::sha256_to_cid(&download.object.oid)
+            .map_err(internal_error)?
+            .to_string();
+        let output_path = self.download_folder.join(&download.object.oid);
+
+        self.find_provider(&cid).await;
+        // Plain LFS objects are only on the remote, so it is asked before the node looks
+        // on the network, which takes until the timeouts give up for blocks nobody has
+        if !has_block(&self.client, &cid)
+            .await
+            .map_err(internal_error)?
+        {
+            match self
+                .download_from_remote(&download.object, &output_path)
+                .await
+            {
+                Ok(()) => {
+                    info!("downloaded from the remote");
+                    progress.advance(download.object.size);
+                    return Ok(output_path);
                 }
+                Err(err) => debug!(%err, "not on the remote"),
+            }
+            if !probe(&self.client, &self.timeouts, &cid)
+                .await
+                .map_err(stream_error)?
+            {
+                return Err(not_found(&cid));
             }
         }
+
+        // Only renamed into place once complete, so failures leave no truncated object
+        let partial_path = output_path.with_extension("partial");
+        let mut output = std::fs::File::create(&partial_path).map_err(internal_error)?;
+
+        let limits = self.limits()?;
+        let block_get = traced("block_get", &cid, async {
+            let stream = self
+                .timeouts
+                .stream(self.client.block_get(&format!("/ipfs/{}", cid)));
+            let mut stream = limits.download(stream);
+            let mut bytes = 0;
+            while let Some(res) = stream.next().await {
+                let chunk = res.map_err(stream_error)?;
+                output.write_all(&chunk).map_err(internal_error)?;
+                progress.advance(chunk.len() as u64);
+                bytes += chunk.len();
+            }
+            Ok::<_, Error>(bytes)
+        });
+        let bytes = match self.timeouts.object(block_get).await.map_err(timed_out) {
+            Ok(Ok(bytes)) => bytes,
+            Ok(Err(err)) | Err(err) => {
+                let _ = std::fs::remove_file(&partial_path);
+                return Err(err);
+            }
+        };
+        std::fs::rename(&partial_path, &output_path).map_err(internal_error)?;
+        info!(%cid, bytes, "downloaded");
+        Ok(output_path)
+    }
+}
+
+fn internal_error(err: impl std::fmt::Display) -> Error {
+    error!("{}", err);
+    Error {
+        code: INTERNAL_SERVER_ERROR,
+        message: err.to_string(),
+    }
+}
+
+fn not_found(cid: &str) -> Error {
+    warn!(cid, "neither on the remote nor in IPFS");
+    Error {
+        code: NOT_FOUND,
+        message: format!("{} is neither on the remote nor in IPFS", cid),
+    }
+}
+
+/// Ask git-lfs to try the object again when IPFS gave up on it
+fn timed_out(err: TimedOut) -> Error {
+    warn!(%err, "timed out");
+    Error {
+        code: GATEWAY_TIMEOUT,
+        message: format!("{}, try again later", err),
+    }
+}
+
+fn stream_error(err: anyhow::Error) -> Error {
+    match err.downcast::<TimedOut>() {
+        Ok(err) => timed_out(err),
+        Err(err) => internal_error(err),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::File, io::Read};
+    use std::{fs::File, time::Duration};
 
     use super::*;
     use crate::ipfs::client;
-    use git_lfs_spec::{
-        transfer::custom::{Download, Event, Init, Result, Upload},
-        Object,
+    use git_lfs_spec::transfer::custom::{
+        agent::run, AcknowledgeInit, Complete, Event, Message, Progress, Result,
     };
     use pretty_assertions::assert_eq;
     use tempfile::tempdir;
@@ -123,23 +549,18 @@ mod tests {
     const FILE: &[u8] = b"hello world";
     const OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
     const SIZE: u64 = FILE.len() as u64;
+    /// What clean makes of [FILE], which is what is uploaded for it
+    const RAW_BLOCK: &[u8] = include_bytes!("../test/hello_world_raw_block");
+    const RAW_BLOCK_OID: &str = "f852c7fa62f971817f54d8a80dcd63fcf7098b3cbde9ae8ec1ee449013ec5db0";
 
-    #[tokio::test]
-    async fn read_events_parses_event_successfully() {
-        let init = Event::Init(Init {
-            operation: Operation::Download,
-            remote: "origin".to_string(),
-            concurrent: true,
-            concurrenttransfers: Some(3),
-        });
-        let input: &[u8] = br#"{"event":"init","operation":"download","remote":"origin","concurrent":true,"concurrenttransfers":3}"#;
-        let stream = read_events(input);
-        futures::pin_mut!(stream);
-        let mut events = vec![];
-        while let Some(output) = stream.next().await {
-            events.push(output.unwrap());
-        }
-        assert_eq!(events, &[init]);
+    async fn run_transcript(agent: &impl Agent, input: &str) -> Vec<Message> {
+        let mut output = vec![];
+        run(agent, input.as_bytes(), &mut output).await.unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
     }
 
     #[tokio::test]
@@ -149,27 +570,14 @@ mod tests {
 
         let expected_output_path = temp_dir.path().join(OID);
 
-        let client = client();
-        let input_events = [
-            Event::Init(Init {
-                operation: Operation::Download,
-                remote: "origin".to_string(),
-                concurrent: true,
-                concurrenttransfers: Some(3),
-            }),
-            Event::Download(
-                Download {
-                    object: Object {
-                        oid: OID.to_string(),
-                        size: SIZE,
-                    },
-                }
-                .into(),
-            ),
-            Event::Terminate,
-        ];
-        let expected_output_events = [
-            Event::AcknowledgeInit,
+        let input = format!(
+            "{}\n{}\n{}\n",
+            r#"{"event":"init","operation":"download","remote":"origin","concurrent":true,"concurrenttransfers":3}"#,
+            format_args!(r#"{{"event":"download","oid":"{}","size":{}}}"#, OID, SIZE),
+            r#"{"event":"terminate"}"#,
+        );
+        let expected_output_messages: Vec<Message> = vec![
+            AcknowledgeInit::default().into(),
             Event::Progress(
                 Progress {
                     oid: OID.to_string(),
@@ -177,26 +585,22 @@ mod tests {
                     bytes_since_last: SIZE,
                 }
                 .into(),
-            ),
+            )
+            .into(),
             Event::Complete(
                 Complete {
                     oid: OID.to_string(),
                     result: Some(Result::Path(expected_output_path.clone())),
                 }
                 .into(),
-            ),
+            )
+            .into(),
         ];
-        let output_stream = transfer(
-            client,
-            futures::stream::iter(input_events.iter().cloned().map(anyhow::Result::Ok)),
-            temp_dir.path(),
+        let agent = IpfsAgent::new(client(), temp_dir.path());
+        assert_eq!(
+            run_transcript(&agent, &input).await,
+            expected_output_messages
         );
-        futures_util::pin_mut!(output_stream);
-        let expected_output_stream = futures::stream::iter(expected_output_events.iter().cloned());
-        let mut actual_and_expected_output_stream = output_stream.zip(expected_output_stream);
-        while let Some((actual, expected)) = actual_and_expected_output_stream.next().await {
-            assert_eq!(actual.unwrap(), expected);
-        }
 
         let mut actual_file = Vec::with_capacity(FILE.len());
 
@@ -212,51 +616,151 @@ mod tests {
     #[ignore]
     async fn transfer_handles_events_as_expected_for_upload() {
         let temp_dir = tempdir().unwrap();
-        let temp_file = temp_dir.path().join(OID);
-        std::fs::File::create(&temp_file).unwrap();
-
-        let client = client();
-        let input_events = [
-            Event::Init(Init {
-                operation: Operation::Upload,
-                remote: "origin".to_string(),
-                concurrent: true,
-                concurrenttransfers: Some(3),
-            }),
-            Event::Upload(
-                Upload {
-                    object: Object {
-                        oid: OID.to_string(),
-                        size: SIZE,
-                    },
-                    path: temp_file.clone(),
+        let temp_file = temp_dir.path().join(RAW_BLOCK_OID);
+        std::fs::write(&temp_file, RAW_BLOCK).unwrap();
+
+        let input = format!(
+            "{}\n{}\n{}\n",
+            r#"{"event":"init","operation":"upload","remote":"origin","concurrent":true,"concurrenttransfers":3}"#,
+            format_args!(
+                r#"{{"event":"upload","oid":"{}","size":{},"path":"{}"}}"#,
+                RAW_BLOCK_OID,
+                RAW_BLOCK.len(),
+                temp_file.display()
+            ),
+            r#"{"event":"terminate"}"#,
+        );
+        let expected_output_messages: Vec<Message> = vec![
+            AcknowledgeInit::default().into(),
+            Event::Progress(
+                Progress {
+                    oid: RAW_BLOCK_OID.to_string(),
+                    bytes_so_far: RAW_BLOCK.len() as u64,
+                    bytes_since_last: RAW_BLOCK.len() as u64,
                 }
                 .into(),
-            ),
-            Event::Terminate,
-        ];
-        let expected_output_events = [
-            Event::AcknowledgeInit,
+            )
+            .into(),
             Event::Complete(
                 Complete {
-                    oid: OID.to_string(),
+                    oid: RAW_BLOCK_OID.to_string(),
                     result: None,
                 }
                 .into(),
-            ),
+            )
+            .into(),
         ];
-        let output_stream = transfer(
-            client,
-            futures::stream::iter(input_events.iter().cloned().map(anyhow::Result::Ok)),
-            temp_dir.path(),
+        let agent = IpfsAgent::new(client(), temp_dir.path());
+        assert_eq!(
+            run_transcript(&agent, &input).await,
+            expected_output_messages
         );
-        futures_util::pin_mut!(output_stream);
-        let expected_output_stream = futures::stream::iter(expected_output_events.iter().cloned());
-        let mut actual_and_expected_output_stream = output_stream.zip(expected_output_stream);
-        while let Some((actual, expected)) = actual_and_expected_output_stream.next().await {
-            assert_eq!(actual.unwrap(), expected);
-        }
 
         std::fs::remove_file(temp_file).unwrap();
     }
+
+    #[tokio::test]
+    async fn transfer_fails_download_of_invalid_oid() {
+        let temp_dir = tempdir().unwrap();
+        let input = format!(
+            "{}\n{}\n{}\n",
+            r#"{"event":"init","operation":"download","remote":"origin","concurrent":true,"concurrenttransfers":3}"#,
+            r#"{"event":"download","oid":"not-a-sha256","size":11}"#,
+            r#"{"event":"terminate"}"#,
+        );
+        let agent = IpfsAgent::new(client(), temp_dir.path());
+        let messages = run_transcript(&agent, &input).await;
+        assert_eq!(messages.len(), 2);
+        match &messages[1] {
+            Message::Event(Event::Complete(complete)) => {
+                assert_eq!(complete.oid, "not-a-sha256");
+                assert!(matches!(
+                    complete.result,
+                    Some(Result::Error(Error {
+                        code: INTERNAL_SERVER_ERROR,
+                        ..
+                    }))
+                ));
+            }
+            other => panic!("expected complete event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_downloads_fail_with_a_retryable_error() {
+        let timeouts = Timeouts::new(None, Some(Duration::from_millis(10)));
+        let mut stream = timeouts.stream(futures::stream::pending::<
+            std::result::Result<Vec<u8>, std::io::Error>,
+        >());
+        let err = stream_error(stream.next().await.unwrap().unwrap_err());
+        assert_eq!(err.code, GATEWAY_TIMEOUT);
+        assert_eq!(err.message, "IPFS sent nothing for 10ms, try again later");
+
+        let err = stream_error(anyhow::anyhow!("connection reset"));
+        assert_eq!(err.code, INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn transfer_sends_plain_objects_to_the_remote() {
+        let temp_dir = tempdir().unwrap();
+        let temp_file = temp_dir.path().join(OID);
+        std::fs::write(&temp_file, FILE).unwrap();
+        let input = format!(
+            "{}\n{}\n{}\n",
+            r#"{"event":"init","operation":"upload","remote":"no-such-remote","concurrent":false}"#,
+            format_args!(
+                r#"{{"event":"upload","oid":"{}","size":{},"path":"{}"}}"#,
+                OID,
+                SIZE,
+                temp_file.display()
+            ),
+            r#"{"event":"terminate"}"#,
+        );
+        let agent = IpfsAgent::new(client(), temp_dir.path());
+        match run_transcript(&agent, &input).await.last() {
+            Some(Message::Event(Event::Complete(complete))) => match &complete.result {
+                Some(Result::Error(error)) => {
+                    assert!(
+                        error.message.contains("no-such-remote"),
+                        "{}",
+                        error.message
+                    )
+                }
+                other => panic!("expected an error, got {:?}", other),
+            },
+            other => panic!("expected complete event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_rejects_upload_that_does_not_match_its_oid() {
+        let temp_dir = tempdir().unwrap();
+        let temp_file = temp_dir.path().join(OID);
+        std::fs::write(&temp_file, b"goodbye world").unwrap();
+        let input = format!(
+            "{}\n{}\n{}\n",
+            r#"{"event":"init","operation":"upload","remote":"origin","concurrent":true,"concurrenttransfers":3}"#,
+            format_args!(
+                r#"{{"event":"upload","oid":"{}","size":{},"path":"{}"}}"#,
+                OID,
+                SIZE,
+                temp_file.display()
+            ),
+            r#"{"event":"terminate"}"#,
+        );
+        let agent = IpfsAgent::new(client(), temp_dir.path());
+        let messages = run_transcript(&agent, &input).await;
+        match messages.last() {
+            Some(Message::Event(Event::Complete(complete))) => {
+                assert!(matches!(
+                    complete.result,
+                    Some(Result::Error(Error {
+                        code: BAD_REQUEST,
+                        ..
+                    }))
+                ));
+            }
+            other => panic!("expected complete event, got {:?}", other),
+        }
+    }
 }
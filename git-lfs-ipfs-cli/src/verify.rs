@@ -0,0 +1,354 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    future::Future,
+    io::Write,
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures::StreamExt;
+use git_lfs_spec::Object;
+use ipfs_api_backend_hyper::IpfsApi;
+use multihash::{Hasher, Sha2_256};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    encryption::{self, Key},
+    index::{self, Index},
+    ipfs::{recursive_pins, traced},
+    migrate::list_objects,
+    pins::Pins,
+};
+
+/// What is wrong with an object, from least to most serious
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Retrievable, but the local node could drop it
+    Unpinned,
+    /// The root block is there, but not all of the content
+    Incomplete,
+    /// The content doesn't match the pointer
+    Corrupt,
+    /// Not found in IPFS, or no CID is known for it
+    Missing,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Unpinned => "unpinned",
+            Self::Incomplete => "incomplete",
+            Self::Corrupt => "corrupt",
+            Self::Missing => "missing",
+        }
+    }
+
+    /// Exit code of `verify` when this is the most serious status
+    ///
+    /// 1 is left for verify itself failing, and unpinned objects get their own code so
+    /// that CI can choose to tolerate them.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Ok => 0,
+            Self::Unpinned => 2,
+            Self::Incomplete | Self::Corrupt | Self::Missing => 3,
+        }
+    }
+}
+
+/// One line of the report
+#[derive(PartialEq, Eq, Debug, Serialize)]
+struct Verified<'a> {
+    oid: &'a str,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cid: Option<String>,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Verified<'_> {
+    fn human(&self) -> String {
+        let mut line = format!(
+            "{:<10} {} {}",
+            self.status.name(),
+            self.oid,
+            self.cid.as_deref().unwrap_or("-")
+        );
+        if let Some(error) = &self.error {
+            line.push_str(&format!(" ({})", error));
+        }
+        line
+    }
+}
+
+/// Check that the LFS objects of `refs` can be retrieved in full from IPFS, match their
+/// pointers and are pinned, in the repository's [Pins] or recursively
+///
+/// Prints problems and a summary, or a line of JSON per object, and returns the exit
+/// code for the most serious [Status].
+pub async fn verify<E: 'static + Send + Sync + std::error::Error>(
+    client: impl IpfsApi<Error = E> + Send + Sync,
+    refs: &[String],
+    json: bool,
+    timeout: Duration,
+    mut report: impl Write,
+) -> Result<i32> {
+    let index = Index::for_oid_mode()?;
+    let key = Key::from_git_config()?;
+    let objects = list_objects(refs)?;
+    let mut pinned = within(timeout, recursive_pins(&client))
+        .await
+        .map_err(anyhow::Error::msg)?;
+    let pins = Pins::from_git_dir()?;
+    pinned.extend(
+        within(timeout, pins.list(&client))
+            .await
+            .map_err(anyhow::Error::msg)?,
+    );
+
+    let mut counts = BTreeMap::new();
+    for object in &objects {
+        let cid = index::cid(index.as_ref(), &object.oid)?.map(|cid| cid.to_string());
+        let (status, error) = match &cid {
+            Some(cid) => {
+                let stock = index.is_some();
+                match check(&client, object, cid, stock, key.as_ref(), timeout, &pinned).await {
+                    Ok(status) => (status, None),
+                    Err((status, error)) => (status, Some(error)),
+                }
+            }
+            None => (Status::Missing, Some("no CID in the index".to_string())),
+        };
+        if status != Status::Ok {
+            warn!(oid = %object.oid, ?status, error, "verification failed");
+        }
+        *counts.entry(status).or_insert(0) += 1;
+
+        let line = Verified {
+            oid: &object.oid,
+            size: object.size,
+            cid,
+            status,
+            error,
+        };
+        if json {
+            serde_json::to_writer(&mut report, &line)?;
+            writeln!(report)?;
+        } else if status != Status::Ok {
+            writeln!(report, "{}", line.human())?;
+        }
+    }
+    if !json {
+        writeln!(report, "{}", summary(objects.len(), &counts))?;
+    }
+    report.flush()?;
+
+    Ok(counts
+        .keys()
+        .max()
+        .copied()
+        .unwrap_or(Status::Ok)
+        .exit_code())
+}
+
+/// Fetch an object's root block, then all of its content, then check that it is among
+/// the `pinned` CIDs
+async fn check<E: 'static + Send + Sync + std::error::Error>(
+    client: &(impl IpfsApi<Error = E> + Send + Sync),
+    object: &Object,
+    cid: &str,
+    stock: bool,
+    key: Option<&Key>,
+    timeout: Duration,
+    pinned: &HashSet<String>,
+) -> Result<Status, (Status, String)> {
+    let path = format!("/ipfs/{}", cid);
+    let root = within(
+        timeout,
+        traced("block_get", cid, collect(client.block_get(&path))),
+    )
+    .await
+    .map_err(|err| (Status::Missing, err))?;
+    // The root block is the LFS object itself, unless oids are of files
+    if !stock {
+        if root.len() as u64 != object.size {
+            return Err((
+                Status::Corrupt,
+                format!("block is {} bytes, not {}", root.len(), object.size),
+            ));
+        }
+        if sha256(&root) != object.oid {
+            return Err((Status::Corrupt, "block does not match its oid".to_string()));
+        }
+    }
+
+    let stat = within(timeout, traced("files_stat", cid, client.files_stat(&path)))
+        .await
+        .map_err(|err| (Status::Corrupt, err))?;
+    let incomplete = |size: u64| {
+        Err((
+            Status::Incomplete,
+            format!("content is {} bytes, not {}", size, stat.size),
+        ))
+    };
+    let corrupt = || Err((Status::Corrupt, "file does not match its oid".to_string()));
+    if stock && key.is_some() {
+        // Decrypting needs all of the object
+        let content = within(timeout, traced("cat", cid, collect(client.cat(&path))))
+            .await
+            .map_err(|err| (Status::Incomplete, err))?;
+        if content.len() as u64 != stat.size {
+            return incomplete(content.len() as u64);
+        }
+        let file = encryption::decrypt(key, &content)
+            .map_err(|err| (Status::Corrupt, format!("{:#}", err)))?;
+        if file.len() as u64 != object.size || sha256(&file) != object.oid {
+            return corrupt();
+        }
+    } else {
+        let (size, digest) = within(timeout, traced("cat", cid, digest(client.cat(&path))))
+            .await
+            .map_err(|err| (Status::Incomplete, err))?;
+        if size != stat.size {
+            return incomplete(size);
+        }
+        if stock && (size != object.size || digest != object.oid) {
+            return corrupt();
+        }
+    }
+
+    if !pinned.contains(cid) {
+        return Err((Status::Unpinned, "not pinned".to_string()));
+    }
+    Ok(Status::Ok)
+}
+
+/// Wait for an IPFS request, giving up after `timeout`
+async fn within<T, E: std::fmt::Display>(
+    timeout: Duration,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, String> {
+    match tokio::time::timeout(timeout, future).await {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err(format!("timed out after {}s", timeout.as_secs())),
+    }
+}
+
+async fn collect<E>(
+    mut stream: impl futures::Stream<Item = Result<impl AsRef<[u8]>, E>> + Unpin,
+) -> Result<Vec<u8>, E> {
+    let mut bytes = vec![];
+    while let Some(chunk) = stream.next().await {
+        bytes.extend_from_slice(chunk?.as_ref());
+    }
+    Ok(bytes)
+}
+
+/// Size and SHA-256 of a stream, which is never held in memory
+async fn digest<E>(
+    mut stream: impl futures::Stream<Item = Result<impl AsRef<[u8]>, E>> + Unpin,
+) -> Result<(u64, String), E> {
+    let mut hasher = Sha2_256::default();
+    let mut size = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        hasher.update(chunk.as_ref());
+        size += chunk.as_ref().len() as u64;
+    }
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+fn sha256(data: &[u8]) -> String {
+    let mut hasher = Sha2_256::default();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+fn summary(total: usize, counts: &BTreeMap<Status, usize>) -> String {
+    let counts = counts
+        .iter()
+        .map(|(status, count)| format!("{} {}", count, status.name()))
+        .collect::<Vec<_>>();
+    if counts.is_empty() {
+        return format!("{} objects", total);
+    }
+    format!("{} objects: {}", total, counts.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+    const CID: &str = "QmaozNR7DZHQK1ZcU9p7QdrshMvXqWK6gpu5rmrkPdT3L4";
+
+    #[test]
+    fn statuses_are_ordered_by_exit_code() {
+        let statuses = [
+            Status::Ok,
+            Status::Unpinned,
+            Status::Incomplete,
+            Status::Corrupt,
+            Status::Missing,
+        ];
+        assert!(statuses.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(statuses.map(Status::exit_code), [0, 2, 3, 3, 3]);
+        for status in statuses {
+            assert_eq!(
+                serde_json::to_string(&status).unwrap(),
+                format!(r#""{}""#, status.name())
+            );
+        }
+    }
+
+    #[test]
+    fn report_lines_are_human_or_json() {
+        let line = Verified {
+            oid: OID,
+            size: 11,
+            cid: Some(CID.to_string()),
+            status: Status::Missing,
+            error: Some("timed out after 60s".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_string(&line).unwrap(),
+            format!(
+                r#"{{"oid":"{}","size":11,"cid":"{}","status":"missing","error":"timed out after 60s"}}"#,
+                OID, CID
+            )
+        );
+        assert_eq!(
+            line.human(),
+            format!("missing    {} {} (timed out after 60s)", OID, CID)
+        );
+    }
+
+    #[test]
+    fn summary_counts_statuses() {
+        let counts = BTreeMap::from([
+            (Status::Ok, 10),
+            (Status::Unpinned, 1),
+            (Status::Missing, 2),
+        ]);
+        assert_eq!(
+            summary(13, &counts),
+            "13 objects: 10 ok, 1 unpinned, 2 missing"
+        );
+        assert_eq!(summary(0, &BTreeMap::new()), "0 objects");
+    }
+
+    #[tokio::test]
+    async fn digest_hashes_the_whole_stream() {
+        let chunks =
+            futures::stream::iter([Ok::<_, std::io::Error>(&b"hello"[..]), Ok(&b" world"[..])]);
+        assert_eq!(digest(chunks).await.unwrap(), (11, OID.to_string()));
+    }
+}
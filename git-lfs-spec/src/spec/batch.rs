@@ -1,23 +1,23 @@
 use chrono::{DateTime, FixedOffset};
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
 use url::Url;
 
 use crate::spec::Object;
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md#requests
-#[derive(PartialEq, Eq, Debug, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct BatchRequest {
     pub operation: Operation,
     #[serde(default = "Transfer::default_vec")]
     pub transfer: Vec<Transfer>,
-    #[serde(rename = "ref")]
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
     pub ref_property: Option<Ref>,
     pub objects: Vec<Object>,
 }
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md#successful-responses
-#[derive(PartialEq, Eq, Debug, Serialize)]
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct BatchResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub transfer: Option<Transfer>,
@@ -48,26 +48,30 @@ impl Transfer {
 }
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md#ref-property
-#[derive(PartialEq, Eq, Debug, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
 pub struct Ref {
     pub name: String,
 }
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md#successful-responses
-#[derive(PartialEq, Eq, Debug, Serialize)]
+///
+/// Errors come first so that deserializing doesn't mistake them for successes without
+/// actions, which servers send for objects that need no transfer.
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum ObjectResponse {
-    Success {
+    Error {
         #[serde(flatten)]
         object: Object,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        authenticated: Option<bool>,
-        actions: Box<Actions>,
+        error: ObjectError,
     },
-    Error {
+    Success {
         #[serde(flatten)]
         object: Object,
-        error: ObjectError,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        authenticated: Option<bool>,
+        #[serde(default)]
+        actions: Box<Actions>,
     },
 }
 
@@ -94,54 +98,80 @@ pub struct ObjectSuccess {
 }
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md#response-errors
-#[derive(PartialEq, Eq, Debug, Serialize)]
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
 pub struct ObjectError {
     code: u16,
-    message: &'static str,
+    message: String,
 }
 
 impl ObjectError {
-    pub fn DoesNotExist() -> Self {
+    pub fn new(code: u16, message: impl Into<String>) -> Self {
         Self {
-            code: 404u16,
-            message: "Object does not exist",
+            code,
+            message: message.into(),
         }
     }
 
-    pub fn RemovedByOwner() -> Self {
-        Self {
-            code: 410u16,
-            message: "Object removed by owner",
-        }
+    pub fn does_not_exist() -> Self {
+        Self::new(404, "Object does not exist")
     }
-    pub fn ValidationError() -> Self {
-        Self {
-            code: 422u16,
-            message: "Validation error",
-        }
+
+    pub fn removed_by_owner() -> Self {
+        Self::new(410, "Object removed by owner")
+    }
+
+    pub fn validation_error() -> Self {
+        Self::new(422, "Validation error")
+    }
+
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
     }
 }
 
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ObjectError {}
+
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/api/basic-transfers.md#basic-transfer-api
-#[derive(PartialEq, Eq, Debug, Serialize)]
+///
+/// Upload with verify comes before upload so that deserializing doesn't drop the
+/// verify action.
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Default)]
 #[serde(untagged)]
 pub enum Actions {
-    Download { download: Action },
+    Download {
+        download: Action,
+    },
+    UploadAndVerify {
+        upload: Action,
+        verify: Action,
+    },
+    Upload {
+        upload: Action,
+    },
+    #[default]
     None,
-    Upload { upload: Action },
-    UploadAndVerify { upload: Action, verify: Action },
 }
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/api/basic-transfers.md#basic-transfer-api
-#[derive(PartialEq, Eq, Debug, Serialize)]
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
 pub struct Action {
     #[serde(with = "url_serde")]
     href: Url,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     header: Option<HashMap<String, String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     expires_in: Option<i32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     expires_at: Option<DateTime<FixedOffset>>,
 }
 
@@ -154,51 +184,124 @@ impl Action {
             expires_at: None,
         }
     }
+
+    pub fn with_header(mut self, header: HashMap<String, String>) -> Self {
+        self.header = Some(header);
+        self
+    }
+
+    pub fn href(&self) -> &Url {
+        &self.href
+    }
+
+    pub fn header(&self) -> Option<&HashMap<String, String>> {
+        self.header.as_ref()
+    }
 }
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md#response-errors
-#[derive(PartialEq, Eq, Debug, Serialize)]
+///
+/// The HTTP status is not part of the body: responses parsed from JSON have a status
+/// of 0 until one is set with [LfsErrorResponse::with_status].
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
 pub struct LfsErrorResponse {
-    message: &'static str,
-    #[serde(with = "url_serde")]
+    message: String,
+    #[serde(with = "url_serde", default)]
     documentation_url: Option<Url>,
+    #[serde(default)]
     request_id: Option<String>,
     #[serde(skip)]
     status: u16,
 }
 
 impl LfsErrorResponse {
-    const ACCEPT_HEADER_INCORRECT: Self = Self {
-        message: "The Accept header needs to be `application/vnd.git-lfs+json`.",
-        documentation_url: None,
-        request_id: None,
-        status: 406u16,
-    };
-    const RATE_LIMIT_HIT: Self = Self {
-        message: "A rate limit has been hit with the server.",
-        documentation_url: None,
-        request_id: None,
-        status: 429u16,
-    };
-    const NOT_IMPLEMENTED: Self = Self {
-        message: "The server has not implemented the current method.",
-        documentation_url: None,
-        request_id: None,
-        status: 501u16,
-    };
-    const INSUFFICIENT_STORAGE: Self = Self {
-        message: "The server has insufficient storage capacity to complete the request.",
-        documentation_url: None,
-        request_id: None,
-        status: 507u16,
-    };
-
-    const BANDWIDTH_LIMIT_EXCEEDED: Self = Self {
-        message: "A bandwidth limit has been exceeded.",
-        documentation_url: None,
-        request_id: None,
-        status: 509u16,
-    };
+    pub fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            documentation_url: None,
+            request_id: None,
+            status,
+        }
+    }
+
+    pub fn accept_header_incorrect() -> Self {
+        Self::new(
+            406,
+            "The Accept header needs to be `application/vnd.git-lfs+json`.",
+        )
+    }
+
+    pub fn rate_limit_hit() -> Self {
+        Self::new(429, "A rate limit has been hit with the server.")
+    }
+
+    pub fn not_implemented() -> Self {
+        Self::new(501, "The server has not implemented the current method.")
+    }
+
+    pub fn insufficient_storage() -> Self {
+        Self::new(
+            507,
+            "The server has insufficient storage capacity to complete the request.",
+        )
+    }
+
+    pub fn bandwidth_limit_exceeded() -> Self {
+        Self::new(509, "A bandwidth limit has been exceeded.")
+    }
+
+    /// Wrap any other server error as a 500 response
+    ///
+    /// Handy with `map_err` for errors that have no more specific status.
+    pub fn internal_server_error(err: impl fmt::Display) -> Self {
+        Self::new(500, err.to_string())
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_documentation_url(mut self, documentation_url: Url) -> Self {
+        self.documentation_url = Some(documentation_url);
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    /// HTTP status code the response should be sent with
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn documentation_url(&self) -> Option<&Url> {
+        self.documentation_url.as_ref()
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+}
+
+impl fmt::Display for LfsErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.status)
+    }
+}
+
+impl std::error::Error for LfsErrorResponse {}
+
+impl From<ObjectError> for LfsErrorResponse {
+    fn from(err: ObjectError) -> Self {
+        Self::new(err.code, err.message)
+    }
 }
 
 #[cfg(test)]
@@ -243,7 +346,7 @@ mod test {
             serde_json::to_string_pretty(&BatchResponse {
                 transfer: Some(Transfer::Basic),
                 objects: vec![ObjectResponse::Error {
-                    error: ObjectError::DoesNotExist(),
+                    error: ObjectError::does_not_exist(),
                     object: Object {
                         oid: "1111111".to_string(),
                         size: 123,
@@ -254,19 +357,107 @@ mod test {
         );
     }
 
+    #[test]
+    fn batch_response_deserializes_correctly() {
+        let response: BatchResponse =
+            serde_json::from_str(include_str!("test/batch_response_error.json")).unwrap();
+        assert_eq!(
+            response.objects,
+            vec![ObjectResponse::error(
+                Object {
+                    oid: "1111111".to_string(),
+                    size: 123,
+                },
+                ObjectError::does_not_exist()
+            )]
+        );
+
+        let response: BatchResponse =
+            serde_json::from_str(include_str!("test/batch_response_success.json")).unwrap();
+        match &response.objects[..] {
+            [ObjectResponse::Success { actions, .. }] => match actions.as_ref() {
+                Actions::Download { download } => {
+                    assert_eq!(download.href().as_str(), "https://some-download.com/");
+                    assert_eq!(download.header().unwrap()["Key"], "value");
+                }
+                other => panic!("expected download action, got {:?}", other),
+            },
+            other => panic!("expected one success, got {:?}", other),
+        }
+
+        let response: BatchResponse = serde_json::from_str(
+            r#"{"objects":[
+                {"oid":"1","size":1},
+                {"oid":"2","size":2,"actions":{
+                    "upload":{"href":"https://lfs-server.com/upload"},
+                    "verify":{"href":"https://lfs-server.com/verify"}
+                }}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(response.transfer, None);
+        assert!(matches!(
+            response.objects[0],
+            ObjectResponse::Success { ref actions, .. } if **actions == Actions::None
+        ));
+        assert!(matches!(
+            response.objects[1],
+            ObjectResponse::Success { ref actions, .. }
+                if matches!(**actions, Actions::UploadAndVerify { .. })
+        ));
+    }
+
+    #[test]
+    fn batch_request_serializes_correctly() {
+        let request = BatchRequest {
+            operation: Operation::Download,
+            transfer: vec![Transfer::Basic],
+            ref_property: None,
+            objects: vec![Object {
+                oid: "1111111".to_string(),
+                size: 123,
+            }],
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"operation":"download","transfer":["basic"],"objects":[{"oid":"1111111","size":123}]}"#
+        );
+    }
+
     #[test]
     fn lfs_error_serializes_correctly() {
         assert_eq!(
             include_str!("test/lfs_error.json"),
-            serde_json::to_string_pretty(&LfsErrorResponse {
-                message: "Not found",
-                documentation_url: Url::parse("https://lfs-server.com/docs/errors")
-                    .unwrap()
-                    .into(),
-                request_id: Some("123".to_string()),
-                status: 404u16
-            })
+            serde_json::to_string_pretty(
+                &LfsErrorResponse::new(404, "Not found")
+                    .with_documentation_url(
+                        Url::parse("https://lfs-server.com/docs/errors").unwrap()
+                    )
+                    .with_request_id("123")
+            )
             .unwrap(),
         );
     }
+
+    #[test]
+    fn lfs_error_deserializes_correctly() {
+        let error: LfsErrorResponse =
+            serde_json::from_str(include_str!("test/lfs_error.json")).unwrap();
+        assert_eq!(error.message(), "Not found");
+        assert_eq!(error.request_id(), Some("123"));
+        assert_eq!(error.status(), 0);
+        assert_eq!(
+            error.with_status(404),
+            LfsErrorResponse::new(404, "Not found")
+                .with_documentation_url(Url::parse("https://lfs-server.com/docs/errors").unwrap())
+                .with_request_id("123")
+        );
+    }
+
+    #[test]
+    fn object_error_converts_into_lfs_error() {
+        let error: LfsErrorResponse = ObjectError::does_not_exist().into();
+        assert_eq!(error.status(), 404);
+        assert_eq!(error.message(), "Object does not exist");
+    }
 }
@@ -0,0 +1,187 @@
+use chrono::{DateTime, FixedOffset};
+use serde_derive::{Deserialize, Serialize};
+use url::Url;
+
+use crate::spec::batch::Ref;
+
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#create-lock
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+pub struct CreateLockRequest {
+    pub path: String,
+    #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+    pub ref_property: Option<Ref>,
+}
+
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#create-lock
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+pub struct Lock {
+    pub id: String,
+    pub path: String,
+    pub locked_at: DateTime<FixedOffset>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Owner>,
+}
+
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#create-lock
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+pub struct Owner {
+    pub name: String,
+}
+
+/// Successful response to creating or deleting a lock
+///
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#delete-lock
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+pub struct LockResponse {
+    pub lock: Lock,
+}
+
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#create-lock
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+pub struct LockConflict {
+    pub lock: Lock,
+    pub message: String,
+    #[serde(with = "url_serde", default, skip_serializing_if = "Option::is_none")]
+    pub documentation_url: Option<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Query string of a list locks request
+///
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#list-locks
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ListLocksQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refspec: Option<String>,
+}
+
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#list-locks
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+pub struct ListLocksResponse {
+    pub locks: Vec<Lock>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#list-locks-for-verification
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone, Default)]
+pub struct VerifyLocksRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+    pub ref_property: Option<Ref>,
+}
+
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#list-locks-for-verification
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+pub struct VerifyLocksResponse {
+    pub ours: Vec<Lock>,
+    pub theirs: Vec<Lock>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/api/locking.md#delete-lock
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DeleteLockRequest {
+    #[serde(default)]
+    pub force: bool,
+    #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+    pub ref_property: Option<Ref>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn lock() -> Lock {
+        Lock {
+            id: "some-uuid".to_string(),
+            path: "foo/bar.zip".to_string(),
+            locked_at: DateTime::parse_from_rfc3339("2016-05-17T15:49:06+00:00").unwrap(),
+            owner: Some(Owner {
+                name: "Jane Doe".to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn create_lock_request_deserializes_correctly() {
+        assert_eq!(
+            serde_json::from_str::<CreateLockRequest>(include_str!(
+                "test/locks_create_request.json"
+            ))
+            .unwrap(),
+            CreateLockRequest {
+                path: "foo/bar.zip".to_string(),
+                ref_property: Some(Ref {
+                    name: "refs/heads/my-feature".to_string()
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn lock_response_serializes_correctly() {
+        assert_eq!(
+            include_str!("test/locks_lock_response.json"),
+            serde_json::to_string_pretty(&LockResponse { lock: lock() }).unwrap()
+        );
+    }
+
+    #[test]
+    fn list_locks_response_serializes_correctly() {
+        assert_eq!(
+            include_str!("test/locks_list_response.json"),
+            serde_json::to_string_pretty(&ListLocksResponse {
+                locks: vec![lock()],
+                next_cursor: Some("optional next ID".to_string()),
+            })
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn verify_locks_response_round_trips() {
+        let response: VerifyLocksResponse =
+            serde_json::from_str(include_str!("test/locks_verify_response.json")).unwrap();
+        assert_eq!(
+            response,
+            VerifyLocksResponse {
+                ours: vec![lock()],
+                theirs: vec![],
+                next_cursor: Some("optional next ID".to_string()),
+            }
+        );
+        assert_eq!(
+            include_str!("test/locks_verify_response.json"),
+            serde_json::to_string_pretty(&response).unwrap()
+        );
+    }
+
+    #[test]
+    fn delete_lock_request_defaults_force_to_false() {
+        assert_eq!(
+            serde_json::from_str::<DeleteLockRequest>(r#"{"ref":{"name":"refs/heads/main"}}"#)
+                .unwrap(),
+            DeleteLockRequest {
+                force: false,
+                ref_property: Some(Ref {
+                    name: "refs/heads/main".to_string()
+                }),
+            }
+        );
+    }
+}
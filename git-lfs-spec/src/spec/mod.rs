@@ -1,6 +1,8 @@
 use serde_derive::{Deserialize, Serialize};
 
 pub mod batch;
+pub mod locks;
+pub mod pkt_line;
 pub mod transfer;
 
 #[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
@@ -0,0 +1,183 @@
+use std::io::{self, Read, Write};
+
+/// Largest payload a single data packet can carry
+pub const MAX_DATA_LEN: usize = 65516;
+
+const HEADER_LEN: usize = 4;
+
+/// https://git-scm.com/docs/protocol-common#_pkt_line_format
+///
+/// Shared by the SSH transfer protocol and git's long-running filter protocol.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum PktLine {
+    Data(Vec<u8>),
+    /// `0000`
+    Flush,
+    /// `0001`
+    Delim,
+}
+
+impl PktLine {
+    /// A text packet, terminated with a newline as git does
+    pub fn text(line: impl AsRef<str>) -> Self {
+        let mut data = line.as_ref().as_bytes().to_vec();
+        data.push(b'\n');
+        PktLine::Data(data)
+    }
+
+    /// Contents of a text packet without its trailing newline
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            PktLine::Data(data) => {
+                let data = data.strip_suffix(b"\n").unwrap_or(data);
+                std::str::from_utf8(data).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Read the next packet, or `None` if the stream ended cleanly between packets
+pub fn read_pkt_line(reader: &mut impl Read) -> io::Result<Option<PktLine>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut filled = 0;
+    while filled < HEADER_LEN {
+        match reader.read(&mut header[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    let len = std::str::from_utf8(&header)
+        .ok()
+        .and_then(|header| usize::from_str_radix(header, 16).ok())
+        .ok_or_else(|| invalid_data(format!("invalid pkt-line length {:?}", header)))?;
+    match len {
+        0 => Ok(Some(PktLine::Flush)),
+        1 => Ok(Some(PktLine::Delim)),
+        2..=HEADER_LEN => Err(invalid_data(format!("invalid pkt-line length {}", len))),
+        _ if len - HEADER_LEN > MAX_DATA_LEN => {
+            Err(invalid_data(format!("pkt-line length {} is too long", len)))
+        }
+        _ => {
+            let mut data = vec![0u8; len - HEADER_LEN];
+            reader.read_exact(&mut data)?;
+            Ok(Some(PktLine::Data(data)))
+        }
+    }
+}
+
+pub fn write_pkt_line(writer: &mut impl Write, pkt_line: &PktLine) -> io::Result<()> {
+    match pkt_line {
+        PktLine::Flush => writer.write_all(b"0000"),
+        PktLine::Delim => writer.write_all(b"0001"),
+        PktLine::Data(data) => {
+            if data.len() > MAX_DATA_LEN {
+                return Err(invalid_data(format!(
+                    "pkt-line payload of {} bytes is too long",
+                    data.len()
+                )));
+            }
+            write!(writer, "{:04x}", data.len() + HEADER_LEN)?;
+            writer.write_all(data)
+        }
+    }
+}
+
+/// Write arbitrary bytes as a run of data packets, without a trailing flush
+pub fn write_data(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    for chunk in data.chunks(MAX_DATA_LEN) {
+        write_pkt_line(writer, &PktLine::Data(chunk.to_vec()))?;
+    }
+    Ok(())
+}
+
+/// Read text packets up to the next flush or delimiter, which is returned alongside
+pub fn read_text_until_separator(
+    reader: &mut impl Read,
+) -> io::Result<(Vec<String>, Option<PktLine>)> {
+    let mut lines = vec![];
+    loop {
+        match read_pkt_line(reader)? {
+            Some(pkt_line @ PktLine::Data(_)) => lines.push(
+                pkt_line
+                    .as_text()
+                    .ok_or_else(|| invalid_data("expected a text packet".to_string()))?
+                    .to_string(),
+            ),
+            separator @ (Some(PktLine::Flush) | Some(PktLine::Delim)) => {
+                return Ok((lines, separator))
+            }
+            None => return Ok((lines, None)),
+        }
+    }
+}
+
+/// Copy data packets into `writer` until a flush, returning the number of bytes copied
+pub fn copy_data_until_flush(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        match read_pkt_line(reader)? {
+            Some(PktLine::Data(data)) => {
+                writer.write_all(&data)?;
+                copied += data.len() as u64;
+            }
+            Some(PktLine::Flush) => return Ok(copied),
+            Some(PktLine::Delim) => return Err(invalid_data("unexpected delimiter".to_string())),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn pkt_lines_round_trip() {
+        let pkt_lines = [
+            PktLine::text("version 1"),
+            PktLine::Delim,
+            PktLine::Data(vec![0, 1, 2]),
+            PktLine::Flush,
+        ];
+        let mut buffer = vec![];
+        for pkt_line in &pkt_lines {
+            write_pkt_line(&mut buffer, pkt_line).unwrap();
+        }
+        assert_eq!(buffer, b"000eversion 1\n00010007\x00\x01\x020000");
+
+        let mut reader = buffer.as_slice();
+        let mut read = vec![];
+        while let Some(pkt_line) = read_pkt_line(&mut reader).unwrap() {
+            read.push(pkt_line);
+        }
+        assert_eq!(read, pkt_lines);
+    }
+
+    #[test]
+    fn read_pkt_line_rejects_bad_length() {
+        assert!(read_pkt_line(&mut &b"zzzz"[..]).is_err());
+        assert!(read_pkt_line(&mut &b"0003"[..]).is_err());
+        assert!(read_pkt_line(&mut &b"000a"[..]).is_err());
+    }
+
+    #[test]
+    fn write_data_splits_large_payloads() {
+        let data = vec![7u8; MAX_DATA_LEN + 1];
+        let mut buffer = vec![];
+        write_data(&mut buffer, &data).unwrap();
+        write_pkt_line(&mut buffer, &PktLine::Flush).unwrap();
+        let mut copied = vec![];
+        assert_eq!(
+            copy_data_until_flush(&mut buffer.as_slice(), &mut copied).unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(copied, data);
+    }
+}
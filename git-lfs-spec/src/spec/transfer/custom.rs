@@ -1,6 +1,10 @@
-use crate::spec::Object;
+use crate::spec::{batch::Action, Object};
 use serde_derive::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf};
+
+/// Framework for agents that handles the protocol itself
+#[cfg(feature = "agent")]
+pub mod agent;
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/custom-transfers.md#stage-1-intiation
 #[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
@@ -15,11 +19,24 @@ pub enum Operation {
 #[serde(rename_all = "camelCase")]
 pub struct Init {
     pub operation: Operation,
+    #[serde(default)]
     pub remote: String,
+    #[serde(default)]
     pub concurrent: bool,
+    #[serde(default)]
     pub concurrenttransfers: Option<usize>,
 }
 
+/// Response to [Init], which is empty unless the agent failed to start
+///
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/custom-transfers.md#stage-1-intiation
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AcknowledgeInit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<Error>,
+}
+
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/custom-transfers.md#uploads
 #[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -27,6 +44,9 @@ pub struct Upload {
     #[serde(flatten)]
     pub object: Object,
     pub path: std::path::PathBuf,
+    /// Upload action from the batch API, if git-lfs talked to a server first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<Action>,
 }
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/custom-transfers.md#downloads
@@ -35,6 +55,9 @@ pub struct Upload {
 pub struct Download {
     #[serde(flatten)]
     pub object: Object,
+    /// Download action from the batch API, if git-lfs talked to a server first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<Action>,
 }
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/custom-transfers.md#stage-2-0n-transfers
@@ -75,8 +98,6 @@ pub struct Progress {
 #[serde(tag = "event", rename_all = "camelCase")]
 pub enum Event {
     Init(Init),
-    #[serde(skip)]
-    AcknowledgeInit,
     Upload(Box<Upload>),
     Download(Box<Download>),
     Complete(Box<Complete>),
@@ -85,10 +106,168 @@ pub enum Event {
     Terminate,
 }
 
+/// Anything exchanged over the protocol, including the init acknowledgement which carries no event
+#[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Message {
+    Event(Event),
+    AcknowledgeInit(AcknowledgeInit),
+}
+
+impl From<Event> for Message {
+    fn from(event: Event) -> Self {
+        Message::Event(event)
+    }
+}
+
+impl From<AcknowledgeInit> for Message {
+    fn from(ack: AcknowledgeInit) -> Self {
+        Message::AcknowledgeInit(ack)
+    }
+}
+
+/// Code of the complete event sent for a transfer that violates the protocol
+pub const PROTOCOL_ERROR_CODE: i32 = 400;
+
+/// Where an agent is in the protocol, free of any I/O
+///
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/custom-transfers.md#protocol
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub enum State {
+    #[default]
+    AwaitingInit,
+    Transferring(Init),
+    Terminated,
+}
+
+/// What the agent must do in response to an accepted event
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Step {
+    /// Reply with this acknowledgement
+    Acknowledge(AcknowledgeInit),
+    /// Upload the object, reporting progress and completion
+    Upload(Box<Upload>),
+    /// Download the object, reporting progress and completion
+    Download(Box<Download>),
+    /// Stop reading events and exit
+    Terminate,
+}
+
+/// An event that is not valid in the current [State], which is left unchanged
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ProtocolError {
+    /// Anything other than init before the agent was initialized
+    NotInitialized(Event),
+    /// A second init
+    AlreadyInitialized(Init),
+    /// An upload in a download session or vice versa
+    WrongOperation { expected: Operation, event: Event },
+    /// Progress and complete events only flow from the agent to git-lfs
+    AgentEvent(Event),
+    /// Anything after terminate
+    Terminated(Event),
+}
+
+impl State {
+    pub fn is_terminated(&self) -> bool {
+        matches!(self, State::Terminated)
+    }
+
+    /// Validate an incoming event, advancing to the next state if it is accepted
+    pub fn next(&mut self, event: Event) -> std::result::Result<Step, ProtocolError> {
+        match (&*self, event) {
+            (State::Terminated, event) => Err(ProtocolError::Terminated(event)),
+            (_, event @ (Event::Complete(_) | Event::Progress(_))) => {
+                Err(ProtocolError::AgentEvent(event))
+            }
+            (State::AwaitingInit, Event::Init(init)) => {
+                *self = State::Transferring(init);
+                Ok(Step::Acknowledge(AcknowledgeInit::default()))
+            }
+            (State::AwaitingInit, event) => Err(ProtocolError::NotInitialized(event)),
+            (State::Transferring(_), Event::Init(init)) => {
+                Err(ProtocolError::AlreadyInitialized(init))
+            }
+            (State::Transferring(_), Event::Terminate) => {
+                *self = State::Terminated;
+                Ok(Step::Terminate)
+            }
+            (State::Transferring(init), event) => match (&init.operation, event) {
+                (Operation::Upload, Event::Upload(upload)) => Ok(Step::Upload(upload)),
+                (Operation::Download, Event::Download(download)) => Ok(Step::Download(download)),
+                (expected, event) => Err(ProtocolError::WrongOperation {
+                    expected: expected.clone(),
+                    event,
+                }),
+            },
+        }
+    }
+}
+
+impl ProtocolError {
+    /// Message that tells git-lfs about the error without ending the session, if there is one
+    ///
+    /// Transfers are failed individually with a complete event.
+    pub fn response(&self) -> Option<Message> {
+        let oid = match self {
+            ProtocolError::NotInitialized(event) | ProtocolError::WrongOperation { event, .. } => {
+                match event {
+                    Event::Upload(upload) => &upload.object.oid,
+                    Event::Download(download) => &download.object.oid,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        Some(
+            Event::Complete(
+                Complete {
+                    oid: oid.clone(),
+                    result: Some(Result::Error(Error {
+                        code: PROTOCOL_ERROR_CODE,
+                        message: self.to_string(),
+                    })),
+                }
+                .into(),
+            )
+            .into(),
+        )
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::NotInitialized(event) => {
+                write!(f, "unexpected event before init: {:?}", event)
+            }
+            ProtocolError::AlreadyInitialized(init) => {
+                write!(f, "unexpected init event: {:?}", init)
+            }
+            ProtocolError::WrongOperation { expected, event } => {
+                write!(
+                    f,
+                    "unexpected event for {:?} operation: {:?}",
+                    expected, event
+                )
+            }
+            ProtocolError::AgentEvent(event) => {
+                write!(f, "unexpected event from git-lfs: {:?}", event)
+            }
+            ProtocolError::Terminated(event) => {
+                write!(f, "unexpected event after terminate: {:?}", event)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
     use std::str::FromStr;
 
     #[test]
@@ -119,7 +298,8 @@ mod test {
                         oid: "22ab5f63670800cc7be06dbed816012b0dc411e774754c7579467d2536a9cf3e"
                             .to_string(),
                         size: 21245,
-                    }
+                    },
+                    action: None,
                 }
                 .into()
             ))
@@ -177,11 +357,289 @@ mod test {
                             .to_string(),
                         size: 346232
                     },
-                    path: std::path::PathBuf::from_str("/path/to/file.png").unwrap()
+                    path: std::path::PathBuf::from_str("/path/to/file.png").unwrap(),
+                    action: None,
                 }
                 .into()
             ))
             .unwrap(),
         );
     }
+
+    #[test]
+    fn custom_acknowledge_init_serializes_ok() {
+        assert_eq!(
+            "{}",
+            serde_json::to_string(&Message::from(AcknowledgeInit::default())).unwrap(),
+        );
+        assert_eq!(
+            include_str!("../test/custom_init_error.json"),
+            serde_json::to_string(&Message::from(AcknowledgeInit {
+                error: Some(Error {
+                    code: 32,
+                    message: "Some init failure message".to_string()
+                })
+            }))
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn custom_init_tolerates_missing_fields() {
+        assert_eq!(
+            serde_json::from_str::<Event>(r#"{"event":"init","operation":"upload"}"#).unwrap(),
+            Event::Init(Init {
+                operation: Operation::Upload,
+                remote: String::new(),
+                concurrent: false,
+                concurrenttransfers: None,
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_event_is_not_an_acknowledgement() {
+        assert!(serde_json::from_str::<Message>(r#"{"event":"unknown"}"#).is_err());
+    }
+
+    /// Parses every line of a transcript and checks that it survives serialization
+    fn round_trip_transcript(transcript: &str) -> Vec<Message> {
+        transcript
+            .lines()
+            .map(|line| {
+                let message = serde_json::from_str::<Message>(line).unwrap();
+                assert_eq!(
+                    serde_json::from_str::<Message>(&serde_json::to_string(&message).unwrap())
+                        .unwrap(),
+                    message
+                );
+                message
+            })
+            .collect()
+    }
+
+    #[test]
+    fn download_transcript_round_trips() {
+        const OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        assert_eq!(
+            round_trip_transcript(include_str!("../test/custom_transcript_download.jsonl")),
+            vec![
+                Event::Init(Init {
+                    operation: Operation::Download,
+                    remote: "origin".to_string(),
+                    concurrent: true,
+                    concurrenttransfers: Some(8),
+                })
+                .into(),
+                AcknowledgeInit::default().into(),
+                Event::Download(
+                    Download {
+                        object: Object {
+                            oid: OID.to_string(),
+                            size: 11,
+                        },
+                        action: None,
+                    }
+                    .into()
+                )
+                .into(),
+                Event::Progress(
+                    Progress {
+                        oid: OID.to_string(),
+                        bytes_so_far: 11,
+                        bytes_since_last: 11,
+                    }
+                    .into()
+                )
+                .into(),
+                Event::Complete(
+                    Complete {
+                        oid: OID.to_string(),
+                        result: Some(Result::Path(PathBuf::from(format!(
+                            "/home/jane/repo/.git/lfs/tmp/{}",
+                            OID
+                        )))),
+                    }
+                    .into()
+                )
+                .into(),
+                Event::Terminate.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn upload_transcript_round_trips() {
+        let messages =
+            round_trip_transcript(include_str!("../test/custom_transcript_upload.jsonl"));
+        assert_eq!(messages.len(), 6);
+        match &messages[2] {
+            Message::Event(Event::Upload(upload)) => {
+                let action = upload.action.as_ref().unwrap();
+                assert_eq!(
+                    action.href().as_str(),
+                    "https://lfs.example.com/objects/b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+                );
+                assert_eq!(
+                    action.header().unwrap().get("Authorization").unwrap(),
+                    "Basic amFuZTpzZWNyZXQ="
+                );
+            }
+            other => panic!("expected upload, got {:?}", other),
+        }
+        assert_eq!(
+            messages[4],
+            Event::Complete(
+                Complete {
+                    oid: "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+                        .to_string(),
+                    result: None,
+                }
+                .into()
+            )
+            .into()
+        );
+    }
+
+    fn init(operation: Operation) -> Event {
+        Event::Init(Init {
+            operation,
+            remote: "origin".to_string(),
+            concurrent: true,
+            concurrenttransfers: Some(3),
+        })
+    }
+
+    fn download(oid: &str) -> Event {
+        Event::Download(
+            Download {
+                object: Object {
+                    oid: oid.to_string(),
+                    size: 11,
+                },
+                action: None,
+            }
+            .into(),
+        )
+    }
+
+    fn upload(oid: &str) -> Event {
+        Event::Upload(
+            Upload {
+                object: Object {
+                    oid: oid.to_string(),
+                    size: 11,
+                },
+                path: PathBuf::from("/path/to/file.png"),
+                action: None,
+            }
+            .into(),
+        )
+    }
+
+    #[test]
+    fn state_machine_accepts_a_download_session() {
+        let mut state = State::default();
+        assert_eq!(
+            state.next(init(Operation::Download)),
+            Ok(Step::Acknowledge(AcknowledgeInit::default()))
+        );
+        assert!(matches!(state.next(download("a")), Ok(Step::Download(_))));
+        assert_eq!(state.next(Event::Terminate), Ok(Step::Terminate));
+        assert!(state.is_terminated());
+    }
+
+    #[test]
+    fn state_machine_fails_transfers_of_the_wrong_operation() {
+        let mut state = State::default();
+        state.next(init(Operation::Download)).unwrap();
+        let err = state.next(upload("a")).unwrap_err();
+        assert!(matches!(
+            err,
+            ProtocolError::WrongOperation {
+                expected: Operation::Download,
+                ..
+            }
+        ));
+        match err.response() {
+            Some(Message::Event(Event::Complete(complete))) => {
+                assert_eq!(complete.oid, "a");
+                assert!(matches!(
+                    complete.result,
+                    Some(Result::Error(Error {
+                        code: PROTOCOL_ERROR_CODE,
+                        ..
+                    }))
+                ));
+            }
+            other => panic!("expected complete event, got {:?}", other),
+        }
+        assert!(matches!(
+            state,
+            State::Transferring(Init {
+                operation: Operation::Download,
+                ..
+            })
+        ));
+    }
+
+    fn any_event() -> impl Strategy<Value = Event> {
+        prop_oneof![
+            Just(init(Operation::Upload)),
+            Just(init(Operation::Download)),
+            "[ab]".prop_map(|oid| upload(&oid)),
+            "[ab]".prop_map(|oid| download(&oid)),
+            Just(Event::Progress(
+                Progress {
+                    oid: "a".to_string(),
+                    bytes_so_far: 1,
+                    bytes_since_last: 1,
+                }
+                .into()
+            )),
+            Just(Event::Complete(
+                Complete {
+                    oid: "a".to_string(),
+                    result: None,
+                }
+                .into()
+            )),
+            Just(Event::Terminate),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn state_machine_holds_protocol_invariants(
+            events in prop::collection::vec(any_event(), 0..16)
+        ) {
+            let mut state = State::default();
+            let mut steps = vec![];
+            for event in events {
+                let before = state.clone();
+                match state.next(event) {
+                    Ok(step) => steps.push(step),
+                    Err(err) => {
+                        prop_assert_eq!(&state, &before);
+                        prop_assert!(matches!(
+                            err.response(),
+                            None | Some(Message::Event(Event::Complete(_)))
+                        ));
+                    }
+                }
+            }
+            if let Some(first) = steps.first() {
+                prop_assert!(matches!(first, Step::Acknowledge(_)));
+            }
+            let acknowledgements = steps.iter().filter(|step| matches!(step, Step::Acknowledge(_)));
+            prop_assert_eq!(acknowledgements.count(), steps.len().min(1));
+            if let Some(index) = steps.iter().position(|step| *step == Step::Terminate) {
+                prop_assert_eq!(index, steps.len() - 1);
+                prop_assert!(state.is_terminated());
+            }
+            let uploads = steps.iter().any(|step| matches!(step, Step::Upload(_)));
+            let downloads = steps.iter().any(|step| matches!(step, Step::Download(_)));
+            prop_assert!(!(uploads && downloads));
+        }
+    }
 }
@@ -0,0 +1,470 @@
+use async_trait::async_trait;
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    future::BoxFuture,
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+use crate::spec::Object;
+
+use super::{
+    Complete, Download, Error, Event, Init, Message, Progress, Result, State, Step, Upload,
+};
+
+/// Time after which pending progress is reported, however little it is
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pending progress is also reported once it reaches this fraction of the object size
+pub const PROGRESS_STEPS: u64 = 100;
+
+/// A custom transfer agent for some storage backend
+///
+/// [run] takes care of the protocol, so implementations only move bytes.
+///
+/// https://github.com/git-lfs/git-lfs/blob/master/docs/custom-transfers.md
+#[async_trait]
+pub trait Agent: Sync {
+    /// Prepare for transfers, failing the whole session on error
+    async fn init(&self, _init: &Init) -> std::result::Result<(), Error> {
+        Ok(())
+    }
+
+    /// Store the file at [Upload::path]
+    async fn upload(
+        &self,
+        upload: Upload,
+        progress: ProgressSink,
+    ) -> std::result::Result<(), Error>;
+
+    /// Fetch an object, returning the path of a file that git-lfs will move into place
+    async fn download(
+        &self,
+        download: Download,
+        progress: ProgressSink,
+    ) -> std::result::Result<PathBuf, Error>;
+}
+
+/// Reports the progress of one transfer back to git-lfs
+///
+/// Updates are coalesced until either [PROGRESS_INTERVAL] has passed or
+/// 1/[PROGRESS_STEPS] of the object has been transferred since the last event.
+/// Progress is capped at the object size, and the runner reports the full size
+/// once a transfer succeeds, so agents that cannot track progress need not report any.
+#[derive(Clone)]
+pub struct ProgressSink {
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+struct Tracker {
+    oid: String,
+    size: u64,
+    bytes_so_far: u64,
+    bytes_since_last: u64,
+    step: u64,
+    interval: Duration,
+    last_sent: Instant,
+    messages: UnboundedSender<Message>,
+}
+
+impl ProgressSink {
+    fn new(object: &Object, interval: Duration, messages: UnboundedSender<Message>) -> Self {
+        Self {
+            tracker: Arc::new(Mutex::new(Tracker {
+                oid: object.oid.clone(),
+                size: object.size,
+                bytes_so_far: 0,
+                bytes_since_last: 0,
+                step: (object.size / PROGRESS_STEPS).max(1),
+                interval,
+                last_sent: Instant::now(),
+                messages,
+            })),
+        }
+    }
+
+    /// Record that `bytes` more bytes were transferred
+    pub fn advance(&self, bytes: u64) {
+        let mut tracker = self.tracker.lock().unwrap();
+        let bytes = bytes.min(tracker.size - tracker.bytes_so_far);
+        tracker.bytes_so_far += bytes;
+        tracker.bytes_since_last += bytes;
+        if tracker.bytes_since_last >= tracker.step
+            || tracker.last_sent.elapsed() >= tracker.interval
+        {
+            tracker.flush();
+        }
+    }
+
+    /// Report whatever is left of the object after it was transferred successfully
+    fn finish(&self) {
+        let mut tracker = self.tracker.lock().unwrap();
+        tracker.bytes_since_last += tracker.size - tracker.bytes_so_far;
+        tracker.bytes_so_far = tracker.size;
+        tracker.flush();
+    }
+}
+
+impl Tracker {
+    fn flush(&mut self) {
+        if self.bytes_since_last == 0 {
+            return;
+        }
+        // The runner outlives every sink, so this only fails if it already gave up
+        let _ = self.messages.unbounded_send(
+            Event::Progress(
+                Progress {
+                    oid: self.oid.clone(),
+                    bytes_so_far: self.bytes_so_far,
+                    bytes_since_last: self.bytes_since_last,
+                }
+                .into(),
+            )
+            .into(),
+        );
+        self.bytes_since_last = 0;
+        self.last_sent = Instant::now();
+    }
+}
+
+/// Speak the custom transfer protocol over JSON lines until git-lfs terminates the session
+///
+/// Transfers run concurrently, up to the number git-lfs asked for in [Init]. Events that
+/// break the protocol fail their transfer if they have one, and are otherwise logged and
+/// ignored, so the session goes on either way.
+pub async fn run(
+    agent: &impl Agent,
+    input: impl AsyncBufRead + Unpin,
+    mut output: impl AsyncWrite + Unpin,
+) -> io::Result<()> {
+    let (messages, mut outbox) = mpsc::unbounded::<Message>();
+    let mut lines = input.lines();
+    let mut state = State::default();
+    let mut in_flight = FuturesUnordered::<BoxFuture<()>>::new();
+    let mut concurrency = 1;
+    let mut reading = true;
+
+    loop {
+        if !reading && in_flight.is_empty() {
+            outbox.close();
+            while let Some(message) = outbox.next().await {
+                write_message(&mut output, &message).await?;
+            }
+            return Ok(());
+        }
+        tokio::select! {
+            biased;
+            Some(message) = outbox.next() => write_message(&mut output, &message).await?,
+            Some(()) = in_flight.next(), if !in_flight.is_empty() => {}
+            line = lines.next_line(), if reading && in_flight.len() < concurrency => {
+                let line = match line? {
+                    Some(line) => line,
+                    None => {
+                        reading = false;
+                        continue;
+                    }
+                };
+                let event = match serde_json::from_str::<Event>(&line) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!(%err, line, "ignoring invalid event");
+                        continue;
+                    }
+                };
+                let step = match state.next(event) {
+                    Ok(step) => step,
+                    Err(err) => {
+                        match err.response() {
+                            Some(response) => write_message(&mut output, &response).await?,
+                            None => warn!(%err, "ignoring event"),
+                        }
+                        continue;
+                    }
+                };
+                match step {
+                    Step::Acknowledge(mut ack) => {
+                        if let State::Transferring(init) = &state {
+                            if init.concurrent {
+                                concurrency = init.concurrenttransfers.unwrap_or(1).max(1);
+                            }
+                            ack.error = agent.init(init).await.err();
+                        }
+                        write_message(&mut output, &ack.into()).await?;
+                    }
+                    Step::Upload(upload) => {
+                        let messages = messages.clone();
+                        in_flight.push(
+                            async move {
+                                let oid = upload.object.oid.clone();
+                                let progress = ProgressSink::new(
+                                    &upload.object,
+                                    PROGRESS_INTERVAL,
+                                    messages.clone(),
+                                );
+                                let result = match agent.upload(*upload, progress.clone()).await {
+                                    Ok(()) => {
+                                        progress.finish();
+                                        None
+                                    }
+                                    Err(err) => Some(Result::Error(err)),
+                                };
+                                complete(&messages, oid, result);
+                            }
+                            .boxed(),
+                        );
+                    }
+                    Step::Download(download) => {
+                        let messages = messages.clone();
+                        in_flight.push(
+                            async move {
+                                let oid = download.object.oid.clone();
+                                let progress = ProgressSink::new(
+                                    &download.object,
+                                    PROGRESS_INTERVAL,
+                                    messages.clone(),
+                                );
+                                let download = agent.download(*download, progress.clone());
+                                let result = match download.await {
+                                    Ok(path) => {
+                                        progress.finish();
+                                        Result::Path(path)
+                                    }
+                                    Err(err) => Result::Error(err),
+                                };
+                                complete(&messages, oid, Some(result));
+                            }
+                            .boxed(),
+                        );
+                    }
+                    Step::Terminate => reading = false,
+                }
+            }
+        }
+    }
+}
+
+fn complete(messages: &UnboundedSender<Message>, oid: String, result: Option<Result>) {
+    let _ = messages.unbounded_send(Event::Complete(Complete { oid, result }.into()).into());
+}
+
+async fn write_message(
+    output: &mut (impl AsyncWrite + Unpin),
+    message: &Message,
+) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    output.write_all(&line).await?;
+    output.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spec::transfer::custom::AcknowledgeInit;
+    use pretty_assertions::assert_eq;
+
+    const OID: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    struct TestAgent;
+
+    #[async_trait]
+    impl Agent for TestAgent {
+        async fn upload(
+            &self,
+            upload: Upload,
+            progress: ProgressSink,
+        ) -> std::result::Result<(), Error> {
+            progress.advance(upload.object.size);
+            Ok(())
+        }
+
+        async fn download(
+            &self,
+            download: Download,
+            _progress: ProgressSink,
+        ) -> std::result::Result<PathBuf, Error> {
+            Err(Error {
+                code: 404,
+                message: format!("{} does not exist", download.object.oid),
+            })
+        }
+    }
+
+    async fn run_transcript(input: &str) -> Vec<Message> {
+        let mut output = vec![];
+        run(&TestAgent, input.as_bytes(), &mut output)
+            .await
+            .unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn run_reports_upload_progress_and_completion() {
+        let input = format!(
+            "{}\n{}\n{}\n",
+            r#"{"event":"init","operation":"upload","remote":"origin","concurrent":true,"concurrenttransfers":3}"#,
+            format_args!(
+                r#"{{"event":"upload","oid":"{}","size":11,"path":"/tmp/{}"}}"#,
+                OID, OID
+            ),
+            r#"{"event":"terminate"}"#,
+        );
+        assert_eq!(
+            run_transcript(&input).await,
+            vec![
+                AcknowledgeInit::default().into(),
+                Event::Progress(
+                    Progress {
+                        oid: OID.to_string(),
+                        bytes_so_far: 11,
+                        bytes_since_last: 11,
+                    }
+                    .into()
+                )
+                .into(),
+                Event::Complete(
+                    Complete {
+                        oid: OID.to_string(),
+                        result: None,
+                    }
+                    .into()
+                )
+                .into(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn run_reports_download_errors_per_object() {
+        let input = format!(
+            "{}\n{}\n{}\n",
+            r#"{"event":"init","operation":"download","remote":"origin","concurrent":false}"#,
+            format_args!(r#"{{"event":"download","oid":"{}","size":11}}"#, OID),
+            r#"{"event":"terminate"}"#,
+        );
+        assert_eq!(
+            run_transcript(&input).await,
+            vec![
+                AcknowledgeInit::default().into(),
+                Event::Complete(
+                    Complete {
+                        oid: OID.to_string(),
+                        result: Some(Result::Error(Error {
+                            code: 404,
+                            message: format!("{} does not exist", OID),
+                        })),
+                    }
+                    .into()
+                )
+                .into(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn run_fails_objects_of_the_wrong_operation() {
+        let input = format!(
+            "{}\n{}\n",
+            r#"{"event":"init","operation":"download","remote":"origin","concurrent":true}"#,
+            format_args!(
+                r#"{{"event":"upload","oid":"{}","size":11,"path":"/tmp/{}"}}"#,
+                OID, OID
+            ),
+        );
+        let messages = run_transcript(&input).await;
+        assert_eq!(messages.len(), 2);
+        match &messages[1] {
+            Message::Event(Event::Complete(complete)) => {
+                assert_eq!(complete.oid, OID);
+                assert!(matches!(complete.result, Some(Result::Error(_))));
+            }
+            other => panic!("expected complete event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn run_goes_on_after_events_without_a_transfer() {
+        let input = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\n",
+            r#"{"event":"init","operation":"download","remote":"origin","concurrent":false}"#,
+            r#"{"event":"init","operation":"upload","remote":"origin","concurrent":false}"#,
+            format_args!(
+                r#"{{"event":"progress","oid":"{}","bytesSoFar":1,"bytesSinceLast":1}}"#,
+                OID
+            ),
+            "not json",
+            format_args!(r#"{{"event":"download","oid":"{}","size":11}}"#, OID),
+            r#"{"event":"terminate"}"#,
+        );
+        let messages = run_transcript(&input).await;
+        assert_eq!(messages.len(), 2);
+        match &messages[1] {
+            Message::Event(Event::Complete(complete)) => {
+                assert_eq!(complete.oid, OID);
+                assert!(matches!(
+                    complete.result,
+                    Some(Result::Error(Error { code: 404, .. }))
+                ));
+            }
+            other => panic!("expected complete event, got {:?}", other),
+        }
+    }
+
+    fn progress(bytes_so_far: u64, bytes_since_last: u64) -> Message {
+        Event::Progress(
+            Progress {
+                oid: OID.to_string(),
+                bytes_so_far,
+                bytes_since_last,
+            }
+            .into(),
+        )
+        .into()
+    }
+
+    #[tokio::test]
+    async fn progress_is_coalesced_and_capped_at_size() {
+        let (messages, outbox) = mpsc::unbounded();
+        let object = Object {
+            oid: OID.to_string(),
+            size: 1000,
+        };
+        let sink = ProgressSink::new(&object, Duration::from_secs(3600), messages);
+        for _ in 0..5 {
+            sink.advance(1);
+        }
+        sink.advance(8);
+        sink.advance(2000);
+        sink.finish();
+        drop(sink);
+        assert_eq!(
+            outbox.collect::<Vec<_>>().await,
+            vec![progress(13, 13), progress(1000, 987)]
+        );
+    }
+
+    #[tokio::test]
+    async fn finish_reports_the_full_size() {
+        let (messages, outbox) = mpsc::unbounded();
+        let object = Object {
+            oid: OID.to_string(),
+            size: 11,
+        };
+        let sink = ProgressSink::new(&object, Duration::from_secs(3600), messages);
+        sink.finish();
+        drop(sink);
+        assert_eq!(outbox.collect::<Vec<_>>().await, vec![progress(11, 11)]);
+    }
+}
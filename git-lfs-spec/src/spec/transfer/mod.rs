@@ -3,3 +3,6 @@ pub mod basic;
 
 /// https://github.com/git-lfs/git-lfs/blob/master/docs/custom-transfers.md
 pub mod custom;
+
+/// https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md
+pub mod ssh;
@@ -0,0 +1,412 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use crate::spec::{
+    locks::Lock,
+    pkt_line::{read_text_until_separator, write_pkt_line, PktLine},
+    Object,
+};
+
+/// https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#connection
+pub const VERSION: u32 = 1;
+
+/// https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#operations
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Command {
+    Version(u32),
+    Batch,
+    GetObject(String),
+    PutObject(String),
+    VerifyObject(String),
+    Lock,
+    ListLock,
+    Unlock(String),
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = io::Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (name, argument) = match line.split_once(' ') {
+            Some((name, argument)) => (name, Some(argument.to_string())),
+            None => (line, None),
+        };
+        let command = match (name, argument) {
+            ("version", Some(version)) => Command::Version(
+                version
+                    .parse()
+                    .map_err(|_| invalid_data(format!("invalid version {}", version)))?,
+            ),
+            ("batch", None) => Command::Batch,
+            ("get-object", Some(oid)) => Command::GetObject(oid),
+            ("put-object", Some(oid)) => Command::PutObject(oid),
+            ("verify-object", Some(oid)) => Command::VerifyObject(oid),
+            ("lock", None) => Command::Lock,
+            ("list-lock", None) => Command::ListLock,
+            ("unlock", Some(id)) => Command::Unlock(id),
+            ("quit", None) => Command::Quit,
+            _ => return Err(invalid_data(format!("unknown command {:?}", line))),
+        };
+        Ok(command)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Version(version) => write!(f, "version {}", version),
+            Command::Batch => write!(f, "batch"),
+            Command::GetObject(oid) => write!(f, "get-object {}", oid),
+            Command::PutObject(oid) => write!(f, "put-object {}", oid),
+            Command::VerifyObject(oid) => write!(f, "verify-object {}", oid),
+            Command::Lock => write!(f, "lock"),
+            Command::ListLock => write!(f, "list-lock"),
+            Command::Unlock(id) => write!(f, "unlock {}", id),
+            Command::Quit => write!(f, "quit"),
+        }
+    }
+}
+
+/// A command and its `key=value` arguments
+///
+/// When `has_body` is set, the arguments are followed by a delimiter and the
+/// command's body, which the caller reads or writes itself up to the flush packet.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Request {
+    pub command: Command,
+    pub args: Vec<(String, String)>,
+    pub has_body: bool,
+}
+
+impl Request {
+    pub fn new(command: Command) -> Self {
+        Self {
+            command,
+            args: vec![],
+            has_body: false,
+        }
+    }
+
+    pub fn arg(&self, key: &str) -> Option<&str> {
+        find_arg(&self.args, key)
+    }
+}
+
+/// https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#responses
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Status {
+    pub code: u16,
+    pub args: Vec<(String, String)>,
+}
+
+impl Status {
+    pub fn new(code: u16) -> Self {
+        Self { code, args: vec![] }
+    }
+
+    pub fn with_arg(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.args.push((key.into(), value.to_string()));
+        self
+    }
+
+    pub fn arg(&self, key: &str) -> Option<&str> {
+        find_arg(&self.args, key)
+    }
+}
+
+/// The server's initial capability advertisement, terminated by a flush
+pub fn write_capabilities(writer: &mut impl Write) -> io::Result<()> {
+    write_pkt_line(writer, &PktLine::text(format!("version={}", VERSION)))?;
+    write_pkt_line(writer, &PktLine::Flush)
+}
+
+/// Read a request's command and arguments, or `None` once the client hangs up
+pub fn read_request(reader: &mut impl Read) -> io::Result<Option<Request>> {
+    let (mut lines, separator) = read_text_until_separator(reader)?;
+    if lines.is_empty() && separator.is_none() {
+        return Ok(None);
+    }
+    if lines.is_empty() {
+        return Err(invalid_data("request has no command".to_string()));
+    }
+    let command = lines.remove(0).parse()?;
+    Ok(Some(Request {
+        command,
+        args: parse_args(lines)?,
+        has_body: separator == Some(PktLine::Delim),
+    }))
+}
+
+pub fn write_request(writer: &mut impl Write, request: &Request) -> io::Result<()> {
+    write_pkt_line(writer, &PktLine::text(request.command.to_string()))?;
+    write_args(writer, &request.args)?;
+    write_pkt_line(
+        writer,
+        if request.has_body {
+            &PktLine::Delim
+        } else {
+            &PktLine::Flush
+        },
+    )
+}
+
+/// Write a complete response, with an optional body of text lines
+pub fn write_status(
+    writer: &mut impl Write,
+    status: &Status,
+    lines: Option<&[String]>,
+) -> io::Result<()> {
+    write_status_line_and_args(writer, status)?;
+    if let Some(lines) = lines {
+        write_pkt_line(writer, &PktLine::Delim)?;
+        for line in lines {
+            write_pkt_line(writer, &PktLine::text(line))?;
+        }
+    }
+    write_pkt_line(writer, &PktLine::Flush)
+}
+
+/// Write the head of a response whose binary body the caller writes next, ending with a flush
+pub fn write_status_with_data(writer: &mut impl Write, status: &Status) -> io::Result<()> {
+    write_status_line_and_args(writer, status)?;
+    write_pkt_line(writer, &PktLine::Delim)
+}
+
+/// An error response, with the message as its body
+pub fn write_error(writer: &mut impl Write, code: u16, message: &str) -> io::Result<()> {
+    write_status(writer, &Status::new(code), Some(&[message.to_string()]))
+}
+
+/// Read a response's status and arguments, and whether a body follows
+pub fn read_status(reader: &mut impl Read) -> io::Result<(Status, bool)> {
+    let (mut lines, separator) = read_text_until_separator(reader)?;
+    if lines.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let code = lines
+        .remove(0)
+        .strip_prefix("status ")
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid_data("expected a status line".to_string()))?;
+    Ok((
+        Status {
+            code,
+            args: parse_args(lines)?,
+        },
+        separator == Some(PktLine::Delim),
+    ))
+}
+
+/// https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#batch
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BatchAction {
+    Download,
+    Upload,
+    Noop,
+}
+
+impl fmt::Display for BatchAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BatchAction::Download => "download",
+            BatchAction::Upload => "upload",
+            BatchAction::Noop => "noop",
+        })
+    }
+}
+
+/// Parse a `<oid> <size>` line of a batch request body
+pub fn parse_batch_object(line: &str) -> io::Result<Object> {
+    match line.split(' ').collect::<Vec<_>>().as_slice() {
+        [oid, size] => Ok(Object {
+            oid: oid.to_string(),
+            size: size
+                .parse()
+                .map_err(|_| invalid_data(format!("invalid size in {:?}", line)))?,
+        }),
+        _ => Err(invalid_data(format!("invalid batch object {:?}", line))),
+    }
+}
+
+/// Format a `<oid> <size> <action>` line of a batch response body
+pub fn format_batch_object(object: &Object, action: BatchAction) -> String {
+    format!("{} {} {}", object.oid, object.size, action)
+}
+
+/// Arguments describing a lock in responses to `lock` and `unlock`
+///
+/// https://github.com/git-lfs/git-lfs/blob/main/docs/proposals/ssh_adapter.md#locking
+pub fn lock_args(lock: &Lock) -> Vec<(String, String)> {
+    let mut args = vec![
+        ("id".to_string(), lock.id.clone()),
+        ("path".to_string(), lock.path.clone()),
+        ("locked-at".to_string(), lock.locked_at.to_rfc3339()),
+    ];
+    if let Some(owner) = &lock.owner {
+        args.push(("ownername".to_string(), owner.name.clone()));
+    }
+    args
+}
+
+/// Body lines describing a lock in a `list-lock` response
+///
+/// `ours` adds the `owner` line used when verifying locks.
+pub fn lock_lines(lock: &Lock, ours: Option<bool>) -> Vec<String> {
+    let mut lines = vec![
+        format!("lock {}", lock.id),
+        format!("path {} {}", lock.id, lock.path),
+        format!("locked-at {} {}", lock.id, lock.locked_at.to_rfc3339()),
+    ];
+    if let Some(owner) = &lock.owner {
+        lines.push(format!("ownername {} {}", lock.id, owner.name));
+    }
+    if let Some(ours) = ours {
+        lines.push(format!(
+            "owner {} {}",
+            lock.id,
+            if ours { "ours" } else { "theirs" }
+        ));
+    }
+    lines
+}
+
+fn parse_args(lines: Vec<String>) -> io::Result<Vec<(String, String)>> {
+    lines
+        .into_iter()
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(invalid_data(format!("invalid argument {:?}", line))),
+        })
+        .collect()
+}
+
+fn write_args(writer: &mut impl Write, args: &[(String, String)]) -> io::Result<()> {
+    for (key, value) in args {
+        write_pkt_line(writer, &PktLine::text(format!("{}={}", key, value)))?;
+    }
+    Ok(())
+}
+
+fn write_status_line_and_args(writer: &mut impl Write, status: &Status) -> io::Result<()> {
+    write_pkt_line(writer, &PktLine::text(format!("status {}", status.code)))?;
+    write_args(writer, &status.args)
+}
+
+fn find_arg<'a>(args: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    args.iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.as_str())
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spec::{locks::Owner, pkt_line::read_pkt_line};
+    use chrono::DateTime;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn batch_request_round_trips() {
+        let mut request = Request::new(Command::Batch);
+        request
+            .args
+            .push(("transfer".to_string(), "basic".to_string()));
+        request.has_body = true;
+
+        let mut buffer = vec![];
+        write_request(&mut buffer, &request).unwrap();
+        write_pkt_line(&mut buffer, &PktLine::text("1111111 123")).unwrap();
+        write_pkt_line(&mut buffer, &PktLine::Flush).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buffer),
+            "000abatch\n0013transfer=basic\n000100101111111 123\n0000"
+        );
+
+        let mut reader = buffer.as_slice();
+        let read = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(read, request);
+        assert_eq!(read.arg("transfer"), Some("basic"));
+        let (lines, _) = read_text_until_separator(&mut reader).unwrap();
+        assert_eq!(
+            parse_batch_object(&lines[0]).unwrap(),
+            Object {
+                oid: "1111111".to_string(),
+                size: 123
+            }
+        );
+        assert_eq!(read_pkt_line(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn command_parses_and_formats() {
+        for line in [
+            "version 1",
+            "batch",
+            "get-object abc",
+            "put-object abc",
+            "verify-object abc",
+            "lock",
+            "list-lock",
+            "unlock some-id",
+            "quit",
+        ] {
+            assert_eq!(line.parse::<Command>().unwrap().to_string(), line);
+        }
+        assert!("get-object".parse::<Command>().is_err());
+        assert!("version one".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn status_with_body_round_trips() {
+        let status = Status::new(200).with_arg("next-cursor", 2);
+        let mut buffer = vec![];
+        write_status(
+            &mut buffer,
+            &status,
+            Some(&["1111111 123 download".to_string()]),
+        )
+        .unwrap();
+        let mut reader = buffer.as_slice();
+        let (read, has_body) = read_status(&mut reader).unwrap();
+        assert_eq!(read, status);
+        assert!(has_body);
+        assert_eq!(
+            read_text_until_separator(&mut reader).unwrap(),
+            (
+                vec!["1111111 123 download".to_string()],
+                Some(PktLine::Flush)
+            )
+        );
+    }
+
+    #[test]
+    fn lock_lines_describe_lock() {
+        let lock = Lock {
+            id: "some-uuid".to_string(),
+            path: "foo/bar.zip".to_string(),
+            locked_at: DateTime::parse_from_rfc3339("2016-05-17T15:49:06+00:00").unwrap(),
+            owner: Some(Owner {
+                name: "Jane Doe".to_string(),
+            }),
+        };
+        assert_eq!(
+            lock_lines(&lock, Some(true)),
+            [
+                "lock some-uuid",
+                "path some-uuid foo/bar.zip",
+                "locked-at some-uuid 2016-05-17T15:49:06+00:00",
+                "ownername some-uuid Jane Doe",
+                "owner some-uuid ours",
+            ]
+        );
+        assert_eq!(lock_args(&lock)[0], ("id".to_string(), lock.id.clone()));
+    }
+}